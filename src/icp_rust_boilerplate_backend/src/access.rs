@@ -0,0 +1,163 @@
+// Role-based access control; canister controllers always act as admins
+use crate::audit;
+use crate::{Error, Memory, VESSEL_MEMORY_MANAGER};
+use candid::{Decode, Encode, Principal};
+use ic_cdk::api::{caller, is_controller};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::storable::Blob;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use std::{borrow::Cow, cell::RefCell};
+
+// Principals are at most 29 bytes long
+pub(crate) type PrincipalKey = Blob<29>;
+
+// Define the roles that can be granted to a principal
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Debug)]
+pub(crate) enum Role {
+    Admin,
+    Operator,
+    Investigator,
+}
+
+// The roles granted to a principal
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct RoleSet {
+    roles: Vec<Role>,
+}
+
+// Implement Storable trait for RoleSet
+impl Storable for RoleSet {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+// Implement BoundedStorable trait for RoleSet
+impl BoundedStorable for RoleSet {
+    const MAX_SIZE: u32 = 64;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Define thread-local variables for role storage
+thread_local! {
+    static ROLE_STORAGE: RefCell<StableBTreeMap<PrincipalKey, RoleSet, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            VESSEL_MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(19)))
+    ));
+}
+
+// Grant a role to a principal (admins only)
+#[ic_cdk::update]
+fn grant_role(principal: Principal, role: Role) -> Result<(), Error> {
+    require_role(Role::Admin)?;
+    let key = principal_key(&principal);
+    let (before, after) = ROLE_STORAGE.with(|service| {
+        let mut service = service.borrow_mut();
+        let before = service.get(&key).unwrap_or_default();
+        let mut after = before.clone();
+        if !after.roles.contains(&role) {
+            after.roles.push(role);
+        }
+        service.insert(key, after.clone());
+        (before, after)
+    });
+    audit::record("grant_role", "role", principal, Some(&before), Some(&after));
+    Ok(())
+}
+
+// Revoke a role from a principal (admins only)
+#[ic_cdk::update]
+fn revoke_role(principal: Principal, role: Role) -> Result<(), Error> {
+    require_role(Role::Admin)?;
+    let key = principal_key(&principal);
+    let (before, after) = ROLE_STORAGE.with(|service| {
+        let mut service = service.borrow_mut();
+        let before = service.get(&key).unwrap_or_default();
+        let mut after = before.clone();
+        after.roles.retain(|r| *r != role);
+        if after.roles.is_empty() {
+            service.remove(&key);
+        } else {
+            service.insert(key, after.clone());
+        }
+        (before, after)
+    });
+    audit::record(
+        "revoke_role",
+        "role",
+        principal,
+        Some(&before),
+        Some(&after),
+    );
+    Ok(())
+}
+
+// List the roles granted to a principal
+#[ic_cdk::query]
+fn get_roles(principal: Principal) -> Vec<Role> {
+    let mut roles = ROLE_STORAGE
+        .with(|service| service.borrow().get(&principal_key(&principal)))
+        .unwrap_or_default()
+        .roles;
+    if is_controller(&principal) && !roles.contains(&Role::Admin) {
+        roles.push(Role::Admin);
+    }
+    roles
+}
+
+// Convert a principal into a stable map key
+pub(crate) fn principal_key(principal: &Principal) -> PrincipalKey {
+    Blob::try_from(principal.as_slice()).expect("principal is longer than 29 bytes")
+}
+
+// Whether a principal holds a role; admins hold every role
+pub(crate) fn has_role(principal: &Principal, role: Role) -> bool {
+    if is_controller(principal) {
+        return true;
+    }
+    let roles = ROLE_STORAGE
+        .with(|service| service.borrow().get(&principal_key(principal)))
+        .unwrap_or_default()
+        .roles;
+    roles.contains(&Role::Admin) || roles.contains(&role)
+}
+
+// List the principals explicitly granted a role
+pub(crate) fn principals_with_role(role: Role) -> Vec<Principal> {
+    ROLE_STORAGE.with(|service| {
+        service
+            .borrow()
+            .iter()
+            .filter(|(_, set)| set.roles.contains(&role))
+            .map(|(key, _)| Principal::from_slice(key.as_slice()))
+            .collect()
+    })
+}
+
+// Fail unless the caller holds the given role
+pub(crate) fn require_role(role: Role) -> Result<(), Error> {
+    let caller = caller();
+    if has_role(&caller, role) {
+        Ok(())
+    } else {
+        Err(Error::Unauthorized {
+            msg: format!("caller {} does not hold the {:?} role", caller, role),
+        })
+    }
+}
+
+// Fail when the caller is anonymous
+pub(crate) fn require_authenticated() -> Result<Principal, Error> {
+    let caller = caller();
+    if caller == Principal::anonymous() {
+        Err(Error::Unauthorized {
+            msg: "anonymous callers are not allowed".to_string(),
+        })
+    } else {
+        Ok(caller)
+    }
+}
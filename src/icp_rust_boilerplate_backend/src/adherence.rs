@@ -0,0 +1,234 @@
+// Schedule adherence: actual departure and arrival times against the planned ones
+use crate::organizations::{can_access_vessel, require_vessel_access, VesselAccess};
+use crate::{_get_vessel, vessel_voyages, Error, Voyage, VOYAGE_STORAGE};
+use ic_cdk::api::time;
+use std::collections::BTreeMap;
+
+// Departures and arrivals up to this late count as on time; early ones always do
+const ON_TIME_TOLERANCE: u64 = 60 * 60 * 1_000_000_000;
+const NANOS_PER_SECOND: f64 = 1_000_000_000.0;
+
+// Adherence figures over a set of voyages with a schedule
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct ScheduleAdherence {
+    // Voyages with a scheduled departure or arrival
+    scheduled_voyages: u32,
+    departures_measured: u32,
+    on_time_departures: u32,
+    on_time_departure_pct: Option<f64>,
+    // Negative when voyages depart early on average
+    average_departure_delay_secs: Option<f64>,
+    arrivals_measured: u32,
+    on_time_arrivals: u32,
+    on_time_arrival_pct: Option<f64>,
+    average_arrival_delay_secs: Option<f64>,
+}
+
+// Adherence of the voyages between two ports
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct RouteAdherence {
+    departure_port: String,
+    destination_port: String,
+    adherence: ScheduleAdherence,
+}
+
+// Running totals for ScheduleAdherence
+#[derive(Default)]
+struct Tally {
+    scheduled_voyages: u32,
+    departures: Delays,
+    arrivals: Delays,
+}
+
+#[derive(Default)]
+struct Delays {
+    measured: u32,
+    on_time: u32,
+    // Nanoseconds; i128 so that early and late voyages can be summed without overflow
+    total_delay: i128,
+}
+
+impl Delays {
+    fn add(&mut self, scheduled: u64, actual: u64) {
+        self.measured += 1;
+        if actual <= scheduled.saturating_add(ON_TIME_TOLERANCE) {
+            self.on_time += 1;
+        }
+        self.total_delay += actual as i128 - scheduled as i128;
+    }
+
+    fn on_time_pct(&self) -> Option<f64> {
+        (self.measured > 0).then(|| self.on_time as f64 * 100.0 / self.measured as f64)
+    }
+
+    fn average_delay_secs(&self) -> Option<f64> {
+        (self.measured > 0)
+            .then(|| self.total_delay as f64 / self.measured as f64 / NANOS_PER_SECOND)
+    }
+}
+
+impl Tally {
+    // Departures count once they are in the past, arrivals once they are recorded
+    fn add(&mut self, voyage: &Voyage, now: u64) {
+        if voyage.scheduled_departure.is_none() && voyage.scheduled_arrival.is_none() {
+            return;
+        }
+        self.scheduled_voyages += 1;
+        if let Some(scheduled) = voyage.scheduled_departure {
+            if voyage.departure_time <= now {
+                self.departures.add(scheduled, voyage.departure_time);
+            }
+        }
+        if let (Some(scheduled), Some(arrival)) = (voyage.scheduled_arrival, voyage.arrival_time) {
+            self.arrivals.add(scheduled, arrival);
+        }
+    }
+
+    fn finish(self) -> ScheduleAdherence {
+        ScheduleAdherence {
+            scheduled_voyages: self.scheduled_voyages,
+            departures_measured: self.departures.measured,
+            on_time_departures: self.departures.on_time,
+            on_time_departure_pct: self.departures.on_time_pct(),
+            average_departure_delay_secs: self.departures.average_delay_secs(),
+            arrivals_measured: self.arrivals.measured,
+            on_time_arrivals: self.arrivals.on_time,
+            on_time_arrival_pct: self.arrivals.on_time_pct(),
+            average_arrival_delay_secs: self.arrivals.average_delay_secs(),
+        }
+    }
+}
+
+// Measure how well the voyages of a vessel kept to their schedule
+#[ic_cdk::query]
+fn get_vessel_schedule_adherence(vessel_id: u64) -> Result<ScheduleAdherence, Error> {
+    require_vessel_access(vessel_id, VesselAccess::View)?;
+    if _get_vessel(&vessel_id).is_none() {
+        return Err(Error::NotFound {
+            msg: format!("a vessel with id={} not found", vessel_id),
+        });
+    }
+    let now = time();
+    let mut tally = Tally::default();
+    for voyage in vessel_voyages(vessel_id) {
+        tally.add(&voyage, now);
+    }
+    Ok(tally.finish())
+}
+
+// Measure schedule adherence per route over the voyages of the vessels the caller may view
+#[ic_cdk::query]
+fn get_route_schedule_adherence() -> Vec<RouteAdherence> {
+    let now = time();
+    let mut routes: BTreeMap<(String, String), Tally> = BTreeMap::new();
+    let mut visible: BTreeMap<u64, bool> = BTreeMap::new();
+    VOYAGE_STORAGE.with(|service| {
+        for (_, voyage) in service.borrow().iter() {
+            let allowed = *visible
+                .entry(voyage.vessel_id)
+                .or_insert_with(|| can_access_vessel(voyage.vessel_id, VesselAccess::View));
+            if !allowed {
+                continue;
+            }
+            routes
+                .entry((
+                    voyage.departure_port.clone(),
+                    voyage.destination_port.clone(),
+                ))
+                .or_default()
+                .add(&voyage, now);
+        }
+    });
+    routes
+        .into_iter()
+        .filter(|(_, tally)| tally.scheduled_voyages > 0)
+        .map(
+            |((departure_port, destination_port), tally)| RouteAdherence {
+                departure_port,
+                destination_port,
+                adherence: tally.finish(),
+            },
+        )
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HOUR: u64 = 60 * 60 * 1_000_000_000;
+
+    #[test]
+    fn delays_within_the_tolerance_are_on_time() {
+        let mut delays = Delays::default();
+        delays.add(10 * HOUR, 10 * HOUR + ON_TIME_TOLERANCE);
+        delays.add(10 * HOUR, 8 * HOUR);
+        delays.add(10 * HOUR, 12 * HOUR);
+        assert_eq!(delays.measured, 3);
+        assert_eq!(delays.on_time, 2);
+        assert_eq!(delays.on_time_pct(), Some(200.0 / 3.0));
+        // +1h - 2h + 2h over three voyages
+        assert_eq!(delays.average_delay_secs(), Some(1200.0));
+    }
+
+    #[test]
+    fn early_voyages_average_to_a_negative_delay() {
+        let mut delays = Delays::default();
+        delays.add(10 * HOUR, 9 * HOUR);
+        assert_eq!(delays.average_delay_secs(), Some(-3600.0));
+        assert_eq!(Delays::default().average_delay_secs(), None);
+        assert_eq!(Delays::default().on_time_pct(), None);
+    }
+
+    #[test]
+    fn tally_counts_scheduled_voyages_only() {
+        let now = 100 * HOUR;
+        let mut tally = Tally::default();
+        tally.add(
+            &Voyage {
+                departure_time: 10 * HOUR,
+                ..Default::default()
+            },
+            now,
+        );
+        assert_eq!(tally.scheduled_voyages, 0);
+
+        tally.add(
+            &Voyage {
+                departure_time: 11 * HOUR,
+                arrival_time: Some(40 * HOUR),
+                scheduled_departure: Some(10 * HOUR),
+                scheduled_arrival: Some(30 * HOUR),
+                ..Default::default()
+            },
+            now,
+        );
+        let adherence = tally.finish();
+        assert_eq!(adherence.scheduled_voyages, 1);
+        assert_eq!(adherence.departures_measured, 1);
+        assert_eq!(adherence.on_time_departures, 1);
+        assert_eq!(adherence.arrivals_measured, 1);
+        assert_eq!(adherence.on_time_arrivals, 0);
+        assert_eq!(adherence.average_arrival_delay_secs, Some(36000.0));
+    }
+
+    #[test]
+    fn future_departures_and_open_voyages_are_not_measured() {
+        let now = 100 * HOUR;
+        let mut tally = Tally::default();
+        tally.add(
+            &Voyage {
+                departure_time: 120 * HOUR,
+                scheduled_departure: Some(110 * HOUR),
+                scheduled_arrival: Some(130 * HOUR),
+                ..Default::default()
+            },
+            now,
+        );
+        let adherence = tally.finish();
+        assert_eq!(adherence.scheduled_voyages, 1);
+        assert_eq!(adherence.departures_measured, 0);
+        assert_eq!(adherence.arrivals_measured, 0);
+        assert_eq!(adherence.on_time_departure_pct, None);
+    }
+}
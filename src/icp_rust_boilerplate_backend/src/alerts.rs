@@ -0,0 +1,391 @@
+// Alert rules, raised alerts and a per-principal notification inbox
+use crate::access::{principal_key, principals_with_role, require_authenticated, require_role};
+use crate::access::{PrincipalKey, Role};
+use crate::organizations::{can_principal_access_vessel, VesselAccess};
+use crate::{audit, Error, IdCell, Memory, VESSEL_MEMORY_MANAGER};
+use candid::{Decode, Encode, Principal};
+use ic_cdk::api::{caller, time};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use std::collections::BTreeSet;
+use std::{borrow::Cow, cell::RefCell};
+
+const MAX_INBOX_PAGE: usize = 100;
+// Keeps an Alert within its MAX_SIZE whatever the message embeds
+const MAX_MESSAGE_LEN: usize = 500;
+// Keep an AlertRule within its MAX_SIZE: a principal takes at most 31 bytes encoded,
+// so a full rule is about 1.8 KiB of the 4 KiB
+const MAX_NAME_LEN: usize = 200;
+const MAX_RECIPIENTS: usize = 50;
+const MAX_RECIPIENT_ROLES: usize = 3;
+
+// Define the events an alert rule can react to
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Debug)]
+pub(crate) enum AlertTrigger {
+    // Raised by the scheduled jobs
+    OverdueVoyage,
+    StaleVessel,
+    ExpiringShipCertificate,
+    ExpiringCrewCertificate,
+    // Raised by update calls
+    IncidentReported,
+    PscDetention,
+    RestHourViolation,
+}
+
+// Define the severities of an alert
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Debug)]
+pub(crate) enum AlertSeverity {
+    Info,
+    Warning,
+    Critical,
+}
+
+// Define the structure for an alert rule
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct AlertRule {
+    id: u64,
+    name: String,
+    trigger: AlertTrigger,
+    severity: AlertSeverity,
+    // Only react to events of this vessel; None matches every vessel
+    vessel_id: Option<u64>,
+    recipients: Vec<Principal>,
+    // Every principal granted one of these roles is notified as well
+    recipient_roles: Vec<Role>,
+    enabled: bool,
+    created_by: Principal,
+    created_at: u64,
+}
+
+// Define the structure for creating an alert rule
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct AlertRulePayload {
+    name: String,
+    trigger: AlertTrigger,
+    severity: AlertSeverity,
+    vessel_id: Option<u64>,
+    recipients: Vec<Principal>,
+    recipient_roles: Vec<Role>,
+}
+
+// Define the structure for a raised alert
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct Alert {
+    id: u64,
+    rule_id: u64,
+    trigger: AlertTrigger,
+    severity: AlertSeverity,
+    vessel_id: Option<u64>,
+    // ID of the voyage, vessel, certificate, incident, inspection or crew member concerned
+    target_id: u64,
+    message: String,
+    raised_at: u64,
+}
+
+// The delivery of an alert to one principal
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct InboxEntry {
+    acknowledged_at: Option<u64>,
+}
+
+// An alert as seen in a principal's inbox
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct InboxItem {
+    alert: Alert,
+    acknowledged_at: Option<u64>,
+}
+
+// Implement Storable trait for AlertRule
+impl Storable for AlertRule {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+// Implement BoundedStorable trait for AlertRule
+impl BoundedStorable for AlertRule {
+    const MAX_SIZE: u32 = 4096;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Implement Storable trait for Alert
+impl Storable for Alert {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+// Implement BoundedStorable trait for Alert
+impl BoundedStorable for Alert {
+    const MAX_SIZE: u32 = 1024;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Implement Storable trait for InboxEntry
+impl Storable for InboxEntry {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+// Implement BoundedStorable trait for InboxEntry
+impl BoundedStorable for InboxEntry {
+    const MAX_SIZE: u32 = 32;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Define thread-local variables for alert storage
+thread_local! {
+    static ALERT_RULE_ID_COUNTER: RefCell<IdCell> = RefCell::new(
+        IdCell::init(VESSEL_MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(34))), 0)
+            .expect("Cannot create a counter")
+    );
+
+    static ALERT_RULE_STORAGE: RefCell<StableBTreeMap<u64, AlertRule, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            VESSEL_MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(35)))
+    ));
+
+    static ALERT_ID_COUNTER: RefCell<IdCell> = RefCell::new(
+        IdCell::init(VESSEL_MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(36))), 0)
+            .expect("Cannot create a counter")
+    );
+
+    static ALERT_STORAGE: RefCell<StableBTreeMap<u64, Alert, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            VESSEL_MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(37)))
+    ));
+
+    // (recipient, alert_id) -> InboxEntry
+    static INBOX: RefCell<StableBTreeMap<(PrincipalKey, u64), InboxEntry, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            VESSEL_MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(38)))
+    ));
+}
+
+// Add an alert rule (admins only)
+#[ic_cdk::update]
+fn add_alert_rule(payload: AlertRulePayload) -> Result<AlertRule, Error> {
+    require_role(Role::Admin)?;
+    if payload.name.trim().is_empty() || payload.name.len() > MAX_NAME_LEN {
+        return Err(Error::InvalidInput {
+            msg: format!("name must be between 1 and {} bytes", MAX_NAME_LEN),
+        });
+    }
+    if payload.recipients.len() > MAX_RECIPIENTS
+        || payload.recipient_roles.len() > MAX_RECIPIENT_ROLES
+    {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "an alert rule may have at most {} recipients and {} recipient roles",
+                MAX_RECIPIENTS, MAX_RECIPIENT_ROLES
+            ),
+        });
+    }
+    if payload.recipients.is_empty() && payload.recipient_roles.is_empty() {
+        return Err(Error::InvalidInput {
+            msg: "an alert rule needs at least one recipient or recipient role".to_string(),
+        });
+    }
+
+    let id = ALERT_RULE_ID_COUNTER
+        .with(|counter| {
+            let current_value = *counter.borrow().get();
+            counter.borrow_mut().set(current_value + 1)
+        })
+        .expect("cannot increment alert rule id counter");
+
+    let rule = AlertRule {
+        id,
+        name: payload.name,
+        trigger: payload.trigger,
+        severity: payload.severity,
+        vessel_id: payload.vessel_id,
+        recipients: payload.recipients,
+        recipient_roles: payload.recipient_roles,
+        enabled: true,
+        created_by: caller(),
+        created_at: time(),
+    };
+    do_insert_alert_rule(&rule);
+    audit::record("add_alert_rule", "alert_rule", id, None, Some(&rule));
+    Ok(rule)
+}
+
+// Enable or disable an alert rule (admins only)
+#[ic_cdk::update]
+fn set_alert_rule_enabled(id: u64, enabled: bool) -> Result<AlertRule, Error> {
+    require_role(Role::Admin)?;
+    match _get_alert_rule(&id) {
+        Some(before) => {
+            let rule = AlertRule {
+                enabled,
+                ..before.clone()
+            };
+            do_insert_alert_rule(&rule);
+            audit::record(
+                "set_alert_rule_enabled",
+                "alert_rule",
+                id,
+                Some(&before),
+                Some(&rule),
+            );
+            Ok(rule)
+        }
+        None => Err(Error::NotFound {
+            msg: format!("an alert rule with id={} not found", id),
+        }),
+    }
+}
+
+// List all alert rules (admins only)
+#[ic_cdk::query]
+fn get_alert_rules() -> Result<Vec<AlertRule>, Error> {
+    require_role(Role::Admin)?;
+    Ok(ALERT_RULE_STORAGE.with(|service| service.borrow().iter().map(|(_, rule)| rule).collect()))
+}
+
+// List the caller's alerts, newest first
+#[ic_cdk::query]
+fn get_inbox(unacknowledged_only: bool, limit: u32) -> Result<Vec<InboxItem>, Error> {
+    let caller = require_authenticated()?;
+    let key = principal_key(&caller);
+    let entries: Vec<(u64, InboxEntry)> = INBOX.with(|inbox| {
+        inbox
+            .borrow()
+            .range((key, 0)..=(key, u64::MAX))
+            .map(|((_, alert_id), entry)| (alert_id, entry))
+            .collect()
+    });
+    Ok(entries
+        .into_iter()
+        .rev()
+        .filter(|(_, entry)| !unacknowledged_only || entry.acknowledged_at.is_none())
+        .take((limit as usize).clamp(1, MAX_INBOX_PAGE))
+        .filter_map(|(alert_id, entry)| {
+            _get_alert(&alert_id).map(|alert| InboxItem {
+                alert,
+                acknowledged_at: entry.acknowledged_at,
+            })
+        })
+        .collect())
+}
+
+// Acknowledge an alert in the caller's inbox
+#[ic_cdk::update]
+fn acknowledge_alert(id: u64) -> Result<InboxItem, Error> {
+    let caller = require_authenticated()?;
+    let key = (principal_key(&caller), id);
+    let (alert, before) = match (
+        _get_alert(&id),
+        INBOX.with(|inbox| inbox.borrow().get(&key)),
+    ) {
+        (Some(alert), Some(before)) => (alert, before),
+        _ => {
+            return Err(Error::NotFound {
+                msg: format!("an alert with id={} not found in the caller's inbox", id),
+            })
+        }
+    };
+    let entry = InboxEntry {
+        acknowledged_at: before.acknowledged_at.or(Some(time())),
+    };
+    INBOX.with(|inbox| inbox.borrow_mut().insert(key, entry.clone()));
+    audit::record(
+        "acknowledge_alert",
+        "alert",
+        id,
+        Some(&before),
+        Some(&entry),
+    );
+    Ok(InboxItem {
+        alert,
+        acknowledged_at: entry.acknowledged_at,
+    })
+}
+
+// Raise an alert for every enabled rule matching the trigger and vessel, and deliver it
+pub(crate) fn raise(trigger: AlertTrigger, vessel_id: Option<u64>, target_id: u64, message: &str) {
+    let rules: Vec<AlertRule> = ALERT_RULE_STORAGE.with(|service| {
+        service
+            .borrow()
+            .iter()
+            .map(|(_, rule)| rule)
+            .filter(|rule| rule.enabled && rule.trigger == trigger)
+            .filter(|rule| rule.vessel_id.is_none() || rule.vessel_id == vessel_id)
+            .collect()
+    });
+    for rule in rules {
+        let id = ALERT_ID_COUNTER
+            .with(|counter| {
+                let current_value = *counter.borrow().get();
+                counter.borrow_mut().set(current_value + 1)
+            })
+            .expect("cannot increment alert id counter");
+        let alert = Alert {
+            id,
+            rule_id: rule.id,
+            trigger,
+            severity: rule.severity,
+            vessel_id,
+            target_id,
+            message: truncate(message, MAX_MESSAGE_LEN),
+            raised_at: time(),
+        };
+        ALERT_STORAGE.with(|service| service.borrow_mut().insert(id, alert));
+
+        let mut recipients: BTreeSet<Principal> = rule.recipients.iter().copied().collect();
+        for role in &rule.recipient_roles {
+            recipients.extend(principals_with_role(*role));
+        }
+        // Alerts about a vessel only reach those who may view it
+        if let Some(vessel_id) = vessel_id {
+            recipients.retain(|recipient| {
+                can_principal_access_vessel(recipient, vessel_id, VesselAccess::View)
+            });
+        }
+        INBOX.with(|inbox| {
+            let mut inbox = inbox.borrow_mut();
+            for recipient in &recipients {
+                inbox.insert((principal_key(recipient), id), InboxEntry::default());
+            }
+        });
+    }
+}
+
+// Helper method to insert an AlertRule into storage
+fn do_insert_alert_rule(rule: &AlertRule) {
+    ALERT_RULE_STORAGE.with(|service| service.borrow_mut().insert(rule.id, rule.clone()));
+}
+
+// Retrieve an AlertRule by ID from storage
+fn _get_alert_rule(id: &u64) -> Option<AlertRule> {
+    ALERT_RULE_STORAGE.with(|service| service.borrow().get(id))
+}
+
+// Retrieve an Alert by ID from storage
+fn _get_alert(id: &u64) -> Option<Alert> {
+    ALERT_STORAGE.with(|service| service.borrow().get(id))
+}
+
+// Cut text to at most `max_len` bytes without splitting a character
+pub(crate) fn truncate(text: &str, max_len: usize) -> String {
+    let mut end = text.len().min(max_len);
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+    text[..end].to_string()
+}
@@ -0,0 +1,349 @@
+// Soft delete: archived vessels and voyages, restore and retention purge
+use crate::access::{require_role, Role};
+use crate::availability;
+use crate::calendar::NANOS_PER_DAY;
+use crate::events;
+use crate::organizations;
+use crate::revisions::{self, track_vessel, track_voyage};
+use crate::{
+    _get_vessel, _get_voyage, audit, check_vessel_free, do_insert_vessel, do_insert_voyage, Error,
+    IdCell, Memory, Vessel, Voyage, VESSEL_MEMORY_MANAGER,
+};
+use candid::{Decode, Encode, Principal};
+use ic_cdk::api::{caller, time};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use std::{borrow::Cow, cell::RefCell};
+
+const DEFAULT_RETENTION_DAYS: u64 = 365;
+const MAX_PURGE_BATCH: u32 = 500;
+const MAX_PAGE_SIZE: u32 = 100;
+
+// A deleted Vessel kept in the archive
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct ArchivedVessel {
+    vessel: Vessel,
+    deleted_by: Principal,
+    deleted_at: u64,
+}
+
+// A deleted Voyage kept in the archive
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct ArchivedVoyage {
+    voyage: Voyage,
+    deleted_by: Principal,
+    deleted_at: u64,
+}
+
+// Number of records removed by a purge run
+#[derive(candid::CandidType, Serialize, Deserialize, Default)]
+pub(crate) struct PurgeReport {
+    vessels: u32,
+    voyages: u32,
+}
+
+// Implement Storable trait for ArchivedVessel
+impl Storable for ArchivedVessel {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+// Implement BoundedStorable trait for ArchivedVessel
+impl BoundedStorable for ArchivedVessel {
+    const MAX_SIZE: u32 = Vessel::MAX_SIZE + 128;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Implement Storable trait for ArchivedVoyage
+impl Storable for ArchivedVoyage {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+// Implement BoundedStorable trait for ArchivedVoyage
+impl BoundedStorable for ArchivedVoyage {
+    const MAX_SIZE: u32 = Voyage::MAX_SIZE + 128;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Define thread-local variables for the archive
+thread_local! {
+    static ARCHIVED_VESSELS: RefCell<StableBTreeMap<u64, ArchivedVessel, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            VESSEL_MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(29)))
+    ));
+
+    static ARCHIVED_VOYAGES: RefCell<StableBTreeMap<u64, ArchivedVoyage, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            VESSEL_MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(30)))
+    ));
+
+    // (deleted_at, vessel_id) -> ()
+    static ARCHIVED_VESSEL_TIMES: RefCell<StableBTreeMap<(u64, u64), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            VESSEL_MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(63)))
+    ));
+
+    // (deleted_at, voyage_id) -> ()
+    static ARCHIVED_VOYAGE_TIMES: RefCell<StableBTreeMap<(u64, u64), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            VESSEL_MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(64)))
+    ));
+
+    static RETENTION_DAYS: RefCell<IdCell> = RefCell::new(
+        IdCell::init(
+            VESSEL_MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(31))),
+            DEFAULT_RETENTION_DAYS,
+        )
+        .expect("Cannot create the retention setting")
+    );
+}
+
+// List up to `limit` archived vessels in ID order, starting at `start` (admins only)
+#[ic_cdk::query]
+fn get_archived_vessels(start: u64, limit: u32) -> Result<Vec<ArchivedVessel>, Error> {
+    require_role(Role::Admin)?;
+    Ok(ARCHIVED_VESSELS.with(|service| {
+        service
+            .borrow()
+            .range(start..)
+            .take(limit.clamp(1, MAX_PAGE_SIZE) as usize)
+            .map(|(_, a)| a)
+            .collect()
+    }))
+}
+
+// List up to `limit` archived voyages in ID order, starting at `start` (admins only)
+#[ic_cdk::query]
+fn get_archived_voyages(start: u64, limit: u32) -> Result<Vec<ArchivedVoyage>, Error> {
+    require_role(Role::Admin)?;
+    Ok(ARCHIVED_VOYAGES.with(|service| {
+        service
+            .borrow()
+            .range(start..)
+            .take(limit.clamp(1, MAX_PAGE_SIZE) as usize)
+            .map(|(_, a)| a)
+            .collect()
+    }))
+}
+
+// Move an archived vessel back into storage (admins only)
+#[ic_cdk::update]
+fn restore_vessel(id: u64) -> Result<Vessel, Error> {
+    require_role(Role::Admin)?;
+    if _get_vessel(&id).is_some() {
+        return Err(Error::InvalidInput {
+            msg: format!("a vessel with id={} already exists", id),
+        });
+    }
+    match ARCHIVED_VESSELS.with(|service| service.borrow_mut().remove(&id)) {
+        Some(archived) => {
+            ARCHIVED_VESSEL_TIMES
+                .with(|index| index.borrow_mut().remove(&(archived.deleted_at, id)));
+            let vessel = archived.vessel;
+            do_insert_vessel(&vessel);
+            audit::record("restore_vessel", "vessel", id, None, Some(&vessel));
+            track_vessel(id, None, Some(&vessel));
+            events::vessel_changed(id, None, Some(&vessel));
+            Ok(vessel)
+        }
+        None => Err(Error::NotFound {
+            msg: format!("an archived vessel with id={} not found", id),
+        }),
+    }
+}
+
+// Move an archived voyage back into storage (admins only)
+#[ic_cdk::update]
+fn restore_voyage(id: u64) -> Result<Voyage, Error> {
+    require_role(Role::Admin)?;
+    if _get_voyage(&id).is_some() {
+        return Err(Error::InvalidInput {
+            msg: format!("a voyage with id={} already exists", id),
+        });
+    }
+    match ARCHIVED_VOYAGES.with(|service| service.borrow().get(&id)) {
+        Some(archived) => {
+            let voyage = archived.voyage;
+            // The voyage returns as recorded, so only its vessel and schedule are rechecked
+            if _get_vessel(&voyage.vessel_id).is_none() {
+                return Err(Error::InvalidInput {
+                    msg: format!(
+                        "the vessel id={} of voyage id={} no longer exists; restore it first",
+                        voyage.vessel_id, id
+                    ),
+                });
+            }
+            check_vessel_free(&voyage, &[])?;
+            ARCHIVED_VOYAGES.with(|service| service.borrow_mut().remove(&id));
+            ARCHIVED_VOYAGE_TIMES
+                .with(|index| index.borrow_mut().remove(&(archived.deleted_at, id)));
+            do_insert_voyage(&voyage);
+            audit::record("restore_voyage", "voyage", id, None, Some(&voyage));
+            track_voyage(id, None, Some(&voyage));
+            events::voyage_changed(id, None, Some(&voyage));
+            Ok(voyage)
+        }
+        None => Err(Error::NotFound {
+            msg: format!("an archived voyage with id={} not found", id),
+        }),
+    }
+}
+
+// Read the archive retention period in days
+#[ic_cdk::query]
+fn get_archive_retention_days() -> u64 {
+    RETENTION_DAYS.with(|cell| *cell.borrow().get())
+}
+
+// Change the archive retention period (admins only)
+#[ic_cdk::update]
+fn set_archive_retention_days(days: u64) -> Result<(), Error> {
+    require_role(Role::Admin)?;
+    let before = get_archive_retention_days();
+    RETENTION_DAYS
+        .with(|cell| cell.borrow_mut().set(days))
+        .expect("cannot update the retention setting");
+    audit::record(
+        "set_archive_retention_days",
+        "archive",
+        "retention_days",
+        Some(&before),
+        Some(&days),
+    );
+    Ok(())
+}
+
+// Permanently remove archived records older than the retention period (admins only)
+#[ic_cdk::update]
+fn purge_archive(limit: u32) -> Result<PurgeReport, Error> {
+    require_role(Role::Admin)?;
+    Ok(purge_expired(limit.min(MAX_PURGE_BATCH)))
+}
+
+// Archive a deleted vessel
+pub(crate) fn archive_vessel(vessel: Vessel) {
+    let archived = ArchivedVessel {
+        vessel,
+        deleted_by: caller(),
+        deleted_at: time(),
+    };
+    let id = archived.vessel.id;
+    ARCHIVED_VESSEL_TIMES.with(|index| index.borrow_mut().insert((archived.deleted_at, id), ()));
+    let replaced = ARCHIVED_VESSELS.with(|service| service.borrow_mut().insert(id, archived));
+    if let Some(replaced) = replaced {
+        ARCHIVED_VESSEL_TIMES.with(|index| index.borrow_mut().remove(&(replaced.deleted_at, id)));
+    }
+}
+
+// Archive a deleted voyage
+pub(crate) fn archive_voyage(voyage: Voyage) {
+    let archived = ArchivedVoyage {
+        voyage,
+        deleted_by: caller(),
+        deleted_at: time(),
+    };
+    let id = archived.voyage.id;
+    ARCHIVED_VOYAGE_TIMES.with(|index| index.borrow_mut().insert((archived.deleted_at, id), ()));
+    let replaced = ARCHIVED_VOYAGES.with(|service| service.borrow_mut().insert(id, archived));
+    if let Some(replaced) = replaced {
+        ARCHIVED_VOYAGE_TIMES.with(|index| index.borrow_mut().remove(&(replaced.deleted_at, id)));
+    }
+}
+
+// Index the archived records stored before the deletion time indexes existed
+pub(crate) fn backfill_time_indexes() {
+    let indexed = ARCHIVED_VESSEL_TIMES.with(|index| !index.borrow().is_empty())
+        || ARCHIVED_VOYAGE_TIMES.with(|index| !index.borrow().is_empty());
+    if indexed {
+        return;
+    }
+    ARCHIVED_VESSELS.with(|service| {
+        ARCHIVED_VESSEL_TIMES.with(|index| {
+            let mut index = index.borrow_mut();
+            for (id, archived) in service.borrow().iter() {
+                index.insert((archived.deleted_at, id), ());
+            }
+        })
+    });
+    ARCHIVED_VOYAGES.with(|service| {
+        ARCHIVED_VOYAGE_TIMES.with(|index| {
+            let mut index = index.borrow_mut();
+            for (id, archived) in service.borrow().iter() {
+                index.insert((archived.deleted_at, id), ());
+            }
+        })
+    });
+}
+
+// Helper method to list up to `limit` IDs deleted at or before the cutoff, oldest first
+fn deleted_before(
+    index: &StableBTreeMap<(u64, u64), (), Memory>,
+    cutoff: u64,
+    limit: u32,
+) -> Vec<(u64, u64)> {
+    index
+        .range((0, 0)..=(cutoff, u64::MAX))
+        .take(limit as usize)
+        .map(|(key, _)| key)
+        .collect()
+}
+
+// Remove up to `limit` archived records whose retention period has passed, oldest first
+pub(crate) fn purge_expired(limit: u32) -> PurgeReport {
+    let retention = get_archive_retention_days().saturating_mul(NANOS_PER_DAY);
+    let cutoff = time().saturating_sub(retention);
+    let mut report = PurgeReport::default();
+
+    let vessels =
+        ARCHIVED_VESSEL_TIMES.with(|index| deleted_before(&index.borrow(), cutoff, limit));
+    for key in vessels {
+        ARCHIVED_VESSEL_TIMES.with(|index| index.borrow_mut().remove(&key));
+        let Some(archived) = ARCHIVED_VESSELS.with(|service| service.borrow_mut().remove(&key.1))
+        else {
+            continue;
+        };
+        organizations::forget_vessel(archived.vessel.id);
+        availability::forget_vessel(archived.vessel.id);
+        revisions::forget_vessel(archived.vessel.id);
+        audit::record(
+            "purge_archive",
+            "vessel",
+            archived.vessel.id,
+            Some(&archived),
+            None,
+        );
+        report.vessels += 1;
+    }
+
+    let remaining = limit - report.vessels;
+    let voyages =
+        ARCHIVED_VOYAGE_TIMES.with(|index| deleted_before(&index.borrow(), cutoff, remaining));
+    for key in voyages {
+        ARCHIVED_VOYAGE_TIMES.with(|index| index.borrow_mut().remove(&key));
+        let Some(archived) = ARCHIVED_VOYAGES.with(|service| service.borrow_mut().remove(&key.1))
+        else {
+            continue;
+        };
+        revisions::forget_voyage(archived.voyage.id);
+        audit::record(
+            "purge_archive",
+            "voyage",
+            archived.voyage.id,
+            Some(&archived),
+            None,
+        );
+        report.voyages += 1;
+    }
+    report
+}
@@ -0,0 +1,204 @@
+// Append-only, hash-chained audit log of every state-changing call
+use crate::access::{require_role, Role};
+use crate::{Error, Memory, VESSEL_MEMORY_MANAGER};
+use candid::{Decode, Encode, Principal};
+use ic_cdk::api::{caller, time};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{StableLog, Storable};
+use serde_json::{Map, Value};
+use sha2::{Digest, Sha256};
+use std::{borrow::Cow, cell::RefCell};
+
+const MAX_PAGE_SIZE: u64 = 100;
+const MAX_VERIFY_BATCH: u64 = 1000;
+
+// One field whose value changed, rendered as JSON
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, PartialEq, Debug)]
+pub(crate) struct FieldChange {
+    field: String,
+    before: Option<String>,
+    after: Option<String>,
+}
+
+// Define the structure for an audit log entry
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct AuditEntry {
+    seq: u64,
+    caller: Principal,
+    method: String,
+    entity: String,
+    target_id: String,
+    timestamp: u64,
+    changes: Vec<FieldChange>,
+    // Hash of the previous entry, empty for the first one
+    prev_hash: Vec<u8>,
+    // SHA-256 over prev_hash and the candid encoding of this entry with an empty hash
+    hash: Vec<u8>,
+}
+
+// A page of the audit log
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct AuditPage {
+    entries: Vec<AuditEntry>,
+    total: u64,
+    next: Option<u64>,
+}
+
+// Result of re-computing the hash chain over a range of entries
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct AuditVerification {
+    checked: u64,
+    // Sequence number of the first entry whose hash does not match, if any
+    first_invalid: Option<u64>,
+    next: Option<u64>,
+}
+
+impl AuditEntry {
+    fn compute_hash(&self) -> Vec<u8> {
+        let unhashed = AuditEntry {
+            hash: Vec::new(),
+            ..self.clone()
+        };
+        let mut hasher = Sha256::new();
+        hasher.update(&self.prev_hash);
+        hasher.update(Encode!(&unhashed).unwrap());
+        hasher.finalize().to_vec()
+    }
+}
+
+// Implement Storable trait for AuditEntry
+impl Storable for AuditEntry {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+// Define thread-local variables for the audit log
+thread_local! {
+    static AUDIT_LOG: RefCell<StableLog<AuditEntry, Memory, Memory>> = RefCell::new(
+        StableLog::init(
+            VESSEL_MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(25))),
+            VESSEL_MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(26))),
+        )
+        .expect("Cannot create the audit log")
+    );
+}
+
+// Read a page of the audit log starting at a sequence number. The log holds the
+// changes of every organization's records, so it is for admins only.
+#[ic_cdk::query]
+fn get_audit_log(start: u64, limit: u64) -> Result<AuditPage, Error> {
+    require_role(Role::Admin)?;
+    let limit = limit.clamp(1, MAX_PAGE_SIZE);
+    Ok(AUDIT_LOG.with(|log| {
+        let log = log.borrow();
+        let total = log.len();
+        let end = start.saturating_add(limit).min(total);
+        AuditPage {
+            entries: (start..end).filter_map(|seq| log.get(seq)).collect(),
+            total,
+            next: (end < total).then_some(end),
+        }
+    }))
+}
+
+// Re-compute the hash chain over a range of entries to detect tampering (admins only)
+#[ic_cdk::query]
+fn verify_audit_log(start: u64, limit: u64) -> Result<AuditVerification, Error> {
+    require_role(Role::Admin)?;
+    let limit = limit.clamp(1, MAX_VERIFY_BATCH);
+    AUDIT_LOG.with(|log| {
+        let log = log.borrow();
+        let total = log.len();
+        if start > total {
+            return Err(Error::InvalidInput {
+                msg: format!("start={} is beyond the end of the log ({})", start, total),
+            });
+        }
+        let end = start.saturating_add(limit).min(total);
+        let mut prev_hash = match start {
+            0 => Vec::new(),
+            _ => log.get(start - 1).map(|e| e.hash).unwrap_or_default(),
+        };
+        let mut first_invalid = None;
+        for seq in start..end {
+            let entry = log.get(seq).expect("audit entry within bounds");
+            if entry.seq != seq
+                || entry.prev_hash != prev_hash
+                || entry.compute_hash() != entry.hash
+            {
+                first_invalid = Some(seq);
+                break;
+            }
+            prev_hash = entry.hash;
+        }
+        Ok(AuditVerification {
+            checked: end - start,
+            first_invalid,
+            next: (first_invalid.is_none() && end < total).then_some(end),
+        })
+    })
+}
+
+// Append an entry describing a state change made by the current caller
+pub(crate) fn record<T: serde::Serialize>(
+    method: &str,
+    entity: &str,
+    target_id: impl ToString,
+    before: Option<&T>,
+    after: Option<&T>,
+) {
+    AUDIT_LOG.with(|log| {
+        let log = log.borrow();
+        let seq = log.len();
+        let prev_hash = match seq {
+            0 => Vec::new(),
+            _ => log.get(seq - 1).map(|e| e.hash).unwrap_or_default(),
+        };
+        let mut entry = AuditEntry {
+            seq,
+            caller: caller(),
+            method: method.to_string(),
+            entity: entity.to_string(),
+            target_id: target_id.to_string(),
+            timestamp: time(),
+            changes: diff(before, after),
+            prev_hash,
+            hash: Vec::new(),
+        };
+        entry.hash = entry.compute_hash();
+        log.append(&entry).expect("cannot append to the audit log");
+    });
+}
+
+// Compare two records field by field
+fn diff<T: serde::Serialize>(before: Option<&T>, after: Option<&T>) -> Vec<FieldChange> {
+    let before = fields(before);
+    let after = fields(after);
+    let mut names: Vec<&String> = before.keys().chain(after.keys()).collect();
+    names.sort();
+    names.dedup();
+    names
+        .into_iter()
+        .filter(|name| before.get(*name) != after.get(*name))
+        .map(|name| FieldChange {
+            field: name.clone(),
+            before: before.get(name).map(Value::to_string),
+            after: after.get(name).map(Value::to_string),
+        })
+        .collect()
+}
+
+// Flatten a record into its top-level JSON fields; non-object values become a single "value" field
+fn fields<T: serde::Serialize>(record: Option<&T>) -> Map<String, Value> {
+    match record.map(serde_json::to_value) {
+        Some(Ok(Value::Object(map))) => map,
+        Some(Ok(Value::Null)) | None => Map::new(),
+        Some(Ok(value)) => Map::from_iter([("value".to_string(), value)]),
+        Some(Err(e)) => Map::from_iter([("error".to_string(), Value::String(e.to_string()))]),
+    }
+}
@@ -0,0 +1,616 @@
+// Vessel operational status history and the availability calendar
+use crate::audit;
+use crate::organizations::{can_access_vessel, require_vessel_access, VesselAccess};
+use crate::{
+    _get_vessel, vessel_voyages, Error, IdCell, Memory, Voyage, VESSEL_MEMORY_MANAGER,
+    VOYAGE_STORAGE,
+};
+use candid::{Decode, Encode, Principal};
+use ic_cdk::api::{caller, time};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use std::collections::BTreeMap;
+use std::{borrow::Cow, cell::RefCell};
+
+const MAX_NOTE_LEN: usize = 500;
+
+// Define what a vessel is doing operationally
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Debug)]
+pub(crate) enum OperationalStatus {
+    Sailing,
+    InPort,
+    AtAnchor,
+    DryDock,
+    LaidUp,
+    OffHire,
+}
+
+// Define the structure for an entry of a vessel's status history
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct StatusChange {
+    vessel_id: u64,
+    pub(crate) status: OperationalStatus,
+    since: u64,
+    note: Option<String>,
+    changed_by: Principal,
+}
+
+// Define the reasons a vessel can be blocked out of the calendar
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Debug)]
+pub(crate) enum BlockKind {
+    Maintenance,
+    DryDock,
+    LayUp,
+    OffHire,
+}
+
+// Define the structure for a period a vessel is unavailable for voyages
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct CalendarBlock {
+    id: u64,
+    vessel_id: u64,
+    kind: BlockKind,
+    // Blocks cover [start, end)
+    start: u64,
+    end: u64,
+    note: Option<String>,
+    created_by: Principal,
+}
+
+// Payload used to block out a period
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct CalendarBlockPayload {
+    vessel_id: u64,
+    kind: BlockKind,
+    start: u64,
+    end: u64,
+    note: Option<String>,
+}
+
+// What occupies a vessel during a booking
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) enum BookingKind {
+    Voyage {
+        voyage_id: u64,
+        departure_port: String,
+        destination_port: String,
+    },
+    Block {
+        block_id: u64,
+        kind: BlockKind,
+        note: Option<String>,
+    },
+}
+
+// A period the vessel is occupied; end is None for open voyages without a future ETA or schedule
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct Booking {
+    start: u64,
+    end: Option<u64>,
+    kind: BookingKind,
+}
+
+// A period the vessel is free
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct TimeWindow {
+    start: u64,
+    end: u64,
+}
+
+// Two stored voyages of the same vessel that occupy it at the same time
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct VoyageOverlap {
+    vessel_id: u64,
+    first_voyage_id: u64,
+    second_voyage_id: u64,
+    overlap_start: u64,
+    // None when both voyages are open-ended
+    overlap_end: Option<u64>,
+}
+
+// The calendar of a vessel over a requested period
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct VesselAvailability {
+    vessel_id: u64,
+    status: Option<StatusChange>,
+    bookings: Vec<Booking>,
+    free: Vec<TimeWindow>,
+}
+
+// Implement Storable trait for StatusChange
+impl Storable for StatusChange {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+// Implement BoundedStorable trait for StatusChange
+impl BoundedStorable for StatusChange {
+    const MAX_SIZE: u32 = 1024;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Implement Storable trait for CalendarBlock
+impl Storable for CalendarBlock {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+// Implement BoundedStorable trait for CalendarBlock
+impl BoundedStorable for CalendarBlock {
+    const MAX_SIZE: u32 = 1024;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Define thread-local variables for status and calendar storage
+thread_local! {
+    // (vessel_id, since) -> StatusChange
+    static STATUS_HISTORY: RefCell<StableBTreeMap<(u64, u64), StatusChange, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            VESSEL_MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(55)))
+    ));
+
+    static BLOCK_ID_COUNTER: RefCell<IdCell> = RefCell::new(
+        IdCell::init(VESSEL_MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(56))), 0)
+            .expect("Cannot create a counter")
+    );
+
+    static BLOCK_STORAGE: RefCell<StableBTreeMap<u64, CalendarBlock, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            VESSEL_MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(57)))
+    ));
+
+    // (vessel_id, block_id) -> ()
+    static VESSEL_BLOCK_INDEX: RefCell<StableBTreeMap<(u64, u64), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            VESSEL_MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(58)))
+    ));
+}
+
+// Record the operational status of a vessel as of now
+#[ic_cdk::update]
+fn set_operational_status(
+    vessel_id: u64,
+    status: OperationalStatus,
+    note: Option<String>,
+) -> Result<StatusChange, Error> {
+    require_vessel_access(vessel_id, VesselAccess::Edit)?;
+    require_vessel(vessel_id)?;
+    check_note(&note)?;
+
+    let before = current_status(vessel_id);
+    let change = StatusChange {
+        vessel_id,
+        status,
+        since: time(),
+        note,
+        changed_by: caller(),
+    };
+    STATUS_HISTORY.with(|service| {
+        service
+            .borrow_mut()
+            .insert((vessel_id, change.since), change.clone())
+    });
+    audit::record(
+        "set_operational_status",
+        "vessel_status",
+        vessel_id,
+        before.as_ref(),
+        Some(&change),
+    );
+    Ok(change)
+}
+
+// Retrieve the current operational status of a vessel; None if it was never set
+#[ic_cdk::query]
+fn get_operational_status(vessel_id: u64) -> Result<Option<StatusChange>, Error> {
+    require_vessel_access(vessel_id, VesselAccess::View)?;
+    require_vessel(vessel_id)?;
+    Ok(current_status(vessel_id))
+}
+
+// List the status changes of a vessel, most recent first
+#[ic_cdk::query]
+fn get_operational_status_history(vessel_id: u64) -> Result<Vec<StatusChange>, Error> {
+    require_vessel_access(vessel_id, VesselAccess::View)?;
+    require_vessel(vessel_id)?;
+    Ok(STATUS_HISTORY.with(|service| {
+        service
+            .borrow()
+            .range((vessel_id, 0)..=(vessel_id, u64::MAX))
+            .map(|(_, change)| change)
+            .collect::<Vec<_>>()
+            .into_iter()
+            .rev()
+            .collect()
+    }))
+}
+
+// Block out a period in which the vessel cannot sail
+#[ic_cdk::update]
+fn add_calendar_block(payload: CalendarBlockPayload) -> Result<CalendarBlock, Error> {
+    require_vessel_access(payload.vessel_id, VesselAccess::Edit)?;
+    require_vessel(payload.vessel_id)?;
+    check_note(&payload.note)?;
+    if payload.start >= payload.end {
+        return Err(Error::InvalidInput {
+            msg: "start must be before end".to_string(),
+        });
+    }
+    check_available(payload.vessel_id, payload.start, payload.end, &[])?;
+
+    // Generate a new ID for the CalendarBlock
+    let id = BLOCK_ID_COUNTER
+        .with(|counter| {
+            let current_value = *counter.borrow().get();
+            counter.borrow_mut().set(current_value + 1)
+        })
+        .expect("cannot increment calendar block id counter");
+
+    let block = CalendarBlock {
+        id,
+        vessel_id: payload.vessel_id,
+        kind: payload.kind,
+        start: payload.start,
+        end: payload.end,
+        note: payload.note,
+        created_by: caller(),
+    };
+    BLOCK_STORAGE.with(|service| service.borrow_mut().insert(id, block.clone()));
+    VESSEL_BLOCK_INDEX.with(|index| index.borrow_mut().insert((block.vessel_id, id), ()));
+    audit::record(
+        "add_calendar_block",
+        "calendar_block",
+        id,
+        None,
+        Some(&block),
+    );
+    Ok(block)
+}
+
+// Remove a calendar block
+#[ic_cdk::update]
+fn remove_calendar_block(id: u64) -> Result<(), Error> {
+    let block = BLOCK_STORAGE
+        .with(|service| service.borrow().get(&id))
+        .ok_or(Error::NotFound {
+            msg: format!("a calendar block with id={} not found", id),
+        })?;
+    require_vessel_access(block.vessel_id, VesselAccess::Edit)?;
+    BLOCK_STORAGE.with(|service| service.borrow_mut().remove(&id));
+    VESSEL_BLOCK_INDEX.with(|index| index.borrow_mut().remove(&(block.vessel_id, id)));
+    audit::record(
+        "remove_calendar_block",
+        "calendar_block",
+        id,
+        Some(&block),
+        None,
+    );
+    Ok(())
+}
+
+// List the voyages and blocks of a vessel overlapping [from, to) and the free windows between them
+#[ic_cdk::query]
+fn get_vessel_availability(
+    vessel_id: u64,
+    from: u64,
+    to: u64,
+) -> Result<VesselAvailability, Error> {
+    require_vessel_access(vessel_id, VesselAccess::View)?;
+    require_vessel(vessel_id)?;
+    if from >= to {
+        return Err(Error::InvalidInput {
+            msg: "from must be before to".to_string(),
+        });
+    }
+
+    let mut bookings: Vec<Booking> = vessel_voyages(vessel_id)
+        .into_iter()
+        .filter_map(|voyage| {
+            let (start, end) = voyage_window(&voyage);
+            overlaps((start, end), (from, to)).then(|| Booking {
+                start,
+                end: (end != u64::MAX).then_some(end),
+                kind: BookingKind::Voyage {
+                    voyage_id: voyage.id,
+                    departure_port: voyage.departure_port,
+                    destination_port: voyage.destination_port,
+                },
+            })
+        })
+        .collect();
+    bookings.extend(
+        vessel_blocks(vessel_id)
+            .into_iter()
+            .filter(|block| overlaps((block.start, block.end), (from, to)))
+            .map(|block| Booking {
+                start: block.start,
+                end: Some(block.end),
+                kind: BookingKind::Block {
+                    block_id: block.id,
+                    kind: block.kind,
+                    note: block.note,
+                },
+            }),
+    );
+    bookings.sort_by_key(|booking| booking.start);
+
+    // Walk the bookings in start order, emitting the gaps between them
+    let mut free = Vec::new();
+    let mut cursor = from;
+    for booking in &bookings {
+        if booking.start > cursor {
+            free.push(TimeWindow {
+                start: cursor,
+                end: booking.start.min(to),
+            });
+        }
+        cursor = cursor.max(booking.end.unwrap_or(u64::MAX));
+        if cursor >= to {
+            break;
+        }
+    }
+    if cursor < to {
+        free.push(TimeWindow {
+            start: cursor,
+            end: to,
+        });
+    }
+
+    Ok(VesselAvailability {
+        vessel_id,
+        status: current_status(vessel_id),
+        bookings,
+        free,
+    })
+}
+
+// Find stored voyages that overlap another voyage of the same vessel, for cleaning up data
+// recorded before overlaps were rejected; all visible vessels unless one is given
+#[ic_cdk::query]
+fn get_voyage_overlaps(vessel_id: Option<u64>) -> Result<Vec<VoyageOverlap>, Error> {
+    if let Some(vessel_id) = vessel_id {
+        require_vessel_access(vessel_id, VesselAccess::View)?;
+    }
+    let voyages: Vec<Voyage> = match vessel_id {
+        Some(vessel_id) => vessel_voyages(vessel_id),
+        None => VOYAGE_STORAGE.with(|service| service.borrow().iter().map(|(_, v)| v).collect()),
+    };
+    let mut by_vessel: BTreeMap<u64, Vec<(u64, u64, u64)>> = BTreeMap::new();
+    for voyage in voyages {
+        let (start, end) = voyage_window(&voyage);
+        by_vessel
+            .entry(voyage.vessel_id)
+            .or_default()
+            .push((start, end, voyage.id));
+    }
+
+    let mut found = Vec::new();
+    for (vessel_id, mut windows) in by_vessel {
+        if !can_access_vessel(vessel_id, VesselAccess::View) {
+            continue;
+        }
+        // Sorted by start, a voyage can only overlap the voyages starting before it ends
+        windows.sort();
+        for (i, &(start, end, id)) in windows.iter().enumerate() {
+            for &(other_start, other_end, other_id) in &windows[i + 1..] {
+                if other_start >= end {
+                    break;
+                }
+                if !overlaps((start, end), (other_start, other_end)) {
+                    continue;
+                }
+                let overlap_end = end.min(other_end);
+                found.push(VoyageOverlap {
+                    vessel_id,
+                    first_voyage_id: id,
+                    second_voyage_id: other_id,
+                    overlap_start: other_start,
+                    overlap_end: (overlap_end != u64::MAX).then_some(overlap_end),
+                });
+            }
+        }
+    }
+    Ok(found)
+}
+
+// The voyage a vessel is on: departed and not yet arrived, ignoring the voyages in `except`
+pub(crate) fn active_voyage(vessel_id: u64, except: &[u64]) -> Option<Voyage> {
+    let now = time();
+    vessel_voyages(vessel_id)
+        .into_iter()
+        .filter(|voyage| !except.contains(&voyage.id))
+        .find(|voyage| voyage.arrival_time.is_none() && voyage.departure_time <= now)
+}
+
+// The period a voyage occupies its vessel: until arrival, else until a future ETA or
+// scheduled arrival, else indefinitely
+pub(crate) fn voyage_window(voyage: &Voyage) -> (u64, u64) {
+    window_at(voyage, time())
+}
+
+// The window of a voyage as seen at `now`
+fn window_at(voyage: &Voyage, now: u64) -> (u64, u64) {
+    let start = voyage.departure_time;
+    let expected = |at: &u64| *at > start && *at > now;
+    let end = match voyage.arrival_time {
+        Some(arrival) => arrival,
+        None => voyage
+            .eta
+            .filter(expected)
+            .or(voyage.scheduled_arrival.filter(expected))
+            .unwrap_or(u64::MAX),
+    };
+    (start, end)
+}
+
+// Fail if [start, end) overlaps a voyage not in `except` or a block of the vessel
+pub(crate) fn check_available(
+    vessel_id: u64,
+    start: u64,
+    end: u64,
+    except: &[u64],
+) -> Result<(), Error> {
+    if let Some(voyage) = vessel_voyages(vessel_id)
+        .into_iter()
+        .filter(|voyage| !except.contains(&voyage.id))
+        .find(|voyage| overlaps(voyage_window(voyage), (start, end)))
+    {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "vessel id={} is already booked for voyage id={}",
+                vessel_id, voyage.id
+            ),
+        });
+    }
+    if let Some(block) = vessel_blocks(vessel_id)
+        .into_iter()
+        .find(|block| overlaps((block.start, block.end), (start, end)))
+    {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "vessel id={} is unavailable for {:?} (calendar block id={})",
+                vessel_id, block.kind, block.id
+            ),
+        });
+    }
+    Ok(())
+}
+
+// Drop the status history and calendar blocks of a vessel that is gone for good
+pub(crate) fn forget_vessel(vessel_id: u64) {
+    let since: Vec<u64> = STATUS_HISTORY.with(|service| {
+        service
+            .borrow()
+            .range((vessel_id, 0)..=(vessel_id, u64::MAX))
+            .map(|((_, since), _)| since)
+            .collect()
+    });
+    for since in since {
+        STATUS_HISTORY.with(|service| service.borrow_mut().remove(&(vessel_id, since)));
+    }
+    for block in vessel_blocks(vessel_id) {
+        BLOCK_STORAGE.with(|service| service.borrow_mut().remove(&block.id));
+        VESSEL_BLOCK_INDEX.with(|index| index.borrow_mut().remove(&(vessel_id, block.id)));
+    }
+}
+
+// Half-open intervals overlap when each starts before the other ends
+pub(crate) fn overlaps(a: (u64, u64), b: (u64, u64)) -> bool {
+    a.0 < b.1 && b.0 < a.1
+}
+
+// The latest status change of a vessel; None if its status was never set
+pub(crate) fn current_status(vessel_id: u64) -> Option<StatusChange> {
+    STATUS_HISTORY.with(|service| {
+        service
+            .borrow()
+            .iter_upper_bound(&(vessel_id, u64::MAX))
+            .next()
+            .filter(|((id, _), _)| *id == vessel_id)
+            .map(|(_, change)| change)
+    })
+}
+
+fn vessel_blocks(vessel_id: u64) -> Vec<CalendarBlock> {
+    let ids: Vec<u64> = VESSEL_BLOCK_INDEX.with(|index| {
+        index
+            .borrow()
+            .range((vessel_id, 0)..=(vessel_id, u64::MAX))
+            .map(|((_, id), _)| id)
+            .collect()
+    });
+    BLOCK_STORAGE.with(|service| {
+        let service = service.borrow();
+        ids.into_iter().filter_map(|id| service.get(&id)).collect()
+    })
+}
+
+fn require_vessel(vessel_id: u64) -> Result<(), Error> {
+    if _get_vessel(&vessel_id).is_none() {
+        return Err(Error::NotFound {
+            msg: format!("a vessel with id={} not found", vessel_id),
+        });
+    }
+    Ok(())
+}
+
+fn check_note(note: &Option<String>) -> Result<(), Error> {
+    if note.as_ref().is_some_and(|note| note.len() > MAX_NOTE_LEN) {
+        return Err(Error::InvalidInput {
+            msg: format!("note must not exceed {} bytes", MAX_NOTE_LEN),
+        });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn voyage(departure_time: u64) -> Voyage {
+        Voyage {
+            departure_time,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn adjacent_windows_do_not_overlap() {
+        assert!(!overlaps((10, 20), (20, 30)));
+        assert!(!overlaps((20, 30), (10, 20)));
+        assert!(overlaps((10, 21), (20, 30)));
+        assert!(overlaps((10, 40), (20, 30)));
+        assert!(overlaps((10, u64::MAX), (1_000, 2_000)));
+    }
+
+    #[test]
+    fn window_ends_at_arrival() {
+        let voyage = Voyage {
+            arrival_time: Some(50),
+            eta: Some(500),
+            ..voyage(10)
+        };
+        assert_eq!(window_at(&voyage, 100), (10, 50));
+    }
+
+    #[test]
+    fn window_ends_at_a_future_eta_before_the_schedule() {
+        let voyage = Voyage {
+            eta: Some(200),
+            scheduled_arrival: Some(300),
+            ..voyage(10)
+        };
+        assert_eq!(window_at(&voyage, 100), (10, 200));
+    }
+
+    #[test]
+    fn window_falls_back_to_the_scheduled_arrival_once_the_eta_passed() {
+        let voyage = Voyage {
+            eta: Some(50),
+            scheduled_arrival: Some(300),
+            ..voyage(10)
+        };
+        assert_eq!(window_at(&voyage, 100), (10, 300));
+    }
+
+    #[test]
+    fn window_is_open_ended_without_an_expected_arrival() {
+        assert_eq!(window_at(&voyage(10), 100), (10, u64::MAX));
+        // Expected times before the departure are ignored
+        let voyage = Voyage {
+            eta: Some(400),
+            ..voyage(500)
+        };
+        assert_eq!(window_at(&voyage, 100), (500, u64::MAX));
+    }
+}
@@ -0,0 +1,312 @@
+// All-or-nothing batch create/update/delete for vessels and voyages
+use crate::availability::{overlaps, voyage_window};
+use crate::organizations::{require_vessel_access, resolve_owner, VesselAccess};
+use crate::{
+    _get_vessel, _get_voyage, check_new_voyage, do_add_vessel, do_add_voyage, do_delete_vessel,
+    do_delete_voyage, do_update_vessel, do_update_voyage, new_voyage_times, patch_vessel,
+    patch_voyage, Error, Vessel, VesselPatch, Voyage, VoyagePatch,
+};
+use std::collections::BTreeSet;
+
+// Every item is audited and revisioned, so keep batches well within the instruction limit
+pub(crate) const MAX_BATCH_SIZE: usize = 100;
+
+// One operation in a vessel batch
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) enum VesselOperation {
+    Create(Vessel),
+    Update {
+        id: u64,
+        expected_version: u64,
+        patch: VesselPatch,
+    },
+    Delete {
+        id: u64,
+    },
+}
+
+// One operation in a voyage batch
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) enum VoyageOperation {
+    Create(Voyage),
+    Update {
+        id: u64,
+        expected_version: u64,
+        patch: VoyagePatch,
+    },
+    Delete {
+        id: u64,
+    },
+}
+
+// Outcome of one batch item; `id` is set once the batch was applied
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct BatchItemResult {
+    index: u32,
+    id: Option<u64>,
+    error: Option<Error>,
+}
+
+// Outcome of a batch; nothing is applied unless every item is valid
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct BatchResult {
+    applied: bool,
+    items: Vec<BatchItemResult>,
+}
+
+// A validated operation, ready to be applied
+enum Planned<T> {
+    Create(T),
+    Update(T, T),
+    Delete(T),
+}
+
+// Validate and apply a batch of vessel operations; created vessels are owned by
+// `owner_organization` or else the caller's only organization
+#[ic_cdk::update]
+fn batch_vessels(
+    operations: Vec<VesselOperation>,
+    owner_organization: Option<u64>,
+) -> Result<BatchResult, Error> {
+    check_batch_size(operations.len())?;
+    let creates = operations
+        .iter()
+        .any(|operation| matches!(operation, VesselOperation::Create(_)));
+    let owner = if creates {
+        resolve_owner(owner_organization)?
+    } else {
+        None
+    };
+
+    // Validate every operation before applying any of them
+    let mut targets = BTreeSet::new();
+    let checked = operations
+        .into_iter()
+        .map(|operation| match operation {
+            VesselOperation::Create(vessel) => Ok(Planned::Create(vessel)),
+            VesselOperation::Update {
+                id,
+                expected_version,
+                patch,
+            } => claim_target(&mut targets, "vessel", id)
+                .and_then(|_| patch_vessel(id, expected_version, patch))
+                .map(|(before, updated)| Planned::Update(before, updated)),
+            VesselOperation::Delete { id } => claim_target(&mut targets, "vessel", id)
+                .and_then(|_| require_vessel_access(id, VesselAccess::Edit))
+                .and_then(|_| {
+                    _get_vessel(&id).ok_or(Error::NotFound {
+                        msg: format!("a vessel with id={} not found", id),
+                    })
+                })
+                .map(Planned::Delete),
+        })
+        .collect();
+
+    Ok(apply_all(checked, |planned| match planned {
+        Planned::Create(vessel) => do_add_vessel("batch_vessels", vessel, owner).id,
+        Planned::Update(before, updated) => {
+            do_update_vessel("batch_vessels", &before, &updated);
+            updated.id
+        }
+        Planned::Delete(vessel) => {
+            let id = vessel.id;
+            do_delete_vessel("batch_vessels", vessel);
+            id
+        }
+    }))
+}
+
+// Validate and apply a batch of voyage operations
+#[ic_cdk::update]
+fn batch_voyages(operations: Vec<VoyageOperation>) -> Result<BatchResult, Error> {
+    check_batch_size(operations.len())?;
+
+    // The stored windows of updated and deleted voyages are released by the batch, so every
+    // item is checked against the other stored voyages and the windows claimed in the batch
+    let moved: Vec<u64> = operations
+        .iter()
+        .filter_map(|operation| match operation {
+            VoyageOperation::Create(_) => None,
+            VoyageOperation::Update { id, .. } | VoyageOperation::Delete { id } => Some(*id),
+        })
+        .collect();
+
+    // Validate every operation before applying any of them
+    let mut targets = BTreeSet::new();
+    let mut claimed = Vec::new();
+    let checked = operations
+        .into_iter()
+        .map(|operation| match operation {
+            VoyageOperation::Create(voyage) => {
+                claim_window(&mut claimed, &new_voyage_times(&voyage))
+                    .and_then(|_| check_new_voyage(&voyage, &moved))
+                    .map(|_| Planned::Create(voyage))
+            }
+            VoyageOperation::Update {
+                id,
+                expected_version,
+                patch,
+            } => claim_target(&mut targets, "voyage", id)
+                .and_then(|_| patch_voyage(id, expected_version, patch, &moved))
+                .and_then(|(before, updated)| {
+                    claim_window(&mut claimed, &updated).map(|_| Planned::Update(before, updated))
+                }),
+            VoyageOperation::Delete { id } => claim_target(&mut targets, "voyage", id)
+                .and_then(|_| {
+                    _get_voyage(&id).ok_or(Error::NotFound {
+                        msg: format!("a voyage with id={} not found", id),
+                    })
+                })
+                .and_then(|voyage| {
+                    require_vessel_access(voyage.vessel_id, VesselAccess::Voyages).map(|_| voyage)
+                })
+                .map(Planned::Delete),
+        })
+        .collect();
+
+    Ok(apply_all(checked, |planned| match planned {
+        Planned::Create(voyage) => do_add_voyage("batch_voyages", voyage).id,
+        Planned::Update(before, updated) => {
+            do_update_voyage("batch_voyages", &before, &updated);
+            updated.id
+        }
+        Planned::Delete(voyage) => {
+            let id = voyage.id;
+            do_delete_voyage("batch_voyages", voyage);
+            id
+        }
+    }))
+}
+
+// Reject empty batches and batches over the size cap
+fn check_batch_size(len: usize) -> Result<(), Error> {
+    if len == 0 || len > MAX_BATCH_SIZE {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "a batch must hold between 1 and {} operations, got {}",
+                MAX_BATCH_SIZE, len
+            ),
+        });
+    }
+    Ok(())
+}
+
+// Items are validated against the stored state, so each record may be targeted once per batch
+fn claim_target(targets: &mut BTreeSet<u64>, entity: &str, id: u64) -> Result<(), Error> {
+    if targets.insert(id) {
+        Ok(())
+    } else {
+        Err(Error::InvalidInput {
+            msg: format!(
+                "a {} with id={} appears more than once in the batch",
+                entity, id
+            ),
+        })
+    }
+}
+
+// Voyages are checked against the stored ones only, so also reject a created or updated
+// voyage that overlaps another one of the same vessel in the request
+pub(crate) fn claim_window(
+    claimed: &mut Vec<(u64, (u64, u64))>,
+    voyage: &Voyage,
+) -> Result<(), Error> {
+    claim(claimed, voyage.vessel_id, voyage_window(voyage))
+}
+
+fn claim(
+    claimed: &mut Vec<(u64, (u64, u64))>,
+    vessel_id: u64,
+    window: (u64, u64),
+) -> Result<(), Error> {
+    if claimed
+        .iter()
+        .any(|&(other_vessel, other)| other_vessel == vessel_id && overlaps(other, window))
+    {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "overlapping voyages for vessel id={} in the same request",
+                vessel_id
+            ),
+        });
+    }
+    claimed.push((vessel_id, window));
+    Ok(())
+}
+
+// Apply every planned operation if all of them validated, otherwise report the failures
+fn apply_all<T>(
+    checked: Vec<Result<Planned<T>, Error>>,
+    mut apply: impl FnMut(Planned<T>) -> u64,
+) -> BatchResult {
+    if checked.iter().any(Result::is_err) {
+        let items = checked
+            .into_iter()
+            .enumerate()
+            .map(|(index, result)| BatchItemResult {
+                index: index as u32,
+                id: None,
+                error: result.err(),
+            })
+            .collect();
+        return BatchResult {
+            applied: false,
+            items,
+        };
+    }
+
+    let items = checked
+        .into_iter()
+        .flatten()
+        .enumerate()
+        .map(|(index, planned)| BatchItemResult {
+            index: index as u32,
+            id: Some(apply(planned)),
+            error: None,
+        })
+        .collect();
+    BatchResult {
+        applied: true,
+        items,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn batch_size_is_bounded() {
+        assert!(check_batch_size(0).is_err());
+        assert!(check_batch_size(1).is_ok());
+        assert!(check_batch_size(MAX_BATCH_SIZE).is_ok());
+        assert!(check_batch_size(MAX_BATCH_SIZE + 1).is_err());
+    }
+
+    #[test]
+    fn a_record_is_targeted_once_per_batch() {
+        let mut targets = BTreeSet::new();
+        assert!(claim_target(&mut targets, "voyage", 1).is_ok());
+        assert!(claim_target(&mut targets, "voyage", 2).is_ok());
+        assert!(claim_target(&mut targets, "voyage", 1).is_err());
+    }
+
+    #[test]
+    fn overlapping_windows_of_one_vessel_are_rejected() {
+        let mut claimed = Vec::new();
+        assert!(claim(&mut claimed, 1, (10, 20)).is_ok());
+        assert!(claim(&mut claimed, 1, (15, 30)).is_err());
+        assert!(claim(&mut claimed, 1, (0, u64::MAX)).is_err());
+        assert_eq!(claimed, vec![(1, (10, 20))]);
+    }
+
+    #[test]
+    fn adjacent_windows_and_other_vessels_may_be_claimed() {
+        let mut claimed = Vec::new();
+        assert!(claim(&mut claimed, 1, (10, 20)).is_ok());
+        assert!(claim(&mut claimed, 1, (20, 30)).is_ok());
+        assert!(claim(&mut claimed, 1, (0, 10)).is_ok());
+        assert!(claim(&mut claimed, 2, (10, 20)).is_ok());
+        assert_eq!(claimed.len(), 4);
+    }
+}
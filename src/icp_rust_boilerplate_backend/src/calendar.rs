@@ -0,0 +1,85 @@
+// Calendar arithmetic on IC timestamps (nanoseconds since the Unix epoch, UTC)
+
+pub(crate) const NANOS_PER_DAY: u64 = 24 * 60 * 60 * 1_000_000_000;
+
+// Add calendar months to a timestamp,
+// clamping the day to the end of the target month and the result to the u64 range
+pub(crate) fn add_months(timestamp: u64, months: i64) -> u64 {
+    let days = (timestamp / NANOS_PER_DAY) as i64;
+    let time_of_day = timestamp % NANOS_PER_DAY;
+    let (year, month, day) = civil_from_days(days);
+
+    let total = year * 12 + (month as i64 - 1) + months;
+    let (year, month) = (total.div_euclid(12), (total.rem_euclid(12) + 1) as u32);
+    let day = day.min(days_in_month(year, month));
+
+    let days = days_from_civil(year, month, day);
+    (days.max(0) as u64)
+        .checked_mul(NANOS_PER_DAY)
+        .and_then(|nanos| nanos.checked_add(time_of_day))
+        .unwrap_or(u64::MAX)
+}
+
+fn days_in_month(year: i64, month: u32) -> u32 {
+    match month {
+        2 if year % 4 == 0 && (year % 100 != 0 || year % 400 == 0) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+// Days since 1970-01-01 to (year, month, day), after Howard Hinnant's civil calendar algorithms
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+// (year, month, day) to days since 1970-01-01
+fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year.rem_euclid(400);
+    let month = month as i64;
+    let doy = (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + day as i64 - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146_097 + doe - 719_468
+}
+
+// Calendar year a timestamp falls in
+pub(crate) fn year_of(timestamp: u64) -> i64 {
+    civil_from_days((timestamp / NANOS_PER_DAY) as i64).0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 2024-01-31T12:00:00Z
+    const JAN_31_2024: u64 = 19_753 * NANOS_PER_DAY + NANOS_PER_DAY / 2;
+
+    #[test]
+    fn months_clamp_to_the_end_of_the_month() {
+        // 2024-02-29, a leap year
+        assert_eq!(
+            add_months(JAN_31_2024, 1),
+            19_782 * NANOS_PER_DAY + NANOS_PER_DAY / 2
+        );
+        assert_eq!(add_months(add_months(JAN_31_2024, 12), -12), JAN_31_2024);
+    }
+
+    #[test]
+    fn months_saturate_past_the_end_of_the_range() {
+        assert_eq!(add_months(u64::MAX, 12), u64::MAX);
+        assert_eq!(add_months(u64::MAX - NANOS_PER_DAY * 400, 24), u64::MAX);
+        assert_eq!(add_months(0, -12), 0);
+    }
+}
@@ -0,0 +1,176 @@
+// Certified data: a hash tree over vessels, voyages and their HTTP representations
+use crate::http::json_body;
+use crate::organizations::{require_vessel_access, VesselAccess};
+use crate::{_get_vessel, _get_voyage, Error, Vessel, Voyage, VESSEL_STORAGE, VOYAGE_STORAGE};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use candid::Encode;
+use ic_cdk::api::{data_certificate, set_certified_data};
+use ic_certified_map::{AsHashTree, Hash, HashTree, RbTree};
+use sha2::{Digest, Sha256};
+use std::cell::RefCell;
+
+// Leaves hold the SHA-256 of the candid encoding of the record, keyed by big-endian ID
+const VESSELS: &str = "vessels";
+const VOYAGES: &str = "voyages";
+// Leaves hold the SHA-256 of the response body, keyed by URL path (HTTP gateway certification)
+const HTTP_ASSETS: &str = "http_assets";
+
+type Tree = RbTree<&'static str, RbTree<Vec<u8>, Hash>>;
+
+// The tree lives on the heap and is rebuilt from stable storage after every upgrade
+thread_local! {
+    static TREE: RefCell<Tree> = const { RefCell::new(RbTree::new()) };
+}
+
+// A Vessel with a proof that the subnet certified it
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct CertifiedVessel {
+    vessel: Vessel,
+    // The system data certificate (CBOR)
+    certificate: Vec<u8>,
+    // Hash tree witness for ["vessels", id] (CBOR)
+    witness: Vec<u8>,
+}
+
+// A Voyage with a proof that the subnet certified it
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct CertifiedVoyage {
+    voyage: Voyage,
+    // The system data certificate (CBOR)
+    certificate: Vec<u8>,
+    // Hash tree witness for ["voyages", id] (CBOR)
+    witness: Vec<u8>,
+}
+
+// Retrieve a Vessel by ID together with its certificate and witness
+#[ic_cdk::query]
+fn get_vessel_certified(id: u64) -> Result<CertifiedVessel, Error> {
+    require_vessel_access(id, VesselAccess::View)?;
+    let vessel = _get_vessel(&id).ok_or(Error::NotFound {
+        msg: format!("a vessel with id={} not found", id),
+    })?;
+    Ok(CertifiedVessel {
+        vessel,
+        certificate: certificate()?,
+        witness: witness(VESSELS, &id.to_be_bytes()),
+    })
+}
+
+// Retrieve a Voyage by ID together with its certificate and witness
+#[ic_cdk::query]
+fn get_voyage_certified(id: u64) -> Result<CertifiedVoyage, Error> {
+    let voyage = _get_voyage(&id).ok_or(Error::NotFound {
+        msg: format!("a voyage with id={} not found", id),
+    })?;
+    require_vessel_access(voyage.vessel_id, VesselAccess::View)?;
+    Ok(CertifiedVoyage {
+        voyage,
+        certificate: certificate()?,
+        witness: witness(VOYAGES, &id.to_be_bytes()),
+    })
+}
+
+// Record the new state of a vessel; None removes it
+pub(crate) fn certify_vessel(id: u64, vessel: Option<&Vessel>) {
+    put_vessel(id, vessel);
+    publish();
+}
+
+// Record the new state of a voyage; None removes it
+pub(crate) fn certify_voyage(id: u64, voyage: Option<&Voyage>) {
+    put_voyage(id, voyage);
+    publish();
+}
+
+// Re-create the tree from stable storage
+pub(crate) fn rebuild() {
+    TREE.with(|tree| *tree.borrow_mut() = RbTree::new());
+    VESSEL_STORAGE.with(|service| {
+        for (id, vessel) in service.borrow().iter() {
+            put_vessel(id, Some(&vessel));
+        }
+    });
+    VOYAGE_STORAGE.with(|service| {
+        for (id, voyage) in service.borrow().iter() {
+            put_voyage(id, Some(&voyage));
+        }
+    });
+    publish();
+}
+
+// The IC-Certificate header (HTTP gateway certification v1) for a certified path
+pub(crate) fn http_certificate_header(path: &str) -> Option<(String, String)> {
+    let certificate = data_certificate()?;
+    let tree = witness(HTTP_ASSETS, path.as_bytes());
+    Some((
+        "IC-Certificate".to_string(),
+        format!(
+            "certificate=:{}:, tree=:{}:",
+            BASE64.encode(certificate),
+            BASE64.encode(tree)
+        ),
+    ))
+}
+
+fn put_vessel(id: u64, vessel: Option<&Vessel>) {
+    update(VESSELS, id.to_be_bytes().to_vec(), vessel.map(record_hash));
+    update(
+        HTTP_ASSETS,
+        format!("/vessels/{}", id).into_bytes(),
+        vessel.map(|v| sha256(&json_body(&serde_json::json!(v)))),
+    );
+}
+
+fn put_voyage(id: u64, voyage: Option<&Voyage>) {
+    update(VOYAGES, id.to_be_bytes().to_vec(), voyage.map(record_hash));
+    update(
+        HTTP_ASSETS,
+        format!("/voyages/{}", id).into_bytes(),
+        voyage.map(|v| sha256(&json_body(&serde_json::json!(v)))),
+    );
+}
+
+fn update(label: &'static str, key: Vec<u8>, hash: Option<Hash>) {
+    TREE.with(|tree| {
+        let mut tree = tree.borrow_mut();
+        if tree.get(label.as_bytes()).is_none() {
+            tree.insert(label, RbTree::new());
+        }
+        tree.modify(label.as_bytes(), |subtree| match hash {
+            Some(hash) => subtree.insert(key, hash),
+            None => subtree.delete(&key),
+        });
+    });
+}
+
+fn publish() {
+    TREE.with(|tree| set_certified_data(&tree.borrow().root_hash()));
+}
+
+fn certificate() -> Result<Vec<u8>, Error> {
+    data_certificate().ok_or(Error::InvalidInput {
+        msg: "certificates are only available in query calls".to_string(),
+    })
+}
+
+// CBOR-encode the witness for [label, key]
+fn witness(label: &'static str, key: &[u8]) -> Vec<u8> {
+    TREE.with(|tree| {
+        let tree = tree.borrow();
+        let witness: HashTree =
+            tree.nested_witness(label.as_bytes(), |subtree| subtree.witness(key));
+        let mut serializer = serde_cbor::Serializer::new(Vec::new());
+        serializer.self_describe().expect("cannot write CBOR tag");
+        serde::Serialize::serialize(&witness, &mut serializer).expect("cannot encode witness");
+        serializer.into_inner()
+    })
+}
+
+fn record_hash<T: candid::CandidType>(record: &T) -> Hash {
+    sha256(&Encode!(record).unwrap())
+}
+
+fn sha256(data: &[u8]) -> Hash {
+    Sha256::digest(data).into()
+}
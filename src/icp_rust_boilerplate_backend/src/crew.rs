@@ -0,0 +1,488 @@
+// Crew registry and vessel crew assignments
+use crate::access::{has_role, require_role, Role};
+use crate::audit;
+use crate::organizations::{can_access_vessel, require_vessel_access, VesselAccess};
+use crate::stcw::check_rank_coverage;
+use crate::{_get_vessel, Error, IdCell, Memory, VESSEL_MEMORY_MANAGER};
+use candid::{Decode, Encode};
+use ic_cdk::api::{caller, time};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::storable::Blob;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use std::{borrow::Cow, cell::RefCell};
+
+// Longest name and nationality accepted; with the seafarer_id and the fixed fields
+// a crew member stays well inside its 1024 byte bound
+const MAX_TEXT_LEN: usize = 200;
+// Longest seafarer_id accepted, which is also the width of the seafarer_id index key
+const MAX_SEAFARER_ID_LEN: usize = 64;
+
+type SeafarerKey = Blob<MAX_SEAFARER_ID_LEN>;
+
+// Define the ranks a seafarer can hold on board
+#[derive(
+    candid::CandidType, Clone, Copy, Serialize, Deserialize, Default, PartialEq, Eq, Debug,
+)]
+pub(crate) enum Rank {
+    Master,
+    ChiefOfficer,
+    SecondOfficer,
+    ThirdOfficer,
+    ChiefEngineer,
+    SecondEngineer,
+    ThirdEngineer,
+    ElectroTechnicalOfficer,
+    Bosun,
+    AbleSeafarer,
+    OrdinarySeaman,
+    Oiler,
+    Cook,
+    Steward,
+    #[default]
+    Cadet,
+}
+
+// Define the structure for CrewMember
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub(crate) struct CrewMember {
+    pub(crate) id: u64,
+    pub(crate) name: String,
+    pub(crate) rank: Rank,
+    pub(crate) nationality: String,
+    pub(crate) seafarer_id: String,
+}
+
+// Payload used to register a new CrewMember
+#[derive(candid::CandidType, Serialize, Deserialize, Default)]
+pub(crate) struct CrewMemberPayload {
+    name: String,
+    rank: Rank,
+    nationality: String,
+    seafarer_id: String,
+}
+
+// Define the structure for a sign-on/sign-off assignment of a crew member to a vessel
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub(crate) struct CrewAssignment {
+    pub(crate) id: u64,
+    pub(crate) crew_member_id: u64,
+    pub(crate) vessel_id: u64,
+    pub(crate) rank: Rank,
+    pub(crate) sign_on_date: u64,
+    pub(crate) sign_off_date: Option<u64>,
+}
+
+// Payload used to sign a crew member onto a vessel
+#[derive(candid::CandidType, Serialize, Deserialize, Default)]
+pub(crate) struct SignOnPayload {
+    crew_member_id: u64,
+    vessel_id: u64,
+    rank: Rank,
+    sign_on_date: u64,
+}
+
+// A crew member currently on board together with the assignment that placed them there
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct CrewOnBoard {
+    member: CrewMember,
+    assignment: CrewAssignment,
+}
+
+impl CrewAssignment {
+    // Whether the assignment covers the given point in time
+    pub(crate) fn is_active_at(&self, at: u64) -> bool {
+        self.sign_on_date <= at && self.sign_off_date.is_none_or(|off| off > at)
+    }
+}
+
+// Implement Storable trait for CrewMember
+impl Storable for CrewMember {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+// Implement BoundedStorable trait for CrewMember
+impl BoundedStorable for CrewMember {
+    const MAX_SIZE: u32 = 1024;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Implement Storable trait for CrewAssignment
+impl Storable for CrewAssignment {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+// Implement BoundedStorable trait for CrewAssignment
+impl BoundedStorable for CrewAssignment {
+    const MAX_SIZE: u32 = 256;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Define thread-local variables for crew storage and the assignment indexes
+thread_local! {
+    static CREW_ID_COUNTER: RefCell<IdCell> = RefCell::new(
+        IdCell::init(VESSEL_MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(2))), 0)
+            .expect("Cannot create a counter")
+    );
+
+    static CREW_STORAGE: RefCell<StableBTreeMap<u64, CrewMember, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            VESSEL_MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(3)))
+    ));
+
+    static ASSIGNMENT_ID_COUNTER: RefCell<IdCell> = RefCell::new(
+        IdCell::init(VESSEL_MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(4))), 0)
+            .expect("Cannot create a counter")
+    );
+
+    static ASSIGNMENT_STORAGE: RefCell<StableBTreeMap<u64, CrewAssignment, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            VESSEL_MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(5)))
+    ));
+
+    // seafarer_id -> crew_member_id
+    static SEAFARER_INDEX: RefCell<StableBTreeMap<SeafarerKey, u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            VESSEL_MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(60)))
+    ));
+
+    // (vessel_id, assignment_id) -> ()
+    static VESSEL_ASSIGNMENT_INDEX: RefCell<StableBTreeMap<(u64, u64), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            VESSEL_MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(6)))
+    ));
+
+    // (crew_member_id, assignment_id) -> ()
+    static CREW_ASSIGNMENT_INDEX: RefCell<StableBTreeMap<(u64, u64), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            VESSEL_MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(7)))
+    ));
+}
+
+// Retrieve a CrewMember by ID
+#[ic_cdk::query]
+fn get_crew_member(id: u64) -> Result<CrewMember, Error> {
+    match _get_crew_member(&id) {
+        Some(member) => {
+            require_crew_access(id, VesselAccess::View)?;
+            Ok(member)
+        }
+        None => Err(Error::NotFound {
+            msg: format!("a crew member with id={} not found", id),
+        }),
+    }
+}
+
+// Register a new CrewMember (operators only)
+#[ic_cdk::update]
+fn add_crew_member(payload: CrewMemberPayload) -> Result<CrewMember, Error> {
+    require_role(Role::Operator)?;
+    if payload.name.trim().is_empty() || payload.seafarer_id.trim().is_empty() {
+        return Err(Error::InvalidInput {
+            msg: "name and seafarer_id must not be empty".to_string(),
+        });
+    }
+    if payload.name.len() > MAX_TEXT_LEN
+        || payload.nationality.len() > MAX_TEXT_LEN
+        || payload.seafarer_id.len() > MAX_SEAFARER_ID_LEN
+    {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "name and nationality must be at most {} bytes and seafarer_id at most {} bytes",
+                MAX_TEXT_LEN, MAX_SEAFARER_ID_LEN
+            ),
+        });
+    }
+    let key = seafarer_key(&payload.seafarer_id).expect("seafarer_id length was checked");
+    let duplicate = SEAFARER_INDEX.with(|index| index.borrow().contains_key(&key));
+    if duplicate {
+        return Err(Error::InvalidInput {
+            msg: format!("seafarer_id={} is already registered", payload.seafarer_id),
+        });
+    }
+
+    // Generate a new ID for the CrewMember
+    let id = CREW_ID_COUNTER
+        .with(|counter| {
+            let current_value = *counter.borrow().get();
+            counter.borrow_mut().set(current_value + 1)
+        })
+        .expect("cannot increment crew id counter");
+
+    let member = CrewMember {
+        id,
+        name: payload.name,
+        rank: payload.rank,
+        nationality: payload.nationality,
+        seafarer_id: payload.seafarer_id,
+    };
+    CREW_STORAGE.with(|service| service.borrow_mut().insert(member.id, member.clone()));
+    SEAFARER_INDEX.with(|index| index.borrow_mut().insert(key, member.id));
+    audit::record(
+        "add_crew_member",
+        "crew_member",
+        member.id,
+        None,
+        Some(&member),
+    );
+    Ok(member)
+}
+
+// Sign a crew member onto a vessel
+#[ic_cdk::update]
+fn sign_on_crew(payload: SignOnPayload) -> Result<CrewAssignment, Error> {
+    require_vessel_access(payload.vessel_id, VesselAccess::Edit)?;
+    if _get_vessel(&payload.vessel_id).is_none() {
+        return Err(Error::NotFound {
+            msg: format!("a vessel with id={} not found", payload.vessel_id),
+        });
+    }
+    if _get_crew_member(&payload.crew_member_id).is_none() {
+        return Err(Error::NotFound {
+            msg: format!("a crew member with id={} not found", payload.crew_member_id),
+        });
+    }
+
+    // The seafarer must hold valid certificates for the rank they sign on in
+    check_rank_coverage(payload.crew_member_id, payload.rank, payload.sign_on_date)?;
+
+    // A seafarer can only serve on one vessel at a time
+    let overlapping = crew_assignments(payload.crew_member_id)
+        .into_iter()
+        .find(|a| a.sign_off_date.is_none_or(|off| off > payload.sign_on_date));
+    if let Some(existing) = overlapping {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "crew member id={} is still signed on vessel id={} (assignment id={})",
+                payload.crew_member_id, existing.vessel_id, existing.id
+            ),
+        });
+    }
+
+    // Generate a new ID for the CrewAssignment
+    let id = ASSIGNMENT_ID_COUNTER
+        .with(|counter| {
+            let current_value = *counter.borrow().get();
+            counter.borrow_mut().set(current_value + 1)
+        })
+        .expect("cannot increment assignment id counter");
+
+    let assignment = CrewAssignment {
+        id,
+        crew_member_id: payload.crew_member_id,
+        vessel_id: payload.vessel_id,
+        rank: payload.rank,
+        sign_on_date: payload.sign_on_date,
+        sign_off_date: None,
+    };
+    do_insert_assignment(&assignment);
+    VESSEL_ASSIGNMENT_INDEX.with(|index| {
+        index
+            .borrow_mut()
+            .insert((assignment.vessel_id, assignment.id), ())
+    });
+    CREW_ASSIGNMENT_INDEX.with(|index| {
+        index
+            .borrow_mut()
+            .insert((assignment.crew_member_id, assignment.id), ())
+    });
+    audit::record(
+        "sign_on_crew",
+        "crew_assignment",
+        id,
+        None,
+        Some(&assignment),
+    );
+    Ok(assignment)
+}
+
+// Sign a crew member off a vessel by closing the assignment
+#[ic_cdk::update]
+fn sign_off_crew(assignment_id: u64, sign_off_date: u64) -> Result<CrewAssignment, Error> {
+    match _get_assignment(&assignment_id) {
+        Some(mut assignment) => {
+            require_vessel_access(assignment.vessel_id, VesselAccess::Edit)?;
+            if assignment.sign_off_date.is_some() {
+                return Err(Error::InvalidInput {
+                    msg: format!("assignment id={} is already closed", assignment_id),
+                });
+            }
+            if sign_off_date < assignment.sign_on_date {
+                return Err(Error::InvalidInput {
+                    msg: "sign_off_date must not be before sign_on_date".to_string(),
+                });
+            }
+            let before = assignment.clone();
+            assignment.sign_off_date = Some(sign_off_date);
+            do_insert_assignment(&assignment);
+            audit::record(
+                "sign_off_crew",
+                "crew_assignment",
+                assignment_id,
+                Some(&before),
+                Some(&assignment),
+            );
+            Ok(assignment)
+        }
+        None => Err(Error::NotFound {
+            msg: format!("an assignment with id={} not found", assignment_id),
+        }),
+    }
+}
+
+// List the crew currently on board a vessel
+#[ic_cdk::query]
+fn get_crew_on_board(vessel_id: u64) -> Result<Vec<CrewOnBoard>, Error> {
+    require_vessel_access(vessel_id, VesselAccess::View)?;
+    if _get_vessel(&vessel_id).is_none() {
+        return Err(Error::NotFound {
+            msg: format!("a vessel with id={} not found", vessel_id),
+        });
+    }
+    let now = time();
+    Ok(vessel_assignments(vessel_id)
+        .into_iter()
+        .filter(|a| a.is_active_at(now))
+        .filter_map(|assignment| {
+            _get_crew_member(&assignment.crew_member_id)
+                .map(|member| CrewOnBoard { member, assignment })
+        })
+        .collect())
+}
+
+// List every assignment a seafarer has served, oldest first
+#[ic_cdk::query]
+fn get_service_history(crew_member_id: u64) -> Result<Vec<CrewAssignment>, Error> {
+    if _get_crew_member(&crew_member_id).is_none() {
+        return Err(Error::NotFound {
+            msg: format!("a crew member with id={} not found", crew_member_id),
+        });
+    }
+    // Only the service on vessels the caller may view
+    let mut history: Vec<CrewAssignment> = crew_assignments(crew_member_id)
+        .into_iter()
+        .filter(|a| can_access_vessel(a.vessel_id, VesselAccess::View))
+        .collect();
+    history.sort_by_key(|a| a.sign_on_date);
+    Ok(history)
+}
+
+// Helper method to insert a CrewAssignment into storage
+fn do_insert_assignment(assignment: &CrewAssignment) {
+    ASSIGNMENT_STORAGE.with(|service| {
+        service
+            .borrow_mut()
+            .insert(assignment.id, assignment.clone())
+    });
+}
+
+// Retrieve a CrewMember by ID from storage
+pub(crate) fn _get_crew_member(id: &u64) -> Option<CrewMember> {
+    CREW_STORAGE.with(|service| service.borrow().get(id))
+}
+
+// Retrieve a CrewAssignment by ID from storage
+fn _get_assignment(id: &u64) -> Option<CrewAssignment> {
+    ASSIGNMENT_STORAGE.with(|service| service.borrow().get(id))
+}
+
+// Collect all assignments of a vessel using the vessel index
+pub(crate) fn vessel_assignments(vessel_id: u64) -> Vec<CrewAssignment> {
+    let ids: Vec<u64> = VESSEL_ASSIGNMENT_INDEX.with(|index| {
+        index
+            .borrow()
+            .range((vessel_id, 0)..=(vessel_id, u64::MAX))
+            .map(|((_, assignment_id), _)| assignment_id)
+            .collect()
+    });
+    ids.iter().filter_map(_get_assignment).collect()
+}
+
+// Collect all assignments of a crew member using the crew index
+pub(crate) fn crew_assignments(crew_member_id: u64) -> Vec<CrewAssignment> {
+    let ids: Vec<u64> = CREW_ASSIGNMENT_INDEX.with(|index| {
+        index
+            .borrow()
+            .range((crew_member_id, 0)..=(crew_member_id, u64::MAX))
+            .map(|((_, assignment_id), _)| assignment_id)
+            .collect()
+    });
+    ids.iter().filter_map(_get_assignment).collect()
+}
+
+// Convert a seafarer_id into a key of the seafarer_id index; None when it is too long
+fn seafarer_key(seafarer_id: &str) -> Option<SeafarerKey> {
+    Blob::try_from(seafarer_id.as_bytes()).ok()
+}
+
+// Index the seafarer_id of crew members registered before the index existed
+pub(crate) fn backfill_seafarer_index() {
+    let indexed = SEAFARER_INDEX.with(|index| !index.borrow().is_empty());
+    if indexed {
+        return;
+    }
+    CREW_STORAGE.with(|service| {
+        SEAFARER_INDEX.with(|index| {
+            let mut index = index.borrow_mut();
+            for (id, member) in service.borrow().iter() {
+                if let Some(key) = seafarer_key(&member.seafarer_id) {
+                    index.insert(key, id);
+                }
+            }
+        })
+    });
+}
+
+// The vessel a crew member is signed on at the given point in time, if any
+pub(crate) fn current_vessel(crew_member_id: u64, at: u64) -> Option<u64> {
+    crew_assignments(crew_member_id)
+        .into_iter()
+        .find(|a| a.is_active_at(at))
+        .map(|a| a.vessel_id)
+}
+
+// Whether the caller may access a crew member's records: through the vessel the
+// member currently serves on, or as an operator while the member is ashore
+pub(crate) fn can_access_crew_member(crew_member_id: u64, access: VesselAccess) -> bool {
+    match current_vessel(crew_member_id, time()) {
+        Some(vessel_id) => can_access_vessel(vessel_id, access),
+        None => has_role(&caller(), Role::Operator),
+    }
+}
+
+// Fail unless the caller may access a crew member's records in the given way
+pub(crate) fn require_crew_access(crew_member_id: u64, access: VesselAccess) -> Result<(), Error> {
+    if can_access_crew_member(crew_member_id, access) {
+        Ok(())
+    } else {
+        Err(Error::Unauthorized {
+            msg: format!(
+                "caller {} has no {:?} access to crew member id={}",
+                caller(),
+                access,
+                crew_member_id
+            ),
+        })
+    }
+}
+
+// Page through crew members in ID order, starting at `start`
+pub(crate) fn crew_members_from(start: u64, limit: usize) -> Vec<(u64, CrewMember)> {
+    CREW_STORAGE.with(|service| service.borrow().range(start..).take(limit).collect())
+}
+
+// Page through crew assignments in ID order, starting at `start`
+pub(crate) fn assignments_from(start: u64, limit: usize) -> Vec<(u64, CrewAssignment)> {
+    ASSIGNMENT_STORAGE.with(|service| service.borrow().range(start..).take(limit).collect())
+}
@@ -0,0 +1,427 @@
+// Ordered log of vessel and voyage events for external consumers
+use crate::organizations::{can_access_vessel, VesselAccess};
+use crate::subscriptions;
+use crate::{_get_voyage, Error, IdCell, Memory, Vessel, Voyage, VESSEL_MEMORY_MANAGER};
+use candid::{Decode, Encode, Principal};
+use ic_cdk::api::{caller, time};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use std::{borrow::Cow, cell::RefCell};
+
+const MAX_EVENTS_PAGE: usize = 500;
+// Events examined per call, whether or not the caller may see them
+const MAX_EVENTS_SCANNED: usize = 5000;
+
+// Define the events a mutation can produce
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) enum EventPayload {
+    VesselRegistered {
+        vessel: Vessel,
+    },
+    VesselUpdated {
+        vessel: Vessel,
+    },
+    PositionReported {
+        vessel_id: u64,
+        location: String,
+        reported_at: u64,
+    },
+    VesselRemoved {
+        vessel_id: u64,
+    },
+    VoyageScheduled {
+        voyage: Voyage,
+    },
+    VoyageUpdated {
+        voyage: Voyage,
+    },
+    VoyageDeparted {
+        voyage_id: u64,
+        vessel_id: u64,
+        departure_port: String,
+        departure_time: u64,
+    },
+    VoyageArrived {
+        voyage_id: u64,
+        vessel_id: u64,
+        destination_port: String,
+        arrival_time: u64,
+    },
+    VoyageRemoved {
+        voyage_id: u64,
+        vessel_id: u64,
+    },
+}
+
+// Define the kinds of events, one per EventPayload variant
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Debug)]
+pub(crate) enum EventKind {
+    VesselRegistered,
+    VesselUpdated,
+    PositionReported,
+    VesselRemoved,
+    VoyageScheduled,
+    VoyageUpdated,
+    VoyageDeparted,
+    VoyageArrived,
+    VoyageRemoved,
+}
+
+// Define the structure for an entry of the event log
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct Event {
+    // Starts at 1 and increases by one per event
+    pub(crate) seq: u64,
+    timestamp: u64,
+    caller: Principal,
+    pub(crate) payload: EventPayload,
+}
+
+// A page of events and the sequence number of the newest event in the log
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct EventPage {
+    events: Vec<Event>,
+    // Pass as since_seq to continue; events of vessels the caller may not see are skipped
+    next_since_seq: u64,
+    last_seq: u64,
+}
+
+impl EventPayload {
+    pub(crate) fn kind(&self) -> EventKind {
+        match self {
+            EventPayload::VesselRegistered { .. } => EventKind::VesselRegistered,
+            EventPayload::VesselUpdated { .. } => EventKind::VesselUpdated,
+            EventPayload::PositionReported { .. } => EventKind::PositionReported,
+            EventPayload::VesselRemoved { .. } => EventKind::VesselRemoved,
+            EventPayload::VoyageScheduled { .. } => EventKind::VoyageScheduled,
+            EventPayload::VoyageUpdated { .. } => EventKind::VoyageUpdated,
+            EventPayload::VoyageDeparted { .. } => EventKind::VoyageDeparted,
+            EventPayload::VoyageArrived { .. } => EventKind::VoyageArrived,
+            EventPayload::VoyageRemoved { .. } => EventKind::VoyageRemoved,
+        }
+    }
+
+    // The vessel the event is about
+    pub(crate) fn vessel_id(&self) -> u64 {
+        match self {
+            EventPayload::VesselRegistered { vessel } | EventPayload::VesselUpdated { vessel } => {
+                vessel.id
+            }
+            EventPayload::VoyageScheduled { voyage } | EventPayload::VoyageUpdated { voyage } => {
+                voyage.vessel_id
+            }
+            EventPayload::PositionReported { vessel_id, .. }
+            | EventPayload::VesselRemoved { vessel_id }
+            | EventPayload::VoyageDeparted { vessel_id, .. }
+            | EventPayload::VoyageArrived { vessel_id, .. }
+            | EventPayload::VoyageRemoved { vessel_id, .. } => *vessel_id,
+        }
+    }
+
+    // The ports the event is about; vessel events have none
+    pub(crate) fn ports(&self) -> Vec<&str> {
+        match self {
+            EventPayload::VoyageScheduled { voyage } | EventPayload::VoyageUpdated { voyage } => {
+                vec![&voyage.departure_port, &voyage.destination_port]
+            }
+            EventPayload::VoyageDeparted { departure_port, .. } => vec![departure_port],
+            EventPayload::VoyageArrived {
+                destination_port, ..
+            } => vec![destination_port],
+            _ => Vec::new(),
+        }
+    }
+}
+
+// Implement Storable trait for Event
+impl Storable for Event {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+// Implement BoundedStorable trait for Event
+impl BoundedStorable for Event {
+    // The encoding repeats the type table of every EventPayload variant around the record
+    const MAX_SIZE: u32 = Vessel::MAX_SIZE + 512;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Define thread-local variables for the event log
+thread_local! {
+    static EVENT_SEQ: RefCell<IdCell> = RefCell::new(
+        IdCell::init(VESSEL_MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(39))), 0)
+            .expect("Cannot create a counter")
+    );
+
+    static EVENT_LOG: RefCell<StableBTreeMap<u64, Event, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            VESSEL_MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(40)))
+    ));
+
+    // (departure_time, voyage_id) -> () for voyages whose departure is still ahead;
+    // consumed in departure order by the periodic job
+    static PENDING_DEPARTURES: RefCell<StableBTreeMap<(u64, u64), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            VESSEL_MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(59)))
+    ));
+}
+
+// Retrieve up to `limit` events with a sequence number above `since_seq`, oldest first.
+// Consumers pass the next_since_seq of the previous page; 0 reads from the beginning.
+#[ic_cdk::query]
+fn get_events(since_seq: u64, limit: u32) -> Result<EventPage, Error> {
+    if limit == 0 {
+        return Err(Error::InvalidInput {
+            msg: "limit must be positive".to_string(),
+        });
+    }
+    let limit = (limit as usize).min(MAX_EVENTS_PAGE);
+    let mut events = Vec::new();
+    let mut next_since_seq = since_seq;
+    EVENT_LOG.with(|log| {
+        for (seq, event) in log
+            .borrow()
+            .range(since_seq.saturating_add(1)..)
+            .take(MAX_EVENTS_SCANNED)
+        {
+            if events.len() == limit {
+                break;
+            }
+            next_since_seq = seq;
+            if can_access_vessel(event.payload.vessel_id(), VesselAccess::View) {
+                events.push(event);
+            }
+        }
+    });
+    Ok(EventPage {
+        events,
+        next_since_seq,
+        last_seq: EVENT_SEQ.with(|seq| *seq.borrow().get()),
+    })
+}
+
+// Append the events describing a change of a vessel; None marks creation or removal
+pub(crate) fn vessel_changed(id: u64, before: Option<&Vessel>, after: Option<&Vessel>) {
+    match (before, after) {
+        (None, Some(vessel)) => append(EventPayload::VesselRegistered {
+            vessel: vessel.clone(),
+        }),
+        (Some(before), Some(vessel)) => {
+            if before.current_location != vessel.current_location {
+                append(EventPayload::PositionReported {
+                    vessel_id: id,
+                    location: vessel.current_location.clone(),
+                    reported_at: vessel.last_update,
+                });
+            }
+            let other_changes = before.name != vessel.name
+                || before.captain != vessel.captain
+                || before.capacity != vessel.capacity
+                || before.flag != vessel.flag
+                || before.year_built != vessel.year_built;
+            if other_changes {
+                append(EventPayload::VesselUpdated {
+                    vessel: vessel.clone(),
+                });
+            }
+        }
+        (Some(_), None) => append(EventPayload::VesselRemoved { vessel_id: id }),
+        (None, None) => {}
+    }
+}
+
+// Append the events describing a change of a voyage; None marks creation or removal
+pub(crate) fn voyage_changed(id: u64, before: Option<&Voyage>, after: Option<&Voyage>) {
+    let now = time();
+    let was_pending = before.is_some_and(|before| {
+        PENDING_DEPARTURES.with(|index| {
+            index
+                .borrow_mut()
+                .remove(&(before.departure_time, before.id))
+                .is_some()
+        })
+    });
+    // Departures still ahead are announced by announce_departures once their time has come
+    if let Some(voyage) = after.filter(|voyage| voyage.departure_time > now) {
+        PENDING_DEPARTURES.with(|index| {
+            index
+                .borrow_mut()
+                .insert((voyage.departure_time, voyage.id), ())
+        });
+    }
+
+    let voyage = match (before, after) {
+        (_, Some(voyage)) => voyage,
+        (Some(before), None) => {
+            return append(EventPayload::VoyageRemoved {
+                voyage_id: id,
+                vessel_id: before.vessel_id,
+            })
+        }
+        (None, None) => return,
+    };
+    // A voyage departs once its departure time is first seen in the past, here or by the job
+    let departed = voyage.departure_time <= now
+        && (was_pending || before.is_none_or(|b| b.departure_time > now));
+    let arrived = voyage.arrival_time.is_some()
+        && before.is_none_or(|b| b.arrival_time != voyage.arrival_time);
+
+    match before {
+        None => append(EventPayload::VoyageScheduled {
+            voyage: voyage.clone(),
+        }),
+        Some(before) => {
+            let other_changes = before.departure_port != voyage.departure_port
+                || before.destination_port != voyage.destination_port
+                || before.eta != voyage.eta
+                || before.scheduled_departure != voyage.scheduled_departure
+                || before.scheduled_arrival != voyage.scheduled_arrival
+                || (before.departure_time != voyage.departure_time && !departed)
+                || (before.arrival_time != voyage.arrival_time && !arrived);
+            if other_changes {
+                append(EventPayload::VoyageUpdated {
+                    voyage: voyage.clone(),
+                });
+            }
+        }
+    }
+    if departed {
+        append(EventPayload::VoyageDeparted {
+            voyage_id: id,
+            vessel_id: voyage.vessel_id,
+            departure_port: voyage.departure_port.clone(),
+            departure_time: voyage.departure_time,
+        });
+    }
+    if let Some(arrival_time) = voyage.arrival_time.filter(|_| arrived) {
+        append(EventPayload::VoyageArrived {
+            voyage_id: id,
+            vessel_id: voyage.vessel_id,
+            destination_port: voyage.destination_port.clone(),
+            arrival_time,
+        });
+    }
+}
+
+// Append a VoyageDeparted event for up to `limit` voyages whose departure time has passed
+// since they were written
+pub(crate) fn announce_departures(limit: usize) {
+    let now = time();
+    let due: Vec<(u64, u64)> = PENDING_DEPARTURES.with(|index| {
+        index
+            .borrow()
+            .range(..=(now, u64::MAX))
+            .take(limit)
+            .map(|(key, _)| key)
+            .collect()
+    });
+    for (departure_time, id) in due {
+        PENDING_DEPARTURES.with(|index| index.borrow_mut().remove(&(departure_time, id)));
+        if let Some(voyage) = _get_voyage(&id) {
+            append(EventPayload::VoyageDeparted {
+                voyage_id: id,
+                vessel_id: voyage.vessel_id,
+                departure_port: voyage.departure_port,
+                departure_time,
+            });
+        }
+    }
+}
+
+fn append(payload: EventPayload) {
+    let seq = EVENT_SEQ
+        .with(|counter| {
+            let current_value = *counter.borrow().get();
+            counter.borrow_mut().set(current_value + 1)
+        })
+        .expect("cannot increment event sequence")
+        + 1;
+    let event = Event {
+        seq,
+        timestamp: time(),
+        caller: caller(),
+        payload,
+    };
+    EVENT_LOG.with(|log| log.borrow_mut().insert(seq, event.clone()));
+    subscriptions::enqueue(&event);
+}
+
+// Retrieve an Event by sequence number
+pub(crate) fn get_event(seq: u64) -> Option<Event> {
+    EVENT_LOG.with(|log| log.borrow().get(&seq))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(payload: EventPayload) -> Event {
+        Event {
+            seq: u64::MAX,
+            timestamp: u64::MAX,
+            caller: Principal::from_slice(&[0xff; 29]),
+            payload,
+        }
+    }
+
+    // Pad a text field so that the record encodes to exactly `size` bytes; the length prefix
+    // of the field grows from one to two bytes on the way
+    fn padded<T: candid::CandidType>(size: u32, build: impl Fn(String) -> T) -> T {
+        let base = Encode!(&build(String::new())).unwrap().len();
+        let record = build("x".repeat(size as usize - base - 1));
+        assert_eq!(Encode!(&record).unwrap().len(), size as usize);
+        record
+    }
+
+    #[test]
+    fn event_of_a_max_size_vessel_fits() {
+        let vessel = padded(Vessel::MAX_SIZE, |name| Vessel {
+            id: u64::MAX,
+            name,
+            captain: "captain".to_string(),
+            capacity: u32::MAX,
+            current_location: "0,0".to_string(),
+            last_update: u64::MAX,
+            flag: Some("flag".to_string()),
+            year_built: Some(u16::MAX),
+            version: Some(u64::MAX),
+        });
+        for payload in [
+            EventPayload::VesselRegistered {
+                vessel: vessel.clone(),
+            },
+            EventPayload::VesselUpdated { vessel },
+        ] {
+            assert!(event(payload).to_bytes().len() <= Event::MAX_SIZE as usize);
+        }
+    }
+
+    #[test]
+    fn event_of_a_max_size_voyage_fits() {
+        let voyage = padded(Voyage::MAX_SIZE, |departure_port| Voyage {
+            id: u64::MAX,
+            vessel_id: u64::MAX,
+            departure_port,
+            destination_port: "port".to_string(),
+            departure_time: u64::MAX,
+            arrival_time: Some(u64::MAX),
+            eta: Some(u64::MAX),
+            version: Some(u64::MAX),
+            scheduled_departure: Some(u64::MAX),
+            scheduled_arrival: Some(u64::MAX),
+        });
+        for payload in [
+            EventPayload::VoyageScheduled {
+                voyage: voyage.clone(),
+            },
+            EventPayload::VoyageUpdated { voyage },
+        ] {
+            assert!(event(payload).to_bytes().len() <= Event::MAX_SIZE as usize);
+        }
+    }
+}
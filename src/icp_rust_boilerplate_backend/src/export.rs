@@ -0,0 +1,244 @@
+// Chunked export of the dataset as CSV, JSON lines or GeoJSON
+use crate::crew::{assignments_from, crew_members_from};
+use crate::incidents::{incidents_from, Position};
+use crate::organizations::{can_access_vessel, VesselAccess};
+use crate::revisions::vessel_track;
+use crate::{vessels_from, voyages_from, Error, Vessel, Voyage};
+use ic_cdk::api::time;
+use serde_json::{json, Value};
+
+const MAX_EXPORT_ROWS: u32 = 1000;
+// Stay well below the query response size limit
+const MAX_CHUNK_BYTES: usize = 1_500_000;
+// Position reports read for one voyage track
+const MAX_TRACK_POINTS: usize = 500;
+
+// Define the collections that can be exported
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq)]
+pub(crate) enum ExportCollection {
+    Vessels,
+    Voyages,
+    CrewMembers,
+    CrewAssignments,
+    Incidents,
+}
+
+// Define the export formats
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq)]
+pub(crate) enum ExportFormat {
+    // Header row in the first chunk only
+    Csv,
+    // One JSON object per line
+    JsonLines,
+    // One FeatureCollection per chunk; vessels and voyages only
+    GeoJson,
+}
+
+// One chunk of an export; pass `next` as `start` to fetch the following chunk
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct ExportChunk {
+    content_type: String,
+    data: String,
+    rows: u32,
+    next: Option<u64>,
+}
+
+// Export up to `limit` records with an ID of at least `start`
+#[ic_cdk::query]
+fn export_data(
+    collection: ExportCollection,
+    format: ExportFormat,
+    start: u64,
+    limit: u32,
+) -> Result<ExportChunk, Error> {
+    let limit = limit.clamp(1, MAX_EXPORT_ROWS) as usize;
+    let count = limit + 1;
+    let records = match (collection, format) {
+        (ExportCollection::Vessels, ExportFormat::GeoJson) => {
+            features(scoped(vessels_from, start, count, |v| v.id), vessel_feature)
+        }
+        (ExportCollection::Voyages, ExportFormat::GeoJson) => features(
+            scoped(voyages_from, start, count, |v| v.vessel_id),
+            voyage_feature,
+        ),
+        (_, ExportFormat::GeoJson) => {
+            return Err(Error::InvalidInput {
+                msg: "GeoJSON export is only available for vessels and voyages".to_string(),
+            })
+        }
+        (ExportCollection::Vessels, _) => values(scoped(vessels_from, start, count, |v| v.id)),
+        (ExportCollection::Voyages, _) => {
+            values(scoped(voyages_from, start, count, |v| v.vessel_id))
+        }
+        (ExportCollection::CrewMembers, _) => values(crew_members_from(start, count)),
+        (ExportCollection::CrewAssignments, _) => {
+            values(scoped(assignments_from, start, count, |a| a.vessel_id))
+        }
+        (ExportCollection::Incidents, _) => {
+            values(scoped(incidents_from, start, count, |i| i.vessel_id))
+        }
+    };
+    Ok(render(format, start, limit, records))
+}
+
+// Read up to `count` records from `start` that belong to vessels the caller may view
+fn scoped<T>(
+    page: fn(u64, usize) -> Vec<(u64, T)>,
+    start: u64,
+    count: usize,
+    vessel_of: impl Fn(&T) -> u64,
+) -> Vec<(u64, T)> {
+    let mut records = Vec::new();
+    let mut cursor = start;
+    loop {
+        let chunk = page(cursor, count);
+        let exhausted = chunk.len() < count;
+        let last = chunk.last().map(|(id, _)| *id);
+        records.extend(
+            chunk
+                .into_iter()
+                .filter(|(_, record)| can_access_vessel(vessel_of(record), VesselAccess::View)),
+        );
+        match last {
+            Some(last) if !exhausted && records.len() < count => cursor = last + 1,
+            _ => break,
+        }
+    }
+    records.truncate(count);
+    records
+}
+
+fn values<T: serde::Serialize>(records: Vec<(u64, T)>) -> Vec<(u64, Value)> {
+    records
+        .into_iter()
+        .map(|(id, record)| (id, serde_json::to_value(record).unwrap_or(Value::Null)))
+        .collect()
+}
+
+fn features<T>(records: Vec<(u64, T)>, feature: impl Fn(T) -> Value) -> Vec<(u64, Value)> {
+    records
+        .into_iter()
+        .map(|(id, record)| (id, feature(record)))
+        .collect()
+}
+
+// A vessel at its current location; the geometry is null when the location is not "lat,lon"
+fn vessel_feature(vessel: Vessel) -> Value {
+    let geometry = Position::parse(&vessel.current_location)
+        .map(|p| json!({ "type": "Point", "coordinates": [p.longitude, p.latitude] }));
+    json!({
+        "type": "Feature",
+        "id": vessel.id,
+        "geometry": geometry,
+        "properties": serde_json::to_value(&vessel).unwrap_or(Value::Null),
+    })
+}
+
+// A voyage track built from the vessel locations recorded while the voyage was under way
+fn voyage_feature(voyage: Voyage) -> Value {
+    let to = voyage.arrival_time.unwrap_or_else(time);
+    let mut track: Vec<[f64; 2]> = vessel_track(
+        voyage.vessel_id,
+        voyage.departure_time,
+        to,
+        MAX_TRACK_POINTS,
+    )
+    .iter()
+    .filter_map(|(_, location)| Position::parse(location))
+    .map(|p| [p.longitude, p.latitude])
+    .collect();
+    track.dedup();
+    let geometry = match track.len() {
+        0 => Value::Null,
+        1 => json!({ "type": "Point", "coordinates": track[0] }),
+        _ => json!({ "type": "LineString", "coordinates": track }),
+    };
+    json!({
+        "type": "Feature",
+        "id": voyage.id,
+        "geometry": geometry,
+        "properties": serde_json::to_value(&voyage).unwrap_or(Value::Null),
+    })
+}
+
+// Render records until the row limit or the chunk size budget is reached
+fn render(
+    format: ExportFormat,
+    start: u64,
+    limit: usize,
+    records: Vec<(u64, Value)>,
+) -> ExportChunk {
+    let mut data = String::new();
+    let mut lines: Vec<String> = Vec::new();
+    let mut next = None;
+    if format == ExportFormat::Csv && start == 0 {
+        if let Some((_, Value::Object(first))) = records.first() {
+            data = csv_line(first.keys().map(|k| k.to_string()));
+        }
+    }
+
+    let mut size = data.len();
+    for (index, (id, record)) in records.into_iter().enumerate() {
+        let line = match format {
+            ExportFormat::Csv => csv_line(csv_fields(&record)),
+            ExportFormat::JsonLines => format!("{}\n", record),
+            ExportFormat::GeoJson => record.to_string(),
+        };
+        // Always emit at least one row so the export makes progress
+        if index == limit || (!lines.is_empty() && size + line.len() > MAX_CHUNK_BYTES) {
+            next = Some(id);
+            break;
+        }
+        size += line.len() + 1;
+        lines.push(line);
+    }
+
+    let rows = lines.len() as u32;
+    let (content_type, data) = match format {
+        ExportFormat::Csv => ("text/csv", data + &lines.concat()),
+        ExportFormat::JsonLines => ("application/x-ndjson", lines.concat()),
+        ExportFormat::GeoJson => (
+            "application/geo+json",
+            format!(
+                "{{\"type\":\"FeatureCollection\",\"features\":[{}]}}",
+                lines.join(",")
+            ),
+        ),
+    };
+    ExportChunk {
+        content_type: content_type.to_string(),
+        data,
+        rows,
+        next,
+    }
+}
+
+// Scalars are written as-is; nested values are written as JSON
+fn csv_fields(record: &Value) -> Vec<String> {
+    match record {
+        Value::Object(fields) => fields.values().map(csv_value).collect(),
+        other => vec![csv_value(other)],
+    }
+}
+
+fn csv_value(value: &Value) -> String {
+    match value {
+        Value::Null => String::new(),
+        Value::String(s) => s.clone(),
+        other => other.to_string(),
+    }
+}
+
+fn csv_line(fields: impl IntoIterator<Item = String>) -> String {
+    let escaped: Vec<String> = fields
+        .into_iter()
+        .map(|field| {
+            if field.contains([',', '"', '\n', '\r']) {
+                format!("\"{}\"", field.replace('"', "\"\""))
+            } else {
+                field
+            }
+        })
+        .collect();
+    escaped.join(",") + "\n"
+}
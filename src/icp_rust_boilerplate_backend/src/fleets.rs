@@ -0,0 +1,362 @@
+// Named fleets of vessels and fleet-level dashboards
+use crate::access::{require_role, Role};
+use crate::availability::{current_status, OperationalStatus};
+use crate::calendar::NANOS_PER_DAY;
+use crate::incidents::Position;
+use crate::jobs::STALE_VESSEL_AFTER;
+use crate::organizations::{
+    can_access_vessel, is_member, require_vessel_access, resolve_owner, VesselAccess,
+};
+use crate::revisions::vessel_track;
+use crate::{
+    _get_vessel, audit, vessel_voyages, Error, IdCell, Memory, Vessel, VESSEL_MEMORY_MANAGER,
+};
+use candid::{Decode, Encode, Principal};
+use ic_cdk::api::{caller, time};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use std::collections::BTreeMap;
+use std::{borrow::Cow, cell::RefCell};
+
+const MAX_NAME_LEN: usize = 200;
+// Vessels without an open voyage that arrived somewhere this recently are in port, else idle
+const IDLE_AFTER: u64 = 30 * NANOS_PER_DAY;
+// Speeds are averaged over the position reports of this window
+const SPEED_WINDOW: u64 = NANOS_PER_DAY;
+const NANOS_PER_HOUR: u64 = NANOS_PER_DAY / 24;
+const NANOS_PER_SECOND: u64 = 1_000_000_000;
+// Position reports used for a speed; one per hour is plenty over SPEED_WINDOW
+const MAX_SPEED_POINTS: usize = 100;
+
+// Define the structure for a fleet
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct Fleet {
+    id: u64,
+    name: String,
+    created_by: Principal,
+    created_at: u64,
+    // Only its members see and manage the fleet; None for fleets created before fleets had
+    // owners, which are left to admins
+    organization: Option<u64>,
+}
+
+// Define what a vessel is doing
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Debug)]
+pub(crate) enum VesselState {
+    // Sailing, or else on a voyage that has departed and not arrived
+    Underway,
+    InPort,
+    Idle,
+}
+
+// The state of one vessel of a fleet
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct FleetVesselStatus {
+    vessel_id: u64,
+    name: String,
+    state: VesselState,
+    // As last set with set_operational_status
+    operational_status: Option<OperationalStatus>,
+    current_location: String,
+    last_seen: u64,
+    staleness_secs: u64,
+    // Over the last SPEED_WINDOW; None without two "lat,lon" reports
+    speed_knots: Option<f64>,
+}
+
+// Fleet-level figures over the vessels of a fleet the caller may view
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct FleetDashboard {
+    fleet: Fleet,
+    vessels: u32,
+    underway: u32,
+    in_port: u32,
+    idle: u32,
+    open_voyages: u32,
+    // Mean speed of the underway vessels with a known speed
+    average_speed_knots: Option<f64>,
+    stale_vessels: u32,
+    max_staleness_secs: Option<u64>,
+    vessel_statuses: Vec<FleetVesselStatus>,
+}
+
+// Implement Storable trait for Fleet
+impl Storable for Fleet {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+// Implement BoundedStorable trait for Fleet
+impl BoundedStorable for Fleet {
+    const MAX_SIZE: u32 = 512;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Define thread-local variables for fleet storage
+thread_local! {
+    static FLEET_ID_COUNTER: RefCell<IdCell> = RefCell::new(
+        IdCell::init(VESSEL_MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(52))), 0)
+            .expect("Cannot create a counter")
+    );
+
+    static FLEET_STORAGE: RefCell<StableBTreeMap<u64, Fleet, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            VESSEL_MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(53)))
+    ));
+
+    // (fleet_id, vessel_id) -> ()
+    static FLEET_VESSELS: RefCell<StableBTreeMap<(u64, u64), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            VESSEL_MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(54)))
+    ));
+}
+
+// Create a fleet owned by `organization`, or else the caller's only organization (operators only)
+#[ic_cdk::update]
+fn create_fleet(name: String, organization: Option<u64>) -> Result<Fleet, Error> {
+    require_role(Role::Operator)?;
+    let organization = resolve_owner(organization)?;
+    let name = name.trim().to_string();
+    if name.is_empty() || name.len() > MAX_NAME_LEN {
+        return Err(Error::InvalidInput {
+            msg: format!("name must be between 1 and {} characters", MAX_NAME_LEN),
+        });
+    }
+
+    let id = FLEET_ID_COUNTER
+        .with(|counter| {
+            let current_value = *counter.borrow().get();
+            counter.borrow_mut().set(current_value + 1)
+        })
+        .expect("cannot increment fleet id counter");
+
+    let fleet = Fleet {
+        id,
+        name,
+        created_by: caller(),
+        created_at: time(),
+        organization,
+    };
+    FLEET_STORAGE.with(|service| service.borrow_mut().insert(id, fleet.clone()));
+    audit::record("create_fleet", "fleet", id, None, Some(&fleet));
+    Ok(fleet)
+}
+
+// Delete a fleet; its vessels are not affected (operators only)
+#[ic_cdk::update]
+fn delete_fleet(id: u64) -> Result<(), Error> {
+    require_role(Role::Operator)?;
+    let fleet = get_fleet(id)?;
+    for vessel_id in fleet_vessel_ids(id) {
+        FLEET_VESSELS.with(|index| index.borrow_mut().remove(&(id, vessel_id)));
+    }
+    FLEET_STORAGE.with(|service| service.borrow_mut().remove(&id));
+    audit::record("delete_fleet", "fleet", id, Some(&fleet), None);
+    Ok(())
+}
+
+// Add a vessel to a fleet (operators only)
+#[ic_cdk::update]
+fn add_vessel_to_fleet(fleet_id: u64, vessel_id: u64) -> Result<(), Error> {
+    require_role(Role::Operator)?;
+    get_fleet(fleet_id)?;
+    require_vessel_access(vessel_id, VesselAccess::View)?;
+    if _get_vessel(&vessel_id).is_none() {
+        return Err(Error::NotFound {
+            msg: format!("a vessel with id={} not found", vessel_id),
+        });
+    }
+    FLEET_VESSELS.with(|index| index.borrow_mut().insert((fleet_id, vessel_id), ()));
+    audit::record(
+        "add_vessel_to_fleet",
+        "fleet",
+        fleet_id,
+        None,
+        Some(&vessel_id),
+    );
+    Ok(())
+}
+
+// Remove a vessel from a fleet (operators only)
+#[ic_cdk::update]
+fn remove_vessel_from_fleet(fleet_id: u64, vessel_id: u64) -> Result<(), Error> {
+    require_role(Role::Operator)?;
+    get_fleet(fleet_id)?;
+    require_vessel_access(vessel_id, VesselAccess::View)?;
+    if FLEET_VESSELS
+        .with(|index| index.borrow_mut().remove(&(fleet_id, vessel_id)))
+        .is_none()
+    {
+        return Err(Error::NotFound {
+            msg: format!("vessel id={} is not in fleet id={}", vessel_id, fleet_id),
+        });
+    }
+    audit::record(
+        "remove_vessel_from_fleet",
+        "fleet",
+        fleet_id,
+        Some(&vessel_id),
+        None,
+    );
+    Ok(())
+}
+
+// List the fleets of the caller's organizations
+#[ic_cdk::query]
+fn get_fleets() -> Vec<Fleet> {
+    FLEET_STORAGE.with(|service| {
+        service
+            .borrow()
+            .iter()
+            .map(|(_, fleet)| fleet)
+            .filter(|fleet| is_member(fleet.organization))
+            .collect()
+    })
+}
+
+// List the vessels of a fleet the caller may view
+#[ic_cdk::query]
+fn get_fleet_vessels(fleet_id: u64) -> Result<Vec<Vessel>, Error> {
+    get_fleet(fleet_id)?;
+    Ok(visible_vessels(fleet_id))
+}
+
+// Summarize what the vessels of a fleet are doing
+#[ic_cdk::query]
+fn get_fleet_dashboard(fleet_id: u64) -> Result<FleetDashboard, Error> {
+    let fleet = get_fleet(fleet_id)?;
+    let now = time();
+    let vessels = visible_vessels(fleet_id);
+
+    // Open voyages and the latest arrival of each vessel
+    let mut open_voyages: BTreeMap<u64, u32> = BTreeMap::new();
+    let mut last_arrival: BTreeMap<u64, u64> = BTreeMap::new();
+    for vessel in &vessels {
+        for voyage in vessel_voyages(vessel.id) {
+            match voyage.arrival_time {
+                None if voyage.departure_time <= now => {
+                    *open_voyages.entry(voyage.vessel_id).or_default() += 1
+                }
+                Some(arrival) if arrival <= now => {
+                    let latest = last_arrival.entry(voyage.vessel_id).or_default();
+                    *latest = (*latest).max(arrival);
+                }
+                _ => {}
+            }
+        }
+    }
+
+    let statuses: Vec<FleetVesselStatus> = vessels
+        .into_iter()
+        .map(|vessel| {
+            let operational_status = current_status(vessel.id).map(|change| change.status);
+            // A status set with set_operational_status wins over what the voyages suggest
+            let state = if let Some(status) = operational_status {
+                match status {
+                    OperationalStatus::Sailing => VesselState::Underway,
+                    OperationalStatus::InPort | OperationalStatus::AtAnchor => VesselState::InPort,
+                    OperationalStatus::DryDock
+                    | OperationalStatus::LaidUp
+                    | OperationalStatus::OffHire => VesselState::Idle,
+                }
+            } else if open_voyages.contains_key(&vessel.id) {
+                VesselState::Underway
+            } else if last_arrival
+                .get(&vessel.id)
+                .is_some_and(|&arrival| now - arrival <= IDLE_AFTER)
+            {
+                VesselState::InPort
+            } else {
+                VesselState::Idle
+            };
+            FleetVesselStatus {
+                vessel_id: vessel.id,
+                speed_knots: speed_knots(vessel.id, now),
+                state,
+                operational_status,
+                staleness_secs: now.saturating_sub(vessel.last_update) / NANOS_PER_SECOND,
+                last_seen: vessel.last_update,
+                current_location: vessel.current_location,
+                name: vessel.name,
+            }
+        })
+        .collect();
+
+    let count = |state| statuses.iter().filter(|s| s.state == state).count() as u32;
+    let underway_speeds: Vec<f64> = statuses
+        .iter()
+        .filter(|s| s.state == VesselState::Underway)
+        .filter_map(|s| s.speed_knots)
+        .collect();
+    Ok(FleetDashboard {
+        fleet,
+        vessels: statuses.len() as u32,
+        underway: count(VesselState::Underway),
+        in_port: count(VesselState::InPort),
+        idle: count(VesselState::Idle),
+        open_voyages: open_voyages.values().sum(),
+        average_speed_knots: (!underway_speeds.is_empty())
+            .then(|| underway_speeds.iter().sum::<f64>() / underway_speeds.len() as f64),
+        stale_vessels: statuses
+            .iter()
+            .filter(|s| s.staleness_secs * NANOS_PER_SECOND > STALE_VESSEL_AFTER)
+            .count() as u32,
+        max_staleness_secs: statuses.iter().map(|s| s.staleness_secs).max(),
+        vessel_statuses: statuses,
+    })
+}
+
+// Distance covered between the position reports of the last SPEED_WINDOW over the time between them
+fn speed_knots(vessel_id: u64, now: u64) -> Option<f64> {
+    let from = now.saturating_sub(SPEED_WINDOW);
+    let track: Vec<(u64, Position)> = vessel_track(vessel_id, from, now, MAX_SPEED_POINTS)
+        .into_iter()
+        .filter(|(at, _)| *at >= from)
+        .filter_map(|(at, location)| Position::parse(&location).map(|p| (at, p)))
+        .collect();
+    let (first, last) = (track.first()?, track.last()?);
+    let hours = last.0.checked_sub(first.0).filter(|&d| d > 0)? as f64 / NANOS_PER_HOUR as f64;
+    let distance: f64 = track
+        .windows(2)
+        .map(|pair| pair[0].1.distance_nm(&pair[1].1))
+        .sum();
+    Some(distance / hours)
+}
+
+fn visible_vessels(fleet_id: u64) -> Vec<Vessel> {
+    fleet_vessel_ids(fleet_id)
+        .into_iter()
+        .filter(|&id| can_access_vessel(id, VesselAccess::View))
+        .filter_map(|id| _get_vessel(&id))
+        .collect()
+}
+
+fn fleet_vessel_ids(fleet_id: u64) -> Vec<u64> {
+    FLEET_VESSELS.with(|index| {
+        index
+            .borrow()
+            .range((fleet_id, 0)..=(fleet_id, u64::MAX))
+            .map(|((_, vessel_id), _)| vessel_id)
+            .collect()
+    })
+}
+
+// Look up a fleet of one of the caller's organizations
+fn get_fleet(id: u64) -> Result<Fleet, Error> {
+    let fleet = FLEET_STORAGE
+        .with(|service| service.borrow().get(&id))
+        .ok_or(Error::NotFound {
+            msg: format!("a fleet with id={} not found", id),
+        })?;
+    if !is_member(fleet.organization) {
+        return Err(Error::Unauthorized {
+            msg: format!("caller {} may not access fleet id={}", caller(), id),
+        });
+    }
+    Ok(fleet)
+}
@@ -0,0 +1,244 @@
+// Read-only JSON REST API served through the HTTP gateway. Gateway requests are
+// anonymous, and anonymous callers belong to no organization, so they are served
+// only what every caller may view.
+use crate::certification::http_certificate_header;
+use crate::organizations::{can_access_vessel, VesselAccess};
+use crate::{_get_vessel, _get_voyage, Vessel, Voyage, VESSEL_STORAGE, VOYAGE_STORAGE};
+use serde_json::json;
+
+const DEFAULT_PAGE_SIZE: usize = 50;
+const MAX_PAGE_SIZE: usize = 500;
+
+type HeaderField = (String, String);
+
+// Define the request and response types of the HTTP gateway interface; the request
+// headers and body are not needed for read-only routes and are skipped when decoding
+#[derive(candid::CandidType, Deserialize)]
+pub(crate) struct HttpRequest {
+    method: String,
+    url: String,
+}
+
+#[derive(candid::CandidType, Serialize)]
+pub(crate) struct HttpResponse {
+    status_code: u16,
+    headers: Vec<HeaderField>,
+    body: Vec<u8>,
+}
+
+// Answer GET requests from non-Candid clients
+#[ic_cdk::query]
+fn http_request(request: HttpRequest) -> HttpResponse {
+    if request.method != "GET" && request.method != "HEAD" {
+        return error(405, format!("method {} is not allowed", request.method));
+    }
+    let (path, query) = request
+        .url
+        .split_once('?')
+        .unwrap_or((request.url.as_str(), ""));
+    let params = parse_query(query);
+    let param = |name: &str| {
+        params
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+    };
+    let segments: Vec<&str> = path.split('/').filter(|s| !s.is_empty()).collect();
+
+    let response = match segments.as_slice() {
+        ["vessels"] => list_vessels(param("start"), param("limit")),
+        ["vessels", id] => get_vessel(id),
+        ["voyages"] => list_voyages(param("vessel_id"), param("start"), param("limit")),
+        ["voyages", id] => get_voyage(id),
+        ["ports", locode] => get_port(locode),
+        _ => Err(error(404, format!("no route for {}", path))),
+    };
+    let response = response.unwrap_or_else(|e| e);
+    if request.method == "HEAD" {
+        return HttpResponse {
+            body: Vec::new(),
+            ..response
+        };
+    }
+    response
+}
+
+// GET /vessels?start=&limit=
+fn list_vessels(start: Option<&str>, limit: Option<&str>) -> Result<HttpResponse, HttpResponse> {
+    let (start, limit) = page(start, limit)?;
+    let vessels: Vec<Vessel> = VESSEL_STORAGE.with(|service| {
+        service
+            .borrow()
+            .range(start..)
+            .filter(|(id, _)| can_access_vessel(*id, VesselAccess::View))
+            .take(limit)
+            .map(|(_, vessel)| vessel)
+            .collect()
+    });
+    Ok(ok(json!(vessels), 10))
+}
+
+// GET /vessels/{id}
+fn get_vessel(id: &str) -> Result<HttpResponse, HttpResponse> {
+    let id = parse_id("vessel id", id)?;
+    let vessel = _get_vessel(&id)
+        .filter(|_| can_access_vessel(id, VesselAccess::View))
+        .ok_or_else(|| error(404, format!("a vessel with id={} not found", id)))?;
+    Ok(certified(
+        ok(json!(vessel), 30),
+        &format!("/vessels/{}", id),
+    ))
+}
+
+// GET /voyages?vessel_id=&start=&limit=
+fn list_voyages(
+    vessel_id: Option<&str>,
+    start: Option<&str>,
+    limit: Option<&str>,
+) -> Result<HttpResponse, HttpResponse> {
+    let vessel_id = vessel_id.map(|id| parse_id("vessel_id", id)).transpose()?;
+    let (start, limit) = page(start, limit)?;
+    let voyages: Vec<Voyage> = VOYAGE_STORAGE.with(|service| {
+        service
+            .borrow()
+            .range(start..)
+            .map(|(_, voyage)| voyage)
+            .filter(|voyage| vessel_id.is_none_or(|id| voyage.vessel_id == id))
+            .filter(|voyage| can_access_vessel(voyage.vessel_id, VesselAccess::View))
+            .take(limit)
+            .collect()
+    });
+    Ok(ok(json!(voyages), 10))
+}
+
+// GET /voyages/{id}
+fn get_voyage(id: &str) -> Result<HttpResponse, HttpResponse> {
+    let id = parse_id("voyage id", id)?;
+    let voyage = _get_voyage(&id)
+        .filter(|v| can_access_vessel(v.vessel_id, VesselAccess::View))
+        .ok_or_else(|| error(404, format!("a voyage with id={} not found", id)))?;
+    Ok(certified(
+        ok(json!(voyage), 30),
+        &format!("/voyages/{}", id),
+    ))
+}
+
+// GET /ports/{locode}: voyages departing from or bound for a UN/LOCODE port
+fn get_port(locode: &str) -> Result<HttpResponse, HttpResponse> {
+    let locode = percent_decode(locode).replace(' ', "").to_uppercase();
+    let valid = locode.len() == 5
+        && locode.is_ascii()
+        && locode[..2].chars().all(|c| c.is_ascii_alphabetic())
+        && locode[2..].chars().all(|c| c.is_ascii_alphanumeric());
+    if !valid {
+        return Err(error(400, format!("{:?} is not a UN/LOCODE", locode)));
+    }
+    let matches = |port: &str| port.replace(' ', "").eq_ignore_ascii_case(&locode);
+    let (departures, arrivals): (Vec<Voyage>, Vec<Voyage>) = VOYAGE_STORAGE.with(|service| {
+        service
+            .borrow()
+            .iter()
+            .map(|(_, voyage)| voyage)
+            .filter(|v| matches(&v.departure_port) || matches(&v.destination_port))
+            .filter(|v| can_access_vessel(v.vessel_id, VesselAccess::View))
+            .partition(|v| matches(&v.departure_port))
+    });
+    if departures.is_empty() && arrivals.is_empty() {
+        return Err(error(404, format!("no voyages call at port {}", locode)));
+    }
+    Ok(ok(
+        json!({ "locode": locode, "departures": departures, "arrivals": arrivals }),
+        10,
+    ))
+}
+
+fn page(start: Option<&str>, limit: Option<&str>) -> Result<(u64, usize), HttpResponse> {
+    let start = start
+        .map(|s| parse_id("start", s))
+        .transpose()?
+        .unwrap_or(0);
+    let limit = match limit {
+        Some(limit) => limit
+            .parse::<usize>()
+            .map_err(|_| error(400, format!("limit: cannot parse {:?}", limit)))?,
+        None => DEFAULT_PAGE_SIZE,
+    };
+    Ok((start, limit.clamp(1, MAX_PAGE_SIZE)))
+}
+
+fn parse_id(name: &str, value: &str) -> Result<u64, HttpResponse> {
+    value
+        .parse()
+        .map_err(|_| error(400, format!("{}: cannot parse {:?}", name, value)))
+}
+
+fn parse_query(query: &str) -> Vec<(String, String)> {
+    query
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+            (percent_decode(key), percent_decode(value))
+        })
+        .collect()
+}
+
+// Decode %XX escapes and '+' as space; malformed escapes are kept as-is
+fn percent_decode(input: &str) -> String {
+    let hex = |byte: Option<&u8>| (*byte? as char).to_digit(16);
+    let bytes = input.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match (bytes[i], hex(bytes.get(i + 1)), hex(bytes.get(i + 2))) {
+            (b'%', Some(high), Some(low)) => {
+                decoded.push((high * 16 + low) as u8);
+                i += 3;
+                continue;
+            }
+            (b'+', _, _) => decoded.push(b' '),
+            (byte, _, _) => decoded.push(byte),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+// Response bodies are rendered the same way when they are certified
+pub(crate) fn json_body(body: &serde_json::Value) -> Vec<u8> {
+    body.to_string().into_bytes()
+}
+
+// Attach the certificate for a path whose body is kept in the certified tree
+fn certified(mut response: HttpResponse, path: &str) -> HttpResponse {
+    response.headers.extend(http_certificate_header(path));
+    response
+}
+
+fn ok(body: serde_json::Value, max_age: u32) -> HttpResponse {
+    respond(200, body, format!("public, max-age={}", max_age))
+}
+
+fn error(status_code: u16, message: String) -> HttpResponse {
+    respond(
+        status_code,
+        json!({ "error": message }),
+        "no-store".to_string(),
+    )
+}
+
+fn respond(status_code: u16, body: serde_json::Value, cache_control: String) -> HttpResponse {
+    let body = json_body(&body);
+    HttpResponse {
+        status_code,
+        headers: vec![
+            (
+                "Content-Type".to_string(),
+                "application/json; charset=utf-8".to_string(),
+            ),
+            ("Content-Length".to_string(), body.len().to_string()),
+            ("Cache-Control".to_string(), cache_control),
+        ],
+        body,
+    }
+}
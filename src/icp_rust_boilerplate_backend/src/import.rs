@@ -0,0 +1,361 @@
+// Bulk import of vessels and voyages from CSV or JSON, with column mapping and dry runs
+use crate::batch::{claim_window, MAX_BATCH_SIZE};
+use crate::organizations::resolve_owner;
+use crate::{
+    check_new_voyage, do_add_vessel, do_add_voyage, new_voyage_times, Error, Vessel, Voyage,
+};
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::str::FromStr;
+
+const VESSEL_FIELDS: [&str; 6] = [
+    "name",
+    "captain",
+    "capacity",
+    "current_location",
+    "flag",
+    "year_built",
+];
+const VOYAGE_FIELDS: [&str; 8] = [
+    "vessel_id",
+    "departure_port",
+    "destination_port",
+    "eta",
+    "departure_time",
+    "arrival_time",
+    "scheduled_departure",
+    "scheduled_arrival",
+];
+
+// Define the supported import formats
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize)]
+pub(crate) enum ImportFormat {
+    // Comma-separated with a header row
+    Csv,
+    // An array of flat objects
+    Json,
+}
+
+// Map a column (CSV header or JSON key) onto a record field
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct ColumnMapping {
+    column: String,
+    field: String,
+}
+
+// Define the structure for an import request
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct ImportRequest {
+    format: ImportFormat,
+    data: Vec<u8>,
+    // Columns without a mapping are matched to fields by name
+    columns: Vec<ColumnMapping>,
+    // Validate and report without storing anything
+    dry_run: bool,
+    // Owner of imported vessels; defaults to the caller's only organization
+    owner_organization: Option<u64>,
+}
+
+// Validation errors for one row; rows are numbered from 1, not counting the CSV header
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct RowError {
+    row: u32,
+    errors: Vec<Error>,
+}
+
+// Outcome of an import; rows are only stored when every row is valid
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct ImportReport {
+    rows: u32,
+    committed: bool,
+    ids: Vec<u64>,
+    errors: Vec<RowError>,
+}
+
+type Row = BTreeMap<String, String>;
+
+// Import vessels from a CSV or JSON blob
+#[ic_cdk::update]
+fn import_vessels(request: ImportRequest) -> Result<ImportReport, Error> {
+    let owner = resolve_owner(request.owner_organization)?;
+    let rows = parse_rows(&request, &VESSEL_FIELDS)?;
+    let checked = rows.iter().map(vessel_from_row).collect();
+    Ok(commit(checked, request.dry_run, |vessel| {
+        do_add_vessel("import_vessels", vessel, owner).id
+    }))
+}
+
+// Import voyages from a CSV or JSON blob
+#[ic_cdk::update]
+fn import_voyages(request: ImportRequest) -> Result<ImportReport, Error> {
+    let rows = parse_rows(&request, &VOYAGE_FIELDS)?;
+    let mut claimed = Vec::new();
+    let checked = rows
+        .iter()
+        .map(|row| {
+            let voyage = voyage_from_row(row)?;
+            claim_window(&mut claimed, &new_voyage_times(&voyage)).map_err(|e| vec![e])?;
+            check_new_voyage(&voyage, &[]).map_err(|e| vec![e])?;
+            Ok(voyage)
+        })
+        .collect();
+    Ok(commit(checked, request.dry_run, |voyage| {
+        do_add_voyage("import_voyages", voyage).id
+    }))
+}
+
+// Store every row if all of them validated and this is not a dry run
+fn commit<T>(
+    checked: Vec<Result<T, Vec<Error>>>,
+    dry_run: bool,
+    mut insert: impl FnMut(T) -> u64,
+) -> ImportReport {
+    let rows = checked.len() as u32;
+    let mut valid = Vec::new();
+    let mut errors = Vec::new();
+    for (index, result) in checked.into_iter().enumerate() {
+        match result {
+            Ok(record) => valid.push(record),
+            Err(row_errors) => errors.push(RowError {
+                row: index as u32 + 1,
+                errors: row_errors,
+            }),
+        }
+    }
+
+    if dry_run || !errors.is_empty() {
+        return ImportReport {
+            rows,
+            committed: false,
+            ids: Vec::new(),
+            errors,
+        };
+    }
+    let ids = valid.into_iter().map(&mut insert).collect();
+    ImportReport {
+        rows,
+        committed: true,
+        ids,
+        errors,
+    }
+}
+
+fn vessel_from_row(row: &Row) -> Result<Vessel, Vec<Error>> {
+    let mut errors = Vec::new();
+    let name = required(row, "name", &mut errors);
+    let captain = required(row, "captain", &mut errors);
+    let capacity = required(row, "capacity", &mut errors);
+    let current_location = required(row, "current_location", &mut errors);
+    let flag = optional(row, "flag", &mut errors);
+    let year_built = optional(row, "year_built", &mut errors);
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+    Ok(Vessel {
+        name: name.unwrap_or_default(),
+        captain: captain.unwrap_or_default(),
+        capacity: capacity.unwrap_or_default(),
+        current_location: current_location.unwrap_or_default(),
+        flag,
+        year_built,
+        ..Default::default()
+    })
+}
+
+fn voyage_from_row(row: &Row) -> Result<Voyage, Vec<Error>> {
+    let mut errors = Vec::new();
+    let vessel_id = required(row, "vessel_id", &mut errors);
+    let departure_port = required(row, "departure_port", &mut errors);
+    let destination_port = required(row, "destination_port", &mut errors);
+    let eta = optional(row, "eta", &mut errors);
+    let departure_time = optional(row, "departure_time", &mut errors);
+    let arrival_time = optional(row, "arrival_time", &mut errors);
+    let scheduled_departure = optional(row, "scheduled_departure", &mut errors);
+    let scheduled_arrival = optional(row, "scheduled_arrival", &mut errors);
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+    Ok(Voyage {
+        vessel_id: vessel_id.unwrap_or_default(),
+        departure_port: departure_port.unwrap_or_default(),
+        destination_port: destination_port.unwrap_or_default(),
+        eta,
+        departure_time: departure_time.unwrap_or_default(),
+        arrival_time,
+        scheduled_departure,
+        scheduled_arrival,
+        ..Default::default()
+    })
+}
+
+// Read a field that must be present and parse it
+fn required<T: FromStr>(row: &Row, field: &str, errors: &mut Vec<Error>) -> Option<T> {
+    if row.get(field).is_none_or(|value| value.is_empty()) {
+        errors.push(Error::InvalidInput {
+            msg: format!("{} is required", field),
+        });
+        return None;
+    }
+    optional(row, field, errors)
+}
+
+// Read a field that may be empty or absent and parse it
+fn optional<T: FromStr>(row: &Row, field: &str, errors: &mut Vec<Error>) -> Option<T> {
+    let value = row.get(field).filter(|value| !value.is_empty())?;
+    match value.parse() {
+        Ok(parsed) => Some(parsed),
+        Err(_) => {
+            errors.push(Error::InvalidInput {
+                msg: format!("{}: cannot parse {:?}", field, value),
+            });
+            None
+        }
+    }
+}
+
+// Decode the blob into rows keyed by record field name
+fn parse_rows(request: &ImportRequest, fields: &[&str]) -> Result<Vec<Row>, Error> {
+    for mapping in &request.columns {
+        if !fields.contains(&mapping.field.as_str()) {
+            return Err(Error::InvalidInput {
+                msg: format!(
+                    "column {:?} is mapped to unknown field {:?}; expected one of {}",
+                    mapping.column,
+                    mapping.field,
+                    fields.join(", ")
+                ),
+            });
+        }
+    }
+    let text = std::str::from_utf8(&request.data).map_err(|e| Error::InvalidInput {
+        msg: format!("import data is not valid UTF-8: {}", e),
+    })?;
+    let records = match request.format {
+        ImportFormat::Csv => parse_csv(text)?,
+        ImportFormat::Json => parse_json(text)?,
+    };
+    if records.len() > MAX_BATCH_SIZE {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "an import may hold at most {} rows, got {}",
+                MAX_BATCH_SIZE,
+                records.len()
+            ),
+        });
+    }
+
+    // Rename mapped columns; anything that does not name a field is ignored
+    Ok(records
+        .into_iter()
+        .map(|record| {
+            record
+                .into_iter()
+                .filter_map(|(column, value)| {
+                    let field = request
+                        .columns
+                        .iter()
+                        .find(|m| m.column == column)
+                        .map_or(column, |m| m.field.clone());
+                    fields
+                        .contains(&field.as_str())
+                        .then(|| (field, value.trim().to_string()))
+                })
+                .collect()
+        })
+        .collect())
+}
+
+fn parse_json(text: &str) -> Result<Vec<Vec<(String, String)>>, Error> {
+    let invalid = |msg: String| Error::InvalidInput { msg };
+    let value: Value =
+        serde_json::from_str(text).map_err(|e| invalid(format!("invalid JSON: {}", e)))?;
+    let Value::Array(items) = value else {
+        return Err(invalid(
+            "JSON import data must be an array of objects".to_string(),
+        ));
+    };
+    items
+        .into_iter()
+        .enumerate()
+        .map(|(index, item)| {
+            let Value::Object(object) = item else {
+                return Err(invalid(format!("row {} is not a JSON object", index + 1)));
+            };
+            object
+                .into_iter()
+                .filter(|(_, value)| !value.is_null())
+                .map(|(key, value)| match value {
+                    Value::String(s) => Ok((key, s)),
+                    Value::Number(_) | Value::Bool(_) => Ok((key, value.to_string())),
+                    _ => Err(invalid(format!(
+                        "row {}: {} must be a string, number or boolean",
+                        index + 1,
+                        key
+                    ))),
+                })
+                .collect()
+        })
+        .collect()
+}
+
+// RFC 4180 style: quoted fields may contain commas, newlines and doubled quotes
+fn parse_csv(text: &str) -> Result<Vec<Vec<(String, String)>>, Error> {
+    let mut lines: Vec<Vec<String>> = Vec::new();
+    let mut line = Vec::new();
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        match (c, quoted) {
+            ('"', true) if chars.peek() == Some(&'"') => {
+                chars.next();
+                field.push('"');
+            }
+            ('"', true) => quoted = false,
+            ('"', false) if field.is_empty() => quoted = true,
+            (',', false) => line.push(std::mem::take(&mut field)),
+            ('\r', false) => {}
+            ('\n', false) => {
+                line.push(std::mem::take(&mut field));
+                lines.push(std::mem::take(&mut line));
+            }
+            (c, _) => field.push(c),
+        }
+    }
+    if quoted {
+        return Err(Error::InvalidInput {
+            msg: "CSV data ends inside a quoted field".to_string(),
+        });
+    }
+    if !field.is_empty() || !line.is_empty() {
+        line.push(field);
+        lines.push(line);
+    }
+    lines.retain(|line| line.iter().any(|value| !value.trim().is_empty()));
+
+    let mut lines = lines.into_iter();
+    let header: Vec<String> = match lines.next() {
+        Some(header) => header.into_iter().map(|h| h.trim().to_string()).collect(),
+        None => {
+            return Err(Error::InvalidInput {
+                msg: "CSV data has no header row".to_string(),
+            })
+        }
+    };
+    lines
+        .enumerate()
+        .map(|(index, values)| {
+            if values.len() != header.len() {
+                return Err(Error::InvalidInput {
+                    msg: format!(
+                        "row {} has {} columns, the header has {}",
+                        index + 1,
+                        values.len(),
+                        header.len()
+                    ),
+                });
+            }
+            Ok(header.iter().cloned().zip(values).collect())
+        })
+        .collect()
+}
@@ -0,0 +1,431 @@
+// Incident and casualty reporting with a role-gated investigation workflow
+use crate::access::{require_authenticated, require_role, Role};
+use crate::alerts::{self, AlertTrigger};
+use crate::audit;
+use crate::organizations::{can_access_vessel, require_vessel_access, VesselAccess};
+use crate::{_get_vessel, _get_voyage, Error, IdCell, Memory, VESSEL_MEMORY_MANAGER};
+use candid::{Decode, Encode, Principal};
+use ic_cdk::api::time;
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use std::{borrow::Cow, cell::RefCell};
+
+const MAX_INVOLVED_PERSONS: usize = 50;
+const MAX_TEXT_LEN: usize = 2000;
+const MAX_PERSON_TEXT_LEN: usize = 200;
+
+// Define the kinds of incidents that can be reported
+#[derive(
+    candid::CandidType, Clone, Copy, Serialize, Deserialize, Default, PartialEq, Eq, Debug,
+)]
+pub(crate) enum IncidentType {
+    Collision,
+    Grounding,
+    Pollution,
+    Injury,
+    ManOverboard,
+    Fire,
+    #[default]
+    Other,
+}
+
+// Severity levels following the IMO Casualty Investigation Code
+#[derive(
+    candid::CandidType, Clone, Copy, Serialize, Deserialize, Default, PartialEq, Eq, Debug,
+)]
+pub(crate) enum Severity {
+    #[default]
+    MarineIncident,
+    LessSerious,
+    Serious,
+    VerySerious,
+}
+
+// Define the investigation workflow states
+#[derive(
+    candid::CandidType, Clone, Copy, Serialize, Deserialize, Default, PartialEq, Eq, Debug,
+)]
+pub(crate) enum IncidentStatus {
+    #[default]
+    Reported,
+    Investigating,
+    Closed,
+}
+
+// A geographic position in decimal degrees (WGS 84)
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, Default, PartialEq, Debug)]
+pub(crate) struct Position {
+    pub(crate) latitude: f64,
+    pub(crate) longitude: f64,
+}
+
+// A person involved in an incident
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub(crate) struct InvolvedPerson {
+    name: String,
+    // e.g. "injured", "witness", "officer of the watch"
+    involvement: String,
+    crew_member_id: Option<u64>,
+}
+
+// A recorded transition of the incident workflow
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct IncidentStatusChange {
+    status: IncidentStatus,
+    changed_by: Principal,
+    changed_at: u64,
+    note: String,
+}
+
+// Define the structure for an Incident
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct Incident {
+    id: u64,
+    pub(crate) vessel_id: u64,
+    voyage_id: Option<u64>,
+    incident_type: IncidentType,
+    severity: Severity,
+    occurred_at: u64,
+    position: Position,
+    description: String,
+    involved_persons: Vec<InvolvedPerson>,
+    status: IncidentStatus,
+    reported_by: Principal,
+    reported_at: u64,
+    history: Vec<IncidentStatusChange>,
+}
+
+// Payload used to report an incident
+#[derive(candid::CandidType, Serialize, Deserialize, Default)]
+pub(crate) struct IncidentPayload {
+    vessel_id: u64,
+    voyage_id: Option<u64>,
+    incident_type: IncidentType,
+    severity: Severity,
+    occurred_at: u64,
+    position: Position,
+    description: String,
+    involved_persons: Vec<InvolvedPerson>,
+}
+
+impl Position {
+    // Whether latitude and longitude are within their valid ranges
+    pub(crate) fn is_valid(&self) -> bool {
+        (-90.0..=90.0).contains(&self.latitude) && (-180.0..=180.0).contains(&self.longitude)
+    }
+
+    // Read a "latitude,longitude" pair in decimal degrees
+    pub(crate) fn parse(location: &str) -> Option<Position> {
+        let (latitude, longitude) = location.split_once(',')?;
+        let position = Position {
+            latitude: latitude.trim().parse().ok()?,
+            longitude: longitude.trim().parse().ok()?,
+        };
+        position.is_valid().then_some(position)
+    }
+
+    // Great-circle distance in nautical miles
+    pub(crate) fn distance_nm(&self, other: &Position) -> f64 {
+        const EARTH_RADIUS_NM: f64 = 3440.065;
+        let (lat1, lat2) = (self.latitude.to_radians(), other.latitude.to_radians());
+        let d_lat = lat2 - lat1;
+        let d_lon = (other.longitude - self.longitude).to_radians();
+        let a = (d_lat / 2.0).sin().powi(2) + lat1.cos() * lat2.cos() * (d_lon / 2.0).sin().powi(2);
+        2.0 * EARTH_RADIUS_NM * a.sqrt().asin()
+    }
+}
+
+impl IncidentStatus {
+    // The role required to move an incident from this status to the next one
+    fn transition_role(self, to: IncidentStatus) -> Option<Role> {
+        match (self, to) {
+            (IncidentStatus::Reported, IncidentStatus::Investigating) => Some(Role::Investigator),
+            (IncidentStatus::Investigating, IncidentStatus::Closed) => Some(Role::Investigator),
+            // Dismissing a report without investigation needs an admin
+            (IncidentStatus::Reported, IncidentStatus::Closed) => Some(Role::Admin),
+            _ => None,
+        }
+    }
+}
+
+// Implement Storable trait for Incident
+impl Storable for Incident {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+// Implement BoundedStorable trait for Incident
+impl BoundedStorable for Incident {
+    const MAX_SIZE: u32 = 32 * 1024;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Define thread-local variables for incident storage and indexes
+thread_local! {
+    static INCIDENT_ID_COUNTER: RefCell<IdCell> = RefCell::new(
+        IdCell::init(VESSEL_MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(20))), 0)
+            .expect("Cannot create a counter")
+    );
+
+    static INCIDENT_STORAGE: RefCell<StableBTreeMap<u64, Incident, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            VESSEL_MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(21)))
+    ));
+
+    // (vessel_id, incident_id) -> ()
+    static VESSEL_INCIDENT_INDEX: RefCell<StableBTreeMap<(u64, u64), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            VESSEL_MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(22)))
+    ));
+
+    // (occurred_at, incident_id) -> ()
+    static INCIDENT_DATE_INDEX: RefCell<StableBTreeMap<(u64, u64), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            VESSEL_MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(23)))
+    ));
+
+    // (incident_type, incident_id) -> ()
+    static INCIDENT_TYPE_INDEX: RefCell<StableBTreeMap<(u8, u64), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            VESSEL_MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(24)))
+    ));
+}
+
+// Report a new incident
+#[ic_cdk::update]
+fn report_incident(payload: IncidentPayload) -> Result<Incident, Error> {
+    let reporter = require_authenticated()?;
+    require_vessel_access(payload.vessel_id, VesselAccess::View)?;
+    if _get_vessel(&payload.vessel_id).is_none() {
+        return Err(Error::NotFound {
+            msg: format!("a vessel with id={} not found", payload.vessel_id),
+        });
+    }
+    if let Some(voyage_id) = payload.voyage_id {
+        match _get_voyage(&voyage_id) {
+            Some(voyage) if voyage.vessel_id == payload.vessel_id => {}
+            Some(_) => {
+                return Err(Error::InvalidInput {
+                    msg: format!(
+                        "voyage id={} does not belong to vessel id={}",
+                        voyage_id, payload.vessel_id
+                    ),
+                })
+            }
+            None => {
+                return Err(Error::NotFound {
+                    msg: format!("a voyage with id={} not found", voyage_id),
+                })
+            }
+        }
+    }
+    if !payload.position.is_valid() {
+        return Err(Error::InvalidInput {
+            msg: "position is out of range".to_string(),
+        });
+    }
+    if payload.involved_persons.len() > MAX_INVOLVED_PERSONS {
+        return Err(Error::InvalidInput {
+            msg: format!("at most {} persons can be listed", MAX_INVOLVED_PERSONS),
+        });
+    }
+    let too_long = payload.description.len() > MAX_TEXT_LEN
+        || payload
+            .involved_persons
+            .iter()
+            .any(|p| p.name.len() + p.involvement.len() > MAX_PERSON_TEXT_LEN);
+    if too_long {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "description must not exceed {} bytes and person details {} bytes",
+                MAX_TEXT_LEN, MAX_PERSON_TEXT_LEN
+            ),
+        });
+    }
+
+    // Generate a new ID for the Incident
+    let id = INCIDENT_ID_COUNTER
+        .with(|counter| {
+            let current_value = *counter.borrow().get();
+            counter.borrow_mut().set(current_value + 1)
+        })
+        .expect("cannot increment incident id counter");
+
+    let now = time();
+    let incident = Incident {
+        id,
+        vessel_id: payload.vessel_id,
+        voyage_id: payload.voyage_id,
+        incident_type: payload.incident_type,
+        severity: payload.severity,
+        occurred_at: payload.occurred_at,
+        position: payload.position,
+        description: payload.description,
+        involved_persons: payload.involved_persons,
+        status: IncidentStatus::Reported,
+        reported_by: reporter,
+        reported_at: now,
+        history: vec![IncidentStatusChange {
+            status: IncidentStatus::Reported,
+            changed_by: reporter,
+            changed_at: now,
+            note: String::new(),
+        }],
+    };
+    do_insert_incident(&incident);
+    VESSEL_INCIDENT_INDEX.with(|index| index.borrow_mut().insert((incident.vessel_id, id), ()));
+    INCIDENT_DATE_INDEX.with(|index| index.borrow_mut().insert((incident.occurred_at, id), ()));
+    INCIDENT_TYPE_INDEX.with(|index| {
+        index
+            .borrow_mut()
+            .insert((incident.incident_type as u8, id), ())
+    });
+    audit::record("report_incident", "incident", id, None, Some(&incident));
+    alerts::raise(
+        AlertTrigger::IncidentReported,
+        Some(incident.vessel_id),
+        id,
+        &format!(
+            "{:?} ({:?}) reported on vessel id={}",
+            incident.incident_type, incident.severity, incident.vessel_id
+        ),
+    );
+    Ok(incident)
+}
+
+// Retrieve an Incident by ID
+#[ic_cdk::query]
+fn get_incident(id: u64) -> Result<Incident, Error> {
+    match _get_incident(&id) {
+        Some(incident) => {
+            require_vessel_access(incident.vessel_id, VesselAccess::View)?;
+            Ok(incident)
+        }
+        None => Err(Error::NotFound {
+            msg: format!("an incident with id={} not found", id),
+        }),
+    }
+}
+
+// Move an incident through the reported -> investigating -> closed workflow
+#[ic_cdk::update]
+fn transition_incident(id: u64, status: IncidentStatus, note: String) -> Result<Incident, Error> {
+    let mut incident = match _get_incident(&id) {
+        Some(incident) => incident,
+        None => {
+            return Err(Error::NotFound {
+                msg: format!("an incident with id={} not found", id),
+            })
+        }
+    };
+    let role = match incident.status.transition_role(status) {
+        Some(role) => role,
+        None => {
+            return Err(Error::InvalidInput {
+                msg: format!(
+                    "an incident cannot move from {:?} to {:?}",
+                    incident.status, status
+                ),
+            })
+        }
+    };
+    require_role(role)?;
+    require_vessel_access(incident.vessel_id, VesselAccess::View)?;
+    if note.len() > MAX_TEXT_LEN {
+        return Err(Error::InvalidInput {
+            msg: format!("note must not exceed {} bytes", MAX_TEXT_LEN),
+        });
+    }
+
+    let before = incident.clone();
+    incident.status = status;
+    incident.history.push(IncidentStatusChange {
+        status,
+        changed_by: ic_cdk::caller(),
+        changed_at: time(),
+        note,
+    });
+    do_insert_incident(&incident);
+    audit::record(
+        "transition_incident",
+        "incident",
+        id,
+        Some(&before),
+        Some(&incident),
+    );
+    Ok(incident)
+}
+
+// List the incidents of a vessel
+#[ic_cdk::query]
+fn get_incidents_by_vessel(vessel_id: u64) -> Vec<Incident> {
+    if !can_access_vessel(vessel_id, VesselAccess::View) {
+        return Vec::new();
+    }
+    let ids: Vec<u64> = VESSEL_INCIDENT_INDEX.with(|index| {
+        index
+            .borrow()
+            .range((vessel_id, 0)..=(vessel_id, u64::MAX))
+            .map(|((_, id), _)| id)
+            .collect()
+    });
+    ids.iter().filter_map(_get_incident).collect()
+}
+
+// List the incidents of a given type
+#[ic_cdk::query]
+fn get_incidents_by_type(incident_type: IncidentType) -> Vec<Incident> {
+    let code = incident_type as u8;
+    let ids: Vec<u64> = INCIDENT_TYPE_INDEX.with(|index| {
+        index
+            .borrow()
+            .range((code, 0)..=(code, u64::MAX))
+            .map(|((_, id), _)| id)
+            .collect()
+    });
+    ids.iter()
+        .filter_map(_get_incident)
+        .filter(visible)
+        .collect()
+}
+
+// List the incidents that occurred between two timestamps (inclusive), oldest first
+#[ic_cdk::query]
+fn get_incidents_by_date(from: u64, to: u64) -> Vec<Incident> {
+    let ids: Vec<u64> = INCIDENT_DATE_INDEX.with(|index| {
+        index
+            .borrow()
+            .range((from, 0)..=(to, u64::MAX))
+            .map(|((_, id), _)| id)
+            .collect()
+    });
+    ids.iter()
+        .filter_map(_get_incident)
+        .filter(visible)
+        .collect()
+}
+
+// Whether the caller may see the vessel an incident happened on
+fn visible(incident: &Incident) -> bool {
+    can_access_vessel(incident.vessel_id, VesselAccess::View)
+}
+
+// Helper method to insert an Incident into storage
+fn do_insert_incident(incident: &Incident) {
+    INCIDENT_STORAGE.with(|service| service.borrow_mut().insert(incident.id, incident.clone()));
+}
+
+// Retrieve an Incident by ID from storage
+fn _get_incident(id: &u64) -> Option<Incident> {
+    INCIDENT_STORAGE.with(|service| service.borrow().get(id))
+}
+
+// Page through incidents in ID order, starting at `start`
+pub(crate) fn incidents_from(start: u64, limit: usize) -> Vec<(u64, Incident)> {
+    INCIDENT_STORAGE.with(|service| service.borrow().range(start..).take(limit).collect())
+}
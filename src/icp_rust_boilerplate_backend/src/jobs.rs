@@ -0,0 +1,313 @@
+// Periodic background jobs: overdue voyages, stale vessels, certificate expiries, departures
+// of scheduled voyages, retention purges
+use crate::access::{has_role, require_role, Role};
+use crate::alerts::{self, truncate, AlertTrigger};
+use crate::archive::purge_expired;
+use crate::calendar::NANOS_PER_DAY;
+use crate::events;
+use crate::organizations::{can_access_vessel, VesselAccess};
+use crate::ship_certificates::ship_certificates_from;
+use crate::stcw::crew_certificates_from;
+use crate::subscriptions;
+use crate::{
+    audit, vessels_from, voyages_from, Error, Memory, Vessel, Voyage, VESSEL_MEMORY_MANAGER,
+};
+use candid::{Decode, Encode};
+use ic_cdk::api::{caller, time};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, Cell, StableBTreeMap, Storable};
+use std::collections::BTreeMap;
+use std::time::Duration;
+use std::{borrow::Cow, cell::RefCell};
+
+const JOB_INTERVAL: Duration = Duration::from_secs(300);
+// Records examined per job and run, to stay within the instruction limit
+const CHUNK_SIZE: usize = 200;
+pub(crate) const STALE_VESSEL_AFTER: u64 = NANOS_PER_DAY;
+const EXPIRY_WARNING: u64 = 30 * NANOS_PER_DAY;
+// Details embed names and ports; the bound keeps a Flag within its MAX_SIZE
+const MAX_DETAIL_LEN: usize = 300;
+
+// Define the conditions the jobs look for
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Debug)]
+pub(crate) enum FlagKind {
+    OverdueVoyage,
+    StaleVessel,
+    ExpiringShipCertificate,
+    ExpiringCrewCertificate,
+}
+
+// A condition raised by a job on a record; cleared once the condition no longer holds
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct Flag {
+    kind: FlagKind,
+    target_id: u64,
+    // The vessel concerned; None for crew certificates and flags raised before it was kept
+    vessel_id: Option<u64>,
+    raised_at: u64,
+    detail: String,
+}
+
+// Where each job resumes on its next run; a cursor of 0 starts a new pass
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub(crate) struct JobStatus {
+    voyage_cursor: u64,
+    vessel_cursor: u64,
+    ship_certificate_cursor: u64,
+    crew_certificate_cursor: u64,
+    runs: u64,
+    last_run: Option<u64>,
+}
+
+impl FlagKind {
+    fn key(self) -> u8 {
+        self as u8
+    }
+
+    fn trigger(self) -> AlertTrigger {
+        match self {
+            FlagKind::OverdueVoyage => AlertTrigger::OverdueVoyage,
+            FlagKind::StaleVessel => AlertTrigger::StaleVessel,
+            FlagKind::ExpiringShipCertificate => AlertTrigger::ExpiringShipCertificate,
+            FlagKind::ExpiringCrewCertificate => AlertTrigger::ExpiringCrewCertificate,
+        }
+    }
+}
+
+// Implement Storable trait for Flag
+impl Storable for Flag {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+// Implement BoundedStorable trait for Flag
+impl BoundedStorable for Flag {
+    const MAX_SIZE: u32 = 512;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Implement Storable trait for JobStatus
+impl Storable for JobStatus {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+// Define thread-local variables for job state
+thread_local! {
+    // (kind, target_id) -> Flag
+    static FLAGS: RefCell<StableBTreeMap<(u8, u64), Flag, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            VESSEL_MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(32)))
+    ));
+
+    static JOB_STATUS: RefCell<Cell<JobStatus, Memory>> = RefCell::new(
+        Cell::init(
+            VESSEL_MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(33))),
+            JobStatus::default(),
+        )
+        .expect("Cannot create the job status")
+    );
+}
+
+// List the raised flags of the vessels the caller may view, optionally of one kind.
+// Flags not tied to a vessel are shown to admins only.
+#[ic_cdk::query]
+fn get_flags(kind: Option<FlagKind>) -> Vec<Flag> {
+    let is_admin = has_role(&caller(), Role::Admin);
+    let visible = |flag: &Flag| match flag.vessel_id {
+        Some(vessel_id) => can_access_vessel(vessel_id, VesselAccess::View),
+        None => is_admin,
+    };
+    FLAGS.with(|flags| {
+        let flags = flags.borrow();
+        match kind {
+            Some(kind) => flags
+                .range((kind.key(), 0)..=(kind.key(), u64::MAX))
+                .map(|(_, flag)| flag)
+                .filter(visible)
+                .collect(),
+            None => flags.iter().map(|(_, flag)| flag).filter(visible).collect(),
+        }
+    })
+}
+
+// Report the job cursors and the time of the last run
+#[ic_cdk::query]
+fn get_job_status() -> JobStatus {
+    JOB_STATUS.with(|status| status.borrow().get().clone())
+}
+
+// Run one round of the jobs now (admins only)
+#[ic_cdk::update]
+fn run_jobs() -> Result<JobStatus, Error> {
+    require_role(Role::Admin)?;
+    let before = get_job_status();
+    run();
+    let after = get_job_status();
+    audit::record("run_jobs", "jobs", "status", Some(&before), Some(&after));
+    Ok(after)
+}
+
+// Arm the periodic timer; timers do not survive upgrades, so this runs from init and post_upgrade
+pub(crate) fn start() {
+    ic_cdk_timers::set_timer_interval(JOB_INTERVAL, run);
+}
+
+// One round of every job, each resuming from its persisted cursor
+fn run() {
+    let now = time();
+    let mut status = get_job_status();
+    status.voyage_cursor = scan(
+        FlagKind::OverdueVoyage,
+        status.voyage_cursor,
+        voyages_from,
+        |voyage: &Voyage| match (voyage.arrival_time, voyage.eta) {
+            (None, Some(eta)) if eta < now => Some((
+                Some(voyage.vessel_id),
+                format!(
+                    "voyage from {} to {} is past its ETA",
+                    voyage.departure_port, voyage.destination_port
+                ),
+            )),
+            _ => None,
+        },
+    );
+    status.vessel_cursor = scan(
+        FlagKind::StaleVessel,
+        status.vessel_cursor,
+        vessels_from,
+        |vessel: &Vessel| {
+            (now.saturating_sub(vessel.last_update) > STALE_VESSEL_AFTER).then(|| {
+                (
+                    Some(vessel.id),
+                    format!(
+                        "{} has not reported for {} hours",
+                        vessel.name,
+                        (now - vessel.last_update) / (NANOS_PER_DAY / 24)
+                    ),
+                )
+            })
+        },
+    );
+    status.ship_certificate_cursor = scan(
+        FlagKind::ExpiringShipCertificate,
+        status.ship_certificate_cursor,
+        ship_certificates_from,
+        |certificate| {
+            let detail = if certificate.is_lapsed_at(now) {
+                Some(format!(
+                    "certificate of vessel id={} has lapsed",
+                    certificate.vessel_id
+                ))
+            } else {
+                (certificate.expiry_date < now + EXPIRY_WARNING).then(|| {
+                    format!(
+                        "certificate of vessel id={} expires within {} days",
+                        certificate.vessel_id,
+                        EXPIRY_WARNING / NANOS_PER_DAY
+                    )
+                })
+            };
+            detail.map(|detail| (Some(certificate.vessel_id), detail))
+        },
+    );
+    status.crew_certificate_cursor = scan(
+        FlagKind::ExpiringCrewCertificate,
+        status.crew_certificate_cursor,
+        crew_certificates_from,
+        |certificate| {
+            let detail = if certificate.expiry_date <= now {
+                Some(format!(
+                    "{:?} certificate of crew member id={} has expired",
+                    certificate.kind, certificate.crew_member_id
+                ))
+            } else {
+                (certificate.expiry_date < now + EXPIRY_WARNING).then(|| {
+                    format!(
+                        "{:?} certificate of crew member id={} expires within {} days",
+                        certificate.kind,
+                        certificate.crew_member_id,
+                        EXPIRY_WARNING / NANOS_PER_DAY
+                    )
+                })
+            };
+            // Crew certificates are not tied to a vessel
+            detail.map(|detail| (None, detail))
+        },
+    );
+    events::announce_departures(CHUNK_SIZE);
+    purge_expired(CHUNK_SIZE as u32);
+    subscriptions::purge_deliveries(CHUNK_SIZE);
+    subscriptions::purge_unsubscribed(CHUNK_SIZE);
+    // Re-arms event delivery after an upgrade dropped the flush timer
+    subscriptions::flush();
+
+    status.runs += 1;
+    status.last_run = Some(now);
+    JOB_STATUS
+        .with(|cell| cell.borrow_mut().set(status))
+        .expect("cannot update the job status");
+}
+
+// Check one chunk of records from `start`, raising and clearing flags of the given kind.
+// `check` returns the vessel concerned and a description; newly raised flags also raise
+// an alert. Returns the cursor for the next run.
+fn scan<T>(
+    kind: FlagKind,
+    start: u64,
+    page: fn(u64, usize) -> Vec<(u64, T)>,
+    check: impl Fn(&T) -> Option<(Option<u64>, String)>,
+) -> u64 {
+    let mut records = page(start, CHUNK_SIZE + 1);
+    // The records between the chunk and the next cursor are covered, including deleted ones
+    let (next, end) = if records.len() > CHUNK_SIZE {
+        let next = records.pop().map(|(id, _)| id).unwrap_or_default();
+        (next, next - 1)
+    } else {
+        (0, u64::MAX)
+    };
+
+    let now = time();
+    FLAGS.with(|flags| {
+        let mut flags = flags.borrow_mut();
+        let mut existing: BTreeMap<u64, Flag> = flags
+            .range((kind.key(), start)..=(kind.key(), end))
+            .map(|((_, id), flag)| (id, flag))
+            .collect();
+        for (id, record) in &records {
+            if let Some((vessel_id, detail)) = check(record) {
+                let detail = truncate(&detail, MAX_DETAIL_LEN);
+                let raised_at = match existing.remove(id) {
+                    Some(flag) => flag.raised_at,
+                    None => {
+                        alerts::raise(kind.trigger(), vessel_id, *id, &detail);
+                        now
+                    }
+                };
+                let flag = Flag {
+                    kind,
+                    target_id: *id,
+                    vessel_id,
+                    raised_at,
+                    detail,
+                };
+                flags.insert((kind.key(), *id), flag);
+            }
+        }
+        // Whatever was flagged before and was not raised again is cleared
+        for id in existing.keys() {
+            flags.remove(&(kind.key(), *id));
+        }
+    });
+    next
+}
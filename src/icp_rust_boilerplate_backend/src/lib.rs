@@ -1,12 +1,70 @@
 // Import necessary crates and modules
 #[macro_use]
 extern crate serde;
-use candid::{Decode, Encode};
+use candid::{Decode, Encode, Principal};
 use ic_cdk::api::time;
 use ic_stable_structures::memory_manager::{MemoryId, MemoryManager, VirtualMemory};
 use ic_stable_structures::{BoundedStorable, Cell, DefaultMemoryImpl, StableBTreeMap, Storable};
 use std::{borrow::Cow, cell::RefCell};
 
+mod access;
+mod adherence;
+mod alerts;
+mod archive;
+mod audit;
+mod availability;
+mod batch;
+mod calendar;
+mod certification;
+mod crew;
+mod events;
+mod export;
+mod fleets;
+mod http;
+mod import;
+mod incidents;
+mod jobs;
+mod organizations;
+mod psc;
+mod rest_hours;
+mod revisions;
+mod ship_certificates;
+mod stcw;
+mod subscriptions;
+
+// Types appearing in module endpoint signatures must be in scope for export_candid!
+use access::Role;
+use adherence::{RouteAdherence, ScheduleAdherence};
+use alerts::{AlertRule, AlertRulePayload, InboxItem};
+use archive::{ArchivedVessel, ArchivedVoyage, PurgeReport};
+use audit::{AuditPage, AuditVerification};
+use availability::{
+    CalendarBlock, CalendarBlockPayload, OperationalStatus, StatusChange, VesselAvailability,
+    VoyageOverlap,
+};
+use batch::{BatchResult, VesselOperation, VoyageOperation};
+use certification::{CertifiedVessel, CertifiedVoyage};
+use crew::{CrewAssignment, CrewMember, CrewMemberPayload, CrewOnBoard, SignOnPayload};
+use events::EventPage;
+use export::{ExportChunk, ExportCollection, ExportFormat};
+use fleets::{Fleet, FleetDashboard};
+use http::{HttpRequest, HttpResponse};
+use import::{ImportReport, ImportRequest};
+use incidents::{Incident, IncidentPayload, IncidentStatus, IncidentType};
+use jobs::{Flag, FlagKind, JobStatus};
+use organizations::{
+    require_vessel_access, Organization, OrganizationPayload, VesselAccess, VesselOrganizations,
+};
+use psc::{DeficiencyRecord, PscInspection, PscInspectionPayload, PscRiskAssessment};
+use rest_hours::{RestHourRecord, RestHourViolation};
+use revisions::{VesselRevision, VoyageRevision};
+use ship_certificates::{
+    check_mandatory_certificates, CertificateSchedule, ShipCertificate, ShipCertificatePayload,
+    SurveyKind,
+};
+use stcw::{CrewCertificate, CrewCertificatePayload, ExpiringCrewCertificate};
+use subscriptions::{Delivery, DeliveryStatus, Subscription, SubscriptionPayload};
+
 // Define types for memory and ID cell
 type Memory = VirtualMemory<DefaultMemoryImpl>;
 type IdCell = Cell<u64, Memory>;
@@ -20,11 +78,22 @@ struct Vessel {
     capacity: u32,
     current_location: String,
     last_update: u64,
+    flag: Option<String>,
+    year_built: Option<u16>,
+    // Incremented on every update; callers must pass the version they last read.
+    // None for vessels stored before versioning, which count as version 1
+    version: Option<u64>,
+}
+
+impl Vessel {
+    fn current_version(&self) -> u64 {
+        self.version.unwrap_or(1)
+    }
 }
 
 // Implement Storable trait for Vessel
 impl Storable for Vessel {
-    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
         Cow::Owned(Encode!(self).unwrap())
     }
 
@@ -63,13 +132,28 @@ struct Voyage {
     vessel_id: u64,
     departure_port: String,
     destination_port: String,
+    // Actual times; a departure_time still in the future is the expected departure
     departure_time: u64,
     arrival_time: Option<u64>,
+    // Estimated time of arrival
+    eta: Option<u64>,
+    // Incremented on every update; callers must pass the version they last read.
+    // None for voyages stored before versioning, which count as version 1
+    version: Option<u64>,
+    // Planned times, against which schedule adherence is measured
+    scheduled_departure: Option<u64>,
+    scheduled_arrival: Option<u64>,
+}
+
+impl Voyage {
+    fn current_version(&self) -> u64 {
+        self.version.unwrap_or(1)
+    }
 }
 
 // Implement Storable trait for Voyage
 impl Storable for Voyage {
-    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
         Cow::Owned(Encode!(self).unwrap())
     }
 
@@ -99,6 +183,138 @@ thread_local! {
         RefCell::new(StableBTreeMap::init(
             VOYAGE_MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(1)))
     ));
+
+    // (vessel_id, voyage_id) -> ()
+    static VESSEL_VOYAGE_INDEX: RefCell<StableBTreeMap<(u64, u64), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            VOYAGE_MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(2)))
+    ));
+}
+
+// Define the structure for a partial Vessel update; only the fields provided are changed
+#[derive(candid::CandidType, Serialize, Deserialize, Default)]
+struct VesselPatch {
+    name: Option<String>,
+    captain: Option<String>,
+    capacity: Option<u32>,
+    current_location: Option<String>,
+    flag: Option<String>,
+    year_built: Option<u16>,
+    // Names of nullable fields to reset to null
+    clear: Option<Vec<String>>,
+    // Read-only, rejected when provided
+    id: Option<u64>,
+    last_update: Option<u64>,
+    version: Option<u64>,
+}
+
+impl VesselPatch {
+    fn read_only_fields(&self) -> Vec<&'static str> {
+        [
+            ("id", self.id.is_some()),
+            ("last_update", self.last_update.is_some()),
+            ("version", self.version.is_some()),
+        ]
+        .into_iter()
+        .filter_map(|(field, provided)| provided.then_some(field))
+        .collect()
+    }
+
+    // The fields that can be cleared, and whether the patch sets them
+    fn nullable_fields(&self) -> Vec<(&'static str, bool)> {
+        vec![
+            ("flag", self.flag.is_some()),
+            ("year_built", self.year_built.is_some()),
+        ]
+    }
+}
+
+// Define the structure for a partial Voyage update; only the fields provided are changed
+#[derive(candid::CandidType, Serialize, Deserialize, Default)]
+struct VoyagePatch {
+    departure_port: Option<String>,
+    destination_port: Option<String>,
+    departure_time: Option<u64>,
+    arrival_time: Option<u64>,
+    eta: Option<u64>,
+    scheduled_departure: Option<u64>,
+    scheduled_arrival: Option<u64>,
+    // Names of nullable fields to reset to null
+    clear: Option<Vec<String>>,
+    // Read-only, rejected when provided
+    id: Option<u64>,
+    vessel_id: Option<u64>,
+    version: Option<u64>,
+}
+
+impl VoyagePatch {
+    fn read_only_fields(&self) -> Vec<&'static str> {
+        [
+            ("id", self.id.is_some()),
+            ("vessel_id", self.vessel_id.is_some()),
+            ("version", self.version.is_some()),
+        ]
+        .into_iter()
+        .filter_map(|(field, provided)| provided.then_some(field))
+        .collect()
+    }
+
+    // The fields that can be cleared, and whether the patch sets them
+    fn nullable_fields(&self) -> Vec<(&'static str, bool)> {
+        vec![
+            ("arrival_time", self.arrival_time.is_some()),
+            ("eta", self.eta.is_some()),
+            ("scheduled_departure", self.scheduled_departure.is_some()),
+            ("scheduled_arrival", self.scheduled_arrival.is_some()),
+        ]
+    }
+}
+
+// Helper method to check the `clear` list of a patch: only nullable fields, none of them
+// also set by the patch
+fn check_clear(clear: &Option<Vec<String>>, nullable: &[(&str, bool)]) -> Result<(), Error> {
+    for field in clear.iter().flatten() {
+        match nullable.iter().find(|(name, _)| name == field) {
+            None => {
+                return Err(Error::InvalidInput {
+                    msg: format!(
+                        "{} cannot be cleared; nullable fields are {}",
+                        field,
+                        nullable
+                            .iter()
+                            .map(|(name, _)| *name)
+                            .collect::<Vec<_>>()
+                            .join(", ")
+                    ),
+                })
+            }
+            Some((_, true)) => {
+                return Err(Error::InvalidInput {
+                    msg: format!("{} cannot be both set and cleared", field),
+                })
+            }
+            Some(_) => {}
+        }
+    }
+    Ok(())
+}
+
+// Rebuild the certified data tree and arm the job timer; neither survives an upgrade
+#[ic_cdk::init]
+fn init() {
+    certification::rebuild();
+    jobs::start();
+}
+
+#[ic_cdk::post_upgrade]
+fn post_upgrade() {
+    backfill_vessel_voyage_index();
+    crew::backfill_seafarer_index();
+    revisions::backfill_time_indexes();
+    archive::backfill_time_indexes();
+    subscriptions::backfill_kind_index();
+    certification::rebuild();
+    jobs::start();
 }
 
 // Functions related to Vessel management
@@ -106,6 +322,7 @@ thread_local! {
 // Retrieve a Vessel by ID
 #[ic_cdk::query]
 fn get_vessel(id: u64) -> Result<Vessel, Error> {
+    require_vessel_access(id, VesselAccess::View)?;
     match _get_vessel(&id) {
         Some(vessel) => Ok(vessel),
         None => Err(Error::NotFound {
@@ -114,9 +331,15 @@ fn get_vessel(id: u64) -> Result<Vessel, Error> {
     }
 }
 
-// Add a new Vessel
+// Add a new Vessel, owned by `owner_organization` or else the caller's only organization
 #[ic_cdk::update]
-fn add_vessel(vessel: Vessel) -> Option<Vessel> {
+fn add_vessel(vessel: Vessel, owner_organization: Option<u64>) -> Result<Vessel, Error> {
+    let owner = organizations::resolve_owner(owner_organization)?;
+    Ok(do_add_vessel("add_vessel", vessel, owner))
+}
+
+// Helper method to assign an ID to a new Vessel and store it
+fn do_add_vessel(method: &str, vessel: Vessel, owner: Option<u64>) -> Vessel {
     // Generate a new ID for the Vessel
     let id = VESSEL_ID_COUNTER
         .with(|counter| {
@@ -133,16 +356,24 @@ fn add_vessel(vessel: Vessel) -> Option<Vessel> {
         capacity: vessel.capacity,
         current_location: vessel.current_location,
         last_update: time(),
+        flag: vessel.flag,
+        year_built: vessel.year_built,
+        version: Some(1),
     };
 
     // Insert the Vessel into storage
     do_insert_vessel(&vessel);
-    Some(vessel)
+    organizations::assign_owner(vessel.id, owner);
+    audit::record(method, "vessel", vessel.id, None, Some(&vessel));
+    revisions::track_vessel(vessel.id, None, Some(&vessel));
+    events::vessel_changed(vessel.id, None, Some(&vessel));
+    vessel
 }
 
 // Helper method to insert a Vessel into storage
 fn do_insert_vessel(vessel: &Vessel) {
     VESSEL_STORAGE.with(|service| service.borrow_mut().insert(vessel.id, vessel.clone()));
+    certification::certify_vessel(vessel.id, Some(vessel));
 }
 
 // Functions related to Voyage management
@@ -151,7 +382,10 @@ fn do_insert_vessel(vessel: &Vessel) {
 #[ic_cdk::query]
 fn get_voyage(id: u64) -> Result<Voyage, Error> {
     match _get_voyage(&id) {
-        Some(voyage) => Ok(voyage),
+        Some(voyage) => {
+            require_vessel_access(voyage.vessel_id, VesselAccess::View)?;
+            Ok(voyage)
+        }
         None => Err(Error::NotFound {
             msg: format!("a voyage with id={} not found", id),
         }),
@@ -160,7 +394,89 @@ fn get_voyage(id: u64) -> Result<Voyage, Error> {
 
 // Add a new Voyage
 #[ic_cdk::update]
-fn add_voyage(voyage: Voyage) -> Option<Voyage> {
+fn add_voyage(voyage: Voyage) -> Result<Voyage, Error> {
+    check_new_voyage(&voyage, &[])?;
+    Ok(do_add_voyage("add_voyage", voyage))
+}
+
+// Helper method to validate a new Voyage before it is stored; the stored voyages in `moved`
+// are being changed or deleted by the same request and do not count against it
+fn check_new_voyage(voyage: &Voyage, moved: &[u64]) -> Result<(), Error> {
+    require_vessel_access(voyage.vessel_id, VesselAccess::Voyages)?;
+    let voyage = new_voyage_times(voyage);
+    check_voyage_times(&voyage)?;
+    check_sailing_rules(&voyage)?;
+    check_vessel_free(&voyage, moved)
+}
+
+// Helper method to check that a Voyage has not arrived in the future. Completed voyages are
+// backfilled history; any other voyage sails under current rules: the vessel must hold its
+// mandatory certificates when it departs
+fn check_sailing_rules(voyage: &Voyage) -> Result<(), Error> {
+    let now = time();
+    if voyage.arrival_time.is_some_and(|arrival| arrival > now) {
+        return Err(Error::InvalidInput {
+            msg: "arrival_time cannot be in the future".to_string(),
+        });
+    }
+    if voyage.arrival_time.is_none() {
+        check_mandatory_certificates(voyage.vessel_id, voyage.departure_time.max(now))?;
+    }
+    Ok(())
+}
+
+// Helper method to check that the vessel of a Voyage is free for it: on no other voyage if it
+// has departed and not arrived, and without bookings or calendar blocks over its window
+fn check_vessel_free(voyage: &Voyage, moved: &[u64]) -> Result<(), Error> {
+    if voyage.arrival_time.is_none() && voyage.departure_time <= time() {
+        if let Some(active) = availability::active_voyage(voyage.vessel_id, moved) {
+            return Err(Error::InvalidInput {
+                msg: format!(
+                    "vessel id={} is still on voyage id={}, which has not arrived",
+                    voyage.vessel_id, active.id
+                ),
+            });
+        }
+    }
+    let (start, end) = availability::voyage_window(voyage);
+    availability::check_available(voyage.vessel_id, start, end, moved)
+}
+
+// Helper method to resolve the departure of a new Voyage: a departure_time of 0 stands for
+// the scheduled departure, or for now when the voyage has no schedule
+fn new_voyage_times(voyage: &Voyage) -> Voyage {
+    Voyage {
+        departure_time: match voyage.departure_time {
+            0 => voyage.scheduled_departure.unwrap_or_else(time),
+            departure_time => departure_time,
+        },
+        ..voyage.clone()
+    }
+}
+
+// Helper method to check that the actual and the planned times of a Voyage are in order
+fn check_voyage_times(voyage: &Voyage) -> Result<(), Error> {
+    if voyage
+        .arrival_time
+        .is_some_and(|arrival| arrival < voyage.departure_time)
+    {
+        return Err(Error::InvalidInput {
+            msg: "arrival_time cannot be before departure_time".to_string(),
+        });
+    }
+    if let (Some(departure), Some(arrival)) = (voyage.scheduled_departure, voyage.scheduled_arrival)
+    {
+        if arrival <= departure {
+            return Err(Error::InvalidInput {
+                msg: "scheduled_arrival must be after scheduled_departure".to_string(),
+            });
+        }
+    }
+    Ok(())
+}
+
+// Helper method to assign an ID to a new Voyage and store it
+fn do_add_voyage(method: &str, voyage: Voyage) -> Voyage {
     // Generate a new ID for the Voyage
     let id = VOYAGE_ID_COUNTER
         .with(|counter| {
@@ -170,23 +486,40 @@ fn add_voyage(voyage: Voyage) -> Option<Voyage> {
         .expect("cannot increment voyage id counter");
 
     // Create a new Voyage instance
+    let voyage = new_voyage_times(&voyage);
     let voyage = Voyage {
         id,
         vessel_id: voyage.vessel_id,
         departure_port: voyage.departure_port,
         destination_port: voyage.destination_port,
-        departure_time: time(),
-        arrival_time: None,
+        departure_time: voyage.departure_time,
+        arrival_time: voyage.arrival_time,
+        eta: voyage.eta,
+        version: Some(1),
+        scheduled_departure: voyage.scheduled_departure,
+        scheduled_arrival: voyage.scheduled_arrival,
     };
 
     // Insert the Voyage into storage
     do_insert_voyage(&voyage);
-    Some(voyage)
+    audit::record(method, "voyage", voyage.id, None, Some(&voyage));
+    revisions::track_voyage(voyage.id, None, Some(&voyage));
+    events::voyage_changed(voyage.id, None, Some(&voyage));
+    voyage
 }
 
 // Helper method to insert a Voyage into storage
 fn do_insert_voyage(voyage: &Voyage) {
-    VOYAGE_STORAGE.with(|service| service.borrow_mut().insert(voyage.id, voyage.clone()));
+    let replaced =
+        VOYAGE_STORAGE.with(|service| service.borrow_mut().insert(voyage.id, voyage.clone()));
+    VESSEL_VOYAGE_INDEX.with(|index| {
+        let mut index = index.borrow_mut();
+        if let Some(replaced) = replaced {
+            index.remove(&(replaced.vessel_id, voyage.id));
+        }
+        index.insert((voyage.vessel_id, voyage.id), ());
+    });
+    certification::certify_voyage(voyage.id, Some(voyage));
 }
 
 // Other helper methods and structures remain unchanged.
@@ -194,7 +527,26 @@ fn do_insert_voyage(voyage: &Voyage) {
 // Define an enum for error handling
 #[derive(candid::CandidType, Deserialize, Serialize)]
 enum Error {
-    NotFound { msg: String },
+    NotFound {
+        msg: String,
+    },
+    InvalidInput {
+        msg: String,
+    },
+    Unauthorized {
+        msg: String,
+    },
+    Conflict {
+        msg: String,
+        current: Box<CurrentRecord>,
+    },
+}
+
+// The stored record returned with a Conflict error
+#[derive(candid::CandidType, Deserialize, Serialize)]
+enum CurrentRecord {
+    Vessel(Vessel),
+    Voyage(Voyage),
 }
 
 // Helper methods for vessel and voyage retrieval
@@ -209,21 +561,132 @@ fn _get_voyage(id: &u64) -> Option<Voyage> {
     VOYAGE_STORAGE.with(|service| service.borrow().get(id))
 }
 
+// Collect all voyages of a vessel using the vessel index
+fn vessel_voyages(vessel_id: u64) -> Vec<Voyage> {
+    let ids: Vec<u64> = VESSEL_VOYAGE_INDEX.with(|index| {
+        index
+            .borrow()
+            .range((vessel_id, 0)..=(vessel_id, u64::MAX))
+            .map(|((_, voyage_id), _)| voyage_id)
+            .collect()
+    });
+    ids.iter().filter_map(_get_voyage).collect()
+}
+
+// Index the voyages stored before the vessel index existed
+fn backfill_vessel_voyage_index() {
+    let indexed = VESSEL_VOYAGE_INDEX.with(|index| !index.borrow().is_empty());
+    if indexed {
+        return;
+    }
+    VOYAGE_STORAGE.with(|service| {
+        VESSEL_VOYAGE_INDEX.with(|index| {
+            let mut index = index.borrow_mut();
+            for (id, voyage) in service.borrow().iter() {
+                index.insert((voyage.vessel_id, id), ());
+            }
+        })
+    });
+}
+
+// Page through vessels in ID order, starting at `start`
+fn vessels_from(start: u64, limit: usize) -> Vec<(u64, Vessel)> {
+    VESSEL_STORAGE.with(|service| service.borrow().range(start..).take(limit).collect())
+}
+
+// Page through voyages in ID order, starting at `start`
+fn voyages_from(start: u64, limit: usize) -> Vec<(u64, Voyage)> {
+    VOYAGE_STORAGE.with(|service| service.borrow().range(start..).take(limit).collect())
+}
+
 // Update a Voyage by ID
 #[ic_cdk::update]
-fn update_voyage(id: u64, updated_voyage: Voyage) -> Result<(), Error> {
+fn update_voyage(id: u64, expected_version: u64, patch: VoyagePatch) -> Result<Voyage, Error> {
+    let (before, updated) = patch_voyage(id, expected_version, patch, &[])?;
+    do_update_voyage("update_voyage", &before, &updated);
+    Ok(updated)
+}
+
+// Helper method to validate a Voyage patch; returns the stored and the patched Voyage.
+// As in check_new_voyage, the voyages in `moved` do not count against the patched one
+fn patch_voyage(
+    id: u64,
+    expected_version: u64,
+    patch: VoyagePatch,
+    moved: &[u64],
+) -> Result<(Voyage, Voyage), Error> {
+    let read_only = patch.read_only_fields();
+    if !read_only.is_empty() {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "read-only voyage fields cannot be updated: {}",
+                read_only.join(", ")
+            ),
+        });
+    }
+    check_clear(&patch.clear, &patch.nullable_fields())?;
     match _get_voyage(&id) {
         Some(mut existing_voyage) => {
-            // Update relevant fields
-            existing_voyage.departure_port = updated_voyage.departure_port;
-            existing_voyage.destination_port = updated_voyage.destination_port;
-
-            // Update the last_update timestamp
-            existing_voyage.departure_time = time();
-
-            // Insert the updated Voyage into storage
-            do_insert_voyage(&existing_voyage);
-            Ok(())
+            require_vessel_access(existing_voyage.vessel_id, VesselAccess::Voyages)?;
+            // Reject the update if someone else changed the Voyage since the caller read it
+            if existing_voyage.current_version() != expected_version {
+                return Err(Error::Conflict {
+                    msg: format!(
+                        "a voyage with id={} is at version {}, not {}",
+                        id,
+                        existing_voyage.current_version(),
+                        expected_version
+                    ),
+                    current: Box::new(CurrentRecord::Voyage(existing_voyage)),
+                });
+            }
+            let before = existing_voyage.clone();
+
+            // Update the fields provided
+            if let Some(departure_port) = patch.departure_port {
+                existing_voyage.departure_port = departure_port;
+            }
+            if let Some(destination_port) = patch.destination_port {
+                existing_voyage.destination_port = destination_port;
+            }
+            if let Some(departure_time) = patch.departure_time {
+                existing_voyage.departure_time = departure_time;
+            }
+            if let Some(arrival_time) = patch.arrival_time {
+                existing_voyage.arrival_time = Some(arrival_time);
+            }
+            if let Some(eta) = patch.eta {
+                existing_voyage.eta = Some(eta);
+            }
+            if let Some(scheduled_departure) = patch.scheduled_departure {
+                existing_voyage.scheduled_departure = Some(scheduled_departure);
+            }
+            if let Some(scheduled_arrival) = patch.scheduled_arrival {
+                existing_voyage.scheduled_arrival = Some(scheduled_arrival);
+            }
+            for field in patch.clear.iter().flatten() {
+                match field.as_str() {
+                    "arrival_time" => existing_voyage.arrival_time = None,
+                    "eta" => existing_voyage.eta = None,
+                    "scheduled_departure" => existing_voyage.scheduled_departure = None,
+                    "scheduled_arrival" => existing_voyage.scheduled_arrival = None,
+                    _ => {}
+                }
+            }
+            check_voyage_times(&existing_voyage)?;
+            if existing_voyage.departure_time != before.departure_time
+                || existing_voyage.arrival_time != before.arrival_time
+            {
+                check_sailing_rules(&existing_voyage)?;
+            }
+            // Moving a voyage must not make it overlap another voyage or a calendar block
+            if availability::voyage_window(&existing_voyage) != availability::voyage_window(&before)
+            {
+                let except: Vec<u64> = moved.iter().copied().chain([id]).collect();
+                check_vessel_free(&existing_voyage, &except)?;
+            }
+            existing_voyage.version = Some(existing_voyage.current_version() + 1);
+            Ok((before, existing_voyage))
         }
         None => Err(Error::NotFound {
             msg: format!("a voyage with id={} not found", id),
@@ -231,13 +694,22 @@ fn update_voyage(id: u64, updated_voyage: Voyage) -> Result<(), Error> {
     }
 }
 
+// Helper method to store a patched Voyage
+fn do_update_voyage(method: &str, before: &Voyage, updated: &Voyage) {
+    // Insert the updated Voyage into storage
+    do_insert_voyage(updated);
+    audit::record(method, "voyage", updated.id, Some(before), Some(updated));
+    revisions::track_voyage(updated.id, Some(before), Some(updated));
+    events::voyage_changed(updated.id, Some(before), Some(updated));
+}
+
 // Delete a Voyage by ID
 #[ic_cdk::update]
 fn delete_voyage(id: u64) -> Result<(), Error> {
     // Check if the Voyage exists
-    if let Some(_) = _get_voyage(&id) {
-        // Remove the Voyage from storage
-        VOYAGE_STORAGE.with(|service| service.borrow_mut().remove(&id));
+    if let Some(voyage) = _get_voyage(&id) {
+        require_vessel_access(voyage.vessel_id, VesselAccess::Voyages)?;
+        do_delete_voyage("delete_voyage", voyage);
         Ok(())
     } else {
         // Return an error if the Voyage is not found
@@ -247,23 +719,90 @@ fn delete_voyage(id: u64) -> Result<(), Error> {
     }
 }
 
+// Helper method to move a Voyage from storage into the archive
+fn do_delete_voyage(method: &str, voyage: Voyage) {
+    let id = voyage.id;
+    VOYAGE_STORAGE.with(|service| service.borrow_mut().remove(&id));
+    VESSEL_VOYAGE_INDEX.with(|index| index.borrow_mut().remove(&(voyage.vessel_id, id)));
+    certification::certify_voyage(id, None);
+    audit::record(method, "voyage", id, Some(&voyage), None);
+    revisions::track_voyage(id, Some(&voyage), None);
+    events::voyage_changed(id, Some(&voyage), None);
+    archive::archive_voyage(voyage);
+}
+
 // Update a Vessel by ID
 #[ic_cdk::update]
-fn update_vessel(id: u64, updated_vessel: Vessel) -> Result<(), Error> {
+fn update_vessel(id: u64, expected_version: u64, patch: VesselPatch) -> Result<Vessel, Error> {
+    let (before, updated) = patch_vessel(id, expected_version, patch)?;
+    do_update_vessel("update_vessel", &before, &updated);
+    Ok(updated)
+}
+
+// Helper method to validate a Vessel patch; returns the stored and the patched Vessel
+fn patch_vessel(
+    id: u64,
+    expected_version: u64,
+    patch: VesselPatch,
+) -> Result<(Vessel, Vessel), Error> {
+    let read_only = patch.read_only_fields();
+    if !read_only.is_empty() {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "read-only vessel fields cannot be updated: {}",
+                read_only.join(", ")
+            ),
+        });
+    }
+    check_clear(&patch.clear, &patch.nullable_fields())?;
+    require_vessel_access(id, VesselAccess::Edit)?;
     match _get_vessel(&id) {
         Some(mut existing_vessel) => {
-            // Update relevant fields
-            existing_vessel.name = updated_vessel.name;
-            existing_vessel.captain = updated_vessel.captain;
-            existing_vessel.capacity = updated_vessel.capacity;
-            existing_vessel.current_location = updated_vessel.current_location;
+            // Reject the update if someone else changed the Vessel since the caller read it
+            if existing_vessel.current_version() != expected_version {
+                return Err(Error::Conflict {
+                    msg: format!(
+                        "a vessel with id={} is at version {}, not {}",
+                        id,
+                        existing_vessel.current_version(),
+                        expected_version
+                    ),
+                    current: Box::new(CurrentRecord::Vessel(existing_vessel)),
+                });
+            }
+            let before = existing_vessel.clone();
+
+            // Update the fields provided
+            if let Some(name) = patch.name {
+                existing_vessel.name = name;
+            }
+            if let Some(captain) = patch.captain {
+                existing_vessel.captain = captain;
+            }
+            if let Some(capacity) = patch.capacity {
+                existing_vessel.capacity = capacity;
+            }
+            if let Some(current_location) = patch.current_location {
+                existing_vessel.current_location = current_location;
+            }
+            if let Some(flag) = patch.flag {
+                existing_vessel.flag = Some(flag);
+            }
+            if let Some(year_built) = patch.year_built {
+                existing_vessel.year_built = Some(year_built);
+            }
+            for field in patch.clear.iter().flatten() {
+                match field.as_str() {
+                    "flag" => existing_vessel.flag = None,
+                    "year_built" => existing_vessel.year_built = None,
+                    _ => {}
+                }
+            }
 
             // Update the last_update timestamp
             existing_vessel.last_update = time();
-
-            // Insert the updated Vessel into storage
-            do_insert_vessel(&existing_vessel);
-            Ok(())
+            existing_vessel.version = Some(existing_vessel.current_version() + 1);
+            Ok((before, existing_vessel))
         }
         None => Err(Error::NotFound {
             msg: format!("a vessel with id={} not found", id),
@@ -271,13 +810,22 @@ fn update_vessel(id: u64, updated_vessel: Vessel) -> Result<(), Error> {
     }
 }
 
+// Helper method to store a patched Vessel
+fn do_update_vessel(method: &str, before: &Vessel, updated: &Vessel) {
+    // Insert the updated Vessel into storage
+    do_insert_vessel(updated);
+    audit::record(method, "vessel", updated.id, Some(before), Some(updated));
+    revisions::track_vessel(updated.id, Some(before), Some(updated));
+    events::vessel_changed(updated.id, Some(before), Some(updated));
+}
+
 // Delete a Vessel by ID
 #[ic_cdk::update]
 fn delete_vessel(id: u64) -> Result<(), Error> {
     // Check if the Vessel exists
-    if let Some(_) = _get_vessel(&id) {
-        // Remove the Vessel from storage
-        VESSEL_STORAGE.with(|service| service.borrow_mut().remove(&id));
+    require_vessel_access(id, VesselAccess::Edit)?;
+    if let Some(vessel) = _get_vessel(&id) {
+        do_delete_vessel("delete_vessel", vessel);
         Ok(())
     } else {
         // Return an error if the Vessel is not found
@@ -287,6 +835,71 @@ fn delete_vessel(id: u64) -> Result<(), Error> {
     }
 }
 
+// Helper method to move a Vessel from storage into the archive
+fn do_delete_vessel(method: &str, vessel: Vessel) {
+    let id = vessel.id;
+    VESSEL_STORAGE.with(|service| service.borrow_mut().remove(&id));
+    certification::certify_vessel(id, None);
+    audit::record(method, "vessel", id, Some(&vessel), None);
+    revisions::track_vessel(id, Some(&vessel), None);
+    events::vessel_changed(id, Some(&vessel), None);
+    archive::archive_vessel(vessel);
+}
+
 // Need this to generate candid
 ic_cdk::export_candid!();
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn new_voyage_keeps_a_given_departure_time() {
+        let voyage = Voyage {
+            departure_time: 50,
+            scheduled_departure: Some(40),
+            ..Default::default()
+        };
+        assert_eq!(new_voyage_times(&voyage).departure_time, 50);
+    }
+
+    #[test]
+    fn new_voyage_departs_as_scheduled_by_default() {
+        let voyage = Voyage {
+            scheduled_departure: Some(40),
+            ..Default::default()
+        };
+        assert_eq!(new_voyage_times(&voyage).departure_time, 40);
+    }
+
+    #[test]
+    fn voyage_times_must_be_in_order() {
+        let voyage = Voyage {
+            departure_time: 50,
+            arrival_time: Some(50),
+            scheduled_departure: Some(40),
+            scheduled_arrival: Some(60),
+            ..Default::default()
+        };
+        assert!(check_voyage_times(&voyage).is_ok());
+        assert!(check_voyage_times(&Voyage {
+            arrival_time: Some(49),
+            ..voyage.clone()
+        })
+        .is_err());
+        assert!(check_voyage_times(&Voyage {
+            scheduled_arrival: Some(40),
+            ..voyage
+        })
+        .is_err());
+    }
+
+    #[test]
+    fn only_unset_nullable_fields_can_be_cleared() {
+        let nullable = [("eta", false), ("arrival_time", true)];
+        assert!(check_clear(&None, &nullable).is_ok());
+        assert!(check_clear(&Some(vec!["eta".to_string()]), &nullable).is_ok());
+        assert!(check_clear(&Some(vec!["arrival_time".to_string()]), &nullable).is_err());
+        assert!(check_clear(&Some(vec!["departure_port".to_string()]), &nullable).is_err());
+    }
+}
@@ -0,0 +1,405 @@
+// Organizations (shipowners, managers, charterers) and organization-scoped access to vessels
+use crate::access::{has_role, principal_key, require_role, PrincipalKey, Role};
+use crate::{_get_vessel, audit, Error, IdCell, Memory, VESSEL_MEMORY_MANAGER};
+use candid::{Decode, Encode, Principal};
+use ic_cdk::api::{caller, time};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use std::{borrow::Cow, cell::RefCell};
+
+const MAX_NAME_LEN: usize = 200;
+
+// Define the kinds of organizations
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Debug)]
+pub(crate) enum OrganizationKind {
+    Shipowner,
+    Manager,
+    Charterer,
+}
+
+// Define the structure for an organization
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct Organization {
+    id: u64,
+    name: String,
+    kind: OrganizationKind,
+    created_at: u64,
+}
+
+// Define the structure for creating an organization
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct OrganizationPayload {
+    name: String,
+    kind: OrganizationKind,
+}
+
+// The organizations a vessel belongs to
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct VesselOrganizations {
+    owner: u64,
+    manager: Option<u64>,
+    charterer: Option<u64>,
+}
+
+// What the caller wants to do with a vessel. Members of the owning and managing
+// organizations may do everything; members of the chartering organization may view
+// the vessel and run its voyages. Vessels without organizations are left to admins.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub(crate) enum VesselAccess {
+    View,
+    Voyages,
+    Edit,
+}
+
+// Implement Storable trait for Organization
+impl Storable for Organization {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+// Implement BoundedStorable trait for Organization
+impl BoundedStorable for Organization {
+    const MAX_SIZE: u32 = 1024;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Implement Storable trait for VesselOrganizations
+impl Storable for VesselOrganizations {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+// Implement BoundedStorable trait for VesselOrganizations
+impl BoundedStorable for VesselOrganizations {
+    const MAX_SIZE: u32 = 128;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Define thread-local variables for organization storage
+thread_local! {
+    static ORGANIZATION_ID_COUNTER: RefCell<IdCell> = RefCell::new(
+        IdCell::init(VESSEL_MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(47))), 0)
+            .expect("Cannot create a counter")
+    );
+
+    static ORGANIZATION_STORAGE: RefCell<StableBTreeMap<u64, Organization, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            VESSEL_MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(48)))
+    ));
+
+    // (member, organization_id) -> ()
+    static MEMBERSHIPS: RefCell<StableBTreeMap<(PrincipalKey, u64), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            VESSEL_MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(49)))
+    ));
+
+    // (organization_id, member) -> ()
+    static ORGANIZATION_MEMBERS: RefCell<StableBTreeMap<(u64, PrincipalKey), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            VESSEL_MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(50)))
+    ));
+
+    static VESSEL_ORGANIZATIONS: RefCell<StableBTreeMap<u64, VesselOrganizations, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            VESSEL_MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(51)))
+    ));
+}
+
+// Create an organization (admins only)
+#[ic_cdk::update]
+fn create_organization(payload: OrganizationPayload) -> Result<Organization, Error> {
+    require_role(Role::Admin)?;
+    let name = payload.name.trim().to_string();
+    if name.is_empty() || name.len() > MAX_NAME_LEN {
+        return Err(Error::InvalidInput {
+            msg: format!("name must be between 1 and {} characters", MAX_NAME_LEN),
+        });
+    }
+
+    let id = ORGANIZATION_ID_COUNTER
+        .with(|counter| {
+            let current_value = *counter.borrow().get();
+            counter.borrow_mut().set(current_value + 1)
+        })
+        .expect("cannot increment organization id counter");
+
+    let organization = Organization {
+        id,
+        name,
+        kind: payload.kind,
+        created_at: time(),
+    };
+    ORGANIZATION_STORAGE.with(|service| service.borrow_mut().insert(id, organization.clone()));
+    audit::record(
+        "create_organization",
+        "organization",
+        id,
+        None,
+        Some(&organization),
+    );
+    Ok(organization)
+}
+
+// Add a principal to an organization (admins only)
+#[ic_cdk::update]
+fn add_organization_member(organization_id: u64, member: Principal) -> Result<(), Error> {
+    require_role(Role::Admin)?;
+    get_organization(organization_id)?;
+    let key = principal_key(&member);
+    MEMBERSHIPS.with(|index| index.borrow_mut().insert((key, organization_id), ()));
+    ORGANIZATION_MEMBERS.with(|index| index.borrow_mut().insert((organization_id, key), ()));
+    audit::record(
+        "add_organization_member",
+        "organization",
+        organization_id,
+        None,
+        Some(&member),
+    );
+    Ok(())
+}
+
+// Remove a principal from an organization (admins only)
+#[ic_cdk::update]
+fn remove_organization_member(organization_id: u64, member: Principal) -> Result<(), Error> {
+    require_role(Role::Admin)?;
+    let key = principal_key(&member);
+    if MEMBERSHIPS
+        .with(|index| index.borrow_mut().remove(&(key, organization_id)))
+        .is_none()
+    {
+        return Err(Error::NotFound {
+            msg: format!(
+                "{} is not a member of organization id={}",
+                member, organization_id
+            ),
+        });
+    }
+    ORGANIZATION_MEMBERS.with(|index| index.borrow_mut().remove(&(organization_id, key)));
+    audit::record(
+        "remove_organization_member",
+        "organization",
+        organization_id,
+        Some(&member),
+        None,
+    );
+    Ok(())
+}
+
+// List the caller's organizations; admins see every organization
+#[ic_cdk::query]
+fn get_organizations() -> Vec<Organization> {
+    let caller = caller();
+    if has_role(&caller, Role::Admin) {
+        return ORGANIZATION_STORAGE
+            .with(|service| service.borrow().iter().map(|(_, org)| org).collect());
+    }
+    memberships(&caller)
+        .into_iter()
+        .filter_map(|id| ORGANIZATION_STORAGE.with(|service| service.borrow().get(&id)))
+        .collect()
+}
+
+// List the members of an organization (its members and admins)
+#[ic_cdk::query]
+fn get_organization_members(organization_id: u64) -> Result<Vec<Principal>, Error> {
+    get_organization(organization_id)?;
+    let caller = caller();
+    if !has_role(&caller, Role::Admin) && !memberships(&caller).contains(&organization_id) {
+        return Err(Error::Unauthorized {
+            msg: format!(
+                "caller {} is not a member of organization id={}",
+                caller, organization_id
+            ),
+        });
+    }
+    Ok(ORGANIZATION_MEMBERS.with(|index| {
+        index
+            .borrow()
+            .range((organization_id, PrincipalKey::default())..)
+            .take_while(|((id, _), _)| *id == organization_id)
+            .map(|((_, key), _)| Principal::from_slice(key.as_slice()))
+            .collect()
+    }))
+}
+
+// Set the organizations of a vessel (admins, or members of the current owner)
+#[ic_cdk::update]
+fn set_vessel_organizations(
+    vessel_id: u64,
+    organizations: VesselOrganizations,
+) -> Result<VesselOrganizations, Error> {
+    if _get_vessel(&vessel_id).is_none() {
+        return Err(Error::NotFound {
+            msg: format!("a vessel with id={} not found", vessel_id),
+        });
+    }
+    let before = VESSEL_ORGANIZATIONS.with(|service| service.borrow().get(&vessel_id));
+    let caller = caller();
+    let owns = before
+        .as_ref()
+        .is_some_and(|orgs| memberships(&caller).contains(&orgs.owner));
+    if !owns && !has_role(&caller, Role::Admin) {
+        return Err(Error::Unauthorized {
+            msg: format!(
+                "caller {} may not change the organizations of vessel id={}",
+                caller, vessel_id
+            ),
+        });
+    }
+    let referenced = std::iter::once(organizations.owner)
+        .chain(organizations.manager)
+        .chain(organizations.charterer);
+    for id in referenced {
+        get_organization(id)?;
+    }
+
+    VESSEL_ORGANIZATIONS.with(|service| {
+        service
+            .borrow_mut()
+            .insert(vessel_id, organizations.clone())
+    });
+    audit::record(
+        "set_vessel_organizations",
+        "vessel",
+        vessel_id,
+        before.as_ref(),
+        Some(&organizations),
+    );
+    Ok(organizations)
+}
+
+// Retrieve the organizations of a vessel; None when it has not been assigned any
+#[ic_cdk::query]
+fn get_vessel_organizations(vessel_id: u64) -> Result<Option<VesselOrganizations>, Error> {
+    require_vessel_access(vessel_id, VesselAccess::View)?;
+    Ok(VESSEL_ORGANIZATIONS.with(|service| service.borrow().get(&vessel_id)))
+}
+
+// Whether the caller may access a vessel in the given way
+pub(crate) fn can_access_vessel(vessel_id: u64, access: VesselAccess) -> bool {
+    can_principal_access_vessel(&caller(), vessel_id, access)
+}
+
+// Whether a principal may access a vessel in the given way
+pub(crate) fn can_principal_access_vessel(
+    principal: &Principal,
+    vessel_id: u64,
+    access: VesselAccess,
+) -> bool {
+    if has_role(principal, Role::Admin) {
+        return true;
+    }
+    let Some(orgs) = VESSEL_ORGANIZATIONS.with(|service| service.borrow().get(&vessel_id)) else {
+        return false;
+    };
+    let member_of = memberships(principal);
+    let is_member = |id: Option<u64>| id.is_some_and(|id| member_of.contains(&id));
+    is_member(Some(orgs.owner))
+        || is_member(orgs.manager)
+        || (access != VesselAccess::Edit && is_member(orgs.charterer))
+}
+
+// Fail unless the caller may access a vessel in the given way
+pub(crate) fn require_vessel_access(vessel_id: u64, access: VesselAccess) -> Result<(), Error> {
+    if can_access_vessel(vessel_id, access) {
+        Ok(())
+    } else {
+        Err(Error::Unauthorized {
+            msg: format!(
+                "caller {} has no {:?} access to vessel id={}",
+                caller(),
+                access,
+                vessel_id
+            ),
+        })
+    }
+}
+
+// The owning organization of a new vessel or fleet: the one requested, which the caller must
+// be a member of, else the caller's only organization. Only admins may create unowned ones.
+pub(crate) fn resolve_owner(requested: Option<u64>) -> Result<Option<u64>, Error> {
+    let caller = caller();
+    let member_of = memberships(&caller);
+    let is_admin = has_role(&caller, Role::Admin);
+    match requested {
+        Some(id) => {
+            get_organization(id)?;
+            if !is_admin && !member_of.contains(&id) {
+                return Err(Error::Unauthorized {
+                    msg: format!(
+                        "caller {} is not a member of organization id={}",
+                        caller, id
+                    ),
+                });
+            }
+            Ok(Some(id))
+        }
+        None => match member_of[..] {
+            [owner] => Ok(Some(owner)),
+            _ if is_admin => Ok(None),
+            [] => Err(Error::Unauthorized {
+                msg: format!("caller {} is not a member of any organization", caller),
+            }),
+            _ => Err(Error::InvalidInput {
+                msg: format!(
+                    "caller {} is a member of several organizations, owner_organization is required",
+                    caller
+                ),
+            }),
+        },
+    }
+}
+
+// Whether the caller belongs to an organization; admins count as members of every
+// organization, and are the only ones with access to records without one
+pub(crate) fn is_member(organization: Option<u64>) -> bool {
+    let caller = caller();
+    has_role(&caller, Role::Admin)
+        || organization.is_some_and(|id| memberships(&caller).contains(&id))
+}
+
+// Record the owning organization of a new vessel
+pub(crate) fn assign_owner(vessel_id: u64, owner: Option<u64>) {
+    if let Some(owner) = owner {
+        let organizations = VesselOrganizations {
+            owner,
+            manager: None,
+            charterer: None,
+        };
+        VESSEL_ORGANIZATIONS.with(|service| service.borrow_mut().insert(vessel_id, organizations));
+    }
+}
+
+// Drop the organizations of a vessel that is gone for good
+pub(crate) fn forget_vessel(vessel_id: u64) {
+    VESSEL_ORGANIZATIONS.with(|service| service.borrow_mut().remove(&vessel_id));
+}
+
+fn memberships(principal: &Principal) -> Vec<u64> {
+    let key = principal_key(principal);
+    MEMBERSHIPS.with(|index| {
+        index
+            .borrow()
+            .range((key, 0)..=(key, u64::MAX))
+            .map(|((_, id), _)| id)
+            .collect()
+    })
+}
+
+fn get_organization(id: u64) -> Result<Organization, Error> {
+    ORGANIZATION_STORAGE
+        .with(|service| service.borrow().get(&id))
+        .ok_or(Error::NotFound {
+            msg: format!("an organization with id={} not found", id),
+        })
+}
@@ -0,0 +1,453 @@
+// Port state control inspections, detention history and targeting risk
+use crate::alerts::{self, AlertTrigger};
+use crate::audit;
+use crate::calendar::{add_months, year_of};
+use crate::organizations::{can_access_vessel, require_vessel_access, VesselAccess};
+use crate::{_get_vessel, Error, IdCell, Memory, Vessel, VESSEL_MEMORY_MANAGER, VESSEL_STORAGE};
+use candid::{Decode, Encode};
+use ic_cdk::api::time;
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use std::cmp::Reverse;
+use std::collections::BTreeMap;
+use std::{borrow::Cow, cell::RefCell};
+
+// Sized so that a fully populated inspection stays within PscInspection::MAX_SIZE:
+// 50 * (16 + 200 + candid overhead) + 200 is about 11 KiB of the 16 KiB
+const MAX_DEFICIENCIES: usize = 50;
+const MAX_CODE_LEN: usize = 16;
+const MAX_TEXT_LEN: usize = 200;
+
+// Inspection history considered for the risk score
+const HISTORY_MONTHS: i64 = 36;
+// Flags need this many inspections before their detention rate is trusted
+const MIN_FLAG_INSPECTIONS: usize = 3;
+
+// Define the port state control regimes
+#[derive(
+    candid::CandidType, Clone, Copy, Serialize, Deserialize, Default, PartialEq, Eq, Debug,
+)]
+pub(crate) enum Mou {
+    #[default]
+    Paris,
+    Tokyo,
+    IndianOcean,
+    Mediterranean,
+    BlackSea,
+    Abuja,
+    Caribbean,
+    VinaDelMar,
+    Riyadh,
+    UsCoastGuard,
+}
+
+// A deficiency recorded during an inspection
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub(crate) struct Deficiency {
+    // MOU deficiency code, e.g. "07106"
+    code: String,
+    description: String,
+    ground_for_detention: bool,
+}
+
+// Define the structure for a port state control inspection
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub(crate) struct PscInspection {
+    id: u64,
+    vessel_id: u64,
+    date: u64,
+    // UN/LOCODE of the inspecting port
+    port: String,
+    mou: Mou,
+    deficiencies: Vec<Deficiency>,
+    detained: bool,
+}
+
+// Payload used to record an inspection
+#[derive(candid::CandidType, Serialize, Deserialize, Default)]
+pub(crate) struct PscInspectionPayload {
+    vessel_id: u64,
+    date: u64,
+    port: String,
+    mou: Mou,
+    deficiencies: Vec<Deficiency>,
+    detained: bool,
+}
+
+// A deficiency together with the inspection it was found in
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct DeficiencyRecord {
+    inspection_id: u64,
+    date: u64,
+    port: String,
+    mou: Mou,
+    deficiency: Deficiency,
+}
+
+// Targeting priority derived from the risk score
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Debug)]
+pub(crate) enum RiskLevel {
+    Low,
+    Standard,
+    High,
+}
+
+// One contribution to a vessel's risk score
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct RiskFactor {
+    name: String,
+    points: u32,
+}
+
+// The targeting risk of a vessel and how it was derived
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct PscRiskAssessment {
+    vessel_id: u64,
+    vessel_name: String,
+    score: u32,
+    level: RiskLevel,
+    factors: Vec<RiskFactor>,
+}
+
+// Implement Storable trait for PscInspection
+impl Storable for PscInspection {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+// Implement BoundedStorable trait for PscInspection
+impl BoundedStorable for PscInspection {
+    const MAX_SIZE: u32 = 16 * 1024;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Define thread-local variables for inspection storage
+thread_local! {
+    static PSC_INSPECTION_ID_COUNTER: RefCell<IdCell> = RefCell::new(
+        IdCell::init(VESSEL_MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(16))), 0)
+            .expect("Cannot create a counter")
+    );
+
+    static PSC_INSPECTION_STORAGE: RefCell<StableBTreeMap<u64, PscInspection, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            VESSEL_MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(17)))
+    ));
+
+    // (vessel_id, inspection_id) -> ()
+    static VESSEL_INSPECTION_INDEX: RefCell<StableBTreeMap<(u64, u64), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            VESSEL_MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(18)))
+    ));
+}
+
+// Record a port state control inspection of a vessel
+#[ic_cdk::update]
+fn add_psc_inspection(payload: PscInspectionPayload) -> Result<PscInspection, Error> {
+    require_vessel_access(payload.vessel_id, VesselAccess::Edit)?;
+    if _get_vessel(&payload.vessel_id).is_none() {
+        return Err(Error::NotFound {
+            msg: format!("a vessel with id={} not found", payload.vessel_id),
+        });
+    }
+    if payload.port.trim().is_empty() {
+        return Err(Error::InvalidInput {
+            msg: "port must not be empty".to_string(),
+        });
+    }
+    if payload.deficiencies.len() > MAX_DEFICIENCIES {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "at most {} deficiencies can be recorded per inspection",
+                MAX_DEFICIENCIES
+            ),
+        });
+    }
+    let too_long = payload.port.len() > MAX_TEXT_LEN
+        || payload
+            .deficiencies
+            .iter()
+            .any(|d| d.code.len() > MAX_CODE_LEN || d.description.len() > MAX_TEXT_LEN);
+    if too_long {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "deficiency codes must not exceed {} bytes and other text fields {} bytes",
+                MAX_CODE_LEN, MAX_TEXT_LEN
+            ),
+        });
+    }
+    if payload.deficiencies.iter().any(|d| d.ground_for_detention) && !payload.detained {
+        return Err(Error::InvalidInput {
+            msg: "an inspection with detainable deficiencies must be flagged as a detention"
+                .to_string(),
+        });
+    }
+
+    // Generate a new ID for the PscInspection
+    let id = PSC_INSPECTION_ID_COUNTER
+        .with(|counter| {
+            let current_value = *counter.borrow().get();
+            counter.borrow_mut().set(current_value + 1)
+        })
+        .expect("cannot increment inspection id counter");
+
+    let inspection = PscInspection {
+        id,
+        vessel_id: payload.vessel_id,
+        date: payload.date,
+        port: payload.port,
+        mou: payload.mou,
+        deficiencies: payload.deficiencies,
+        detained: payload.detained,
+    };
+    PSC_INSPECTION_STORAGE.with(|service| {
+        service
+            .borrow_mut()
+            .insert(inspection.id, inspection.clone())
+    });
+    VESSEL_INSPECTION_INDEX.with(|index| {
+        index
+            .borrow_mut()
+            .insert((inspection.vessel_id, inspection.id), ())
+    });
+    audit::record(
+        "add_psc_inspection",
+        "psc_inspection",
+        id,
+        None,
+        Some(&inspection),
+    );
+    if inspection.detained {
+        alerts::raise(
+            AlertTrigger::PscDetention,
+            Some(inspection.vessel_id),
+            id,
+            &format!(
+                "vessel id={} was detained at {}",
+                inspection.vessel_id, inspection.port
+            ),
+        );
+    }
+    Ok(inspection)
+}
+
+// List the inspections of a vessel, most recent first
+#[ic_cdk::query]
+fn get_psc_inspections(vessel_id: u64) -> Result<Vec<PscInspection>, Error> {
+    require_vessel_access(vessel_id, VesselAccess::View)?;
+    if _get_vessel(&vessel_id).is_none() {
+        return Err(Error::NotFound {
+            msg: format!("a vessel with id={} not found", vessel_id),
+        });
+    }
+    Ok(vessel_inspections(vessel_id))
+}
+
+// List every deficiency found on a vessel, most recent first
+#[ic_cdk::query]
+fn get_deficiency_history(vessel_id: u64) -> Result<Vec<DeficiencyRecord>, Error> {
+    require_vessel_access(vessel_id, VesselAccess::View)?;
+    if _get_vessel(&vessel_id).is_none() {
+        return Err(Error::NotFound {
+            msg: format!("a vessel with id={} not found", vessel_id),
+        });
+    }
+    Ok(vessel_inspections(vessel_id)
+        .into_iter()
+        .flat_map(|inspection| {
+            let PscInspection {
+                id,
+                date,
+                port,
+                mou,
+                deficiencies,
+                ..
+            } = inspection;
+            deficiencies
+                .into_iter()
+                .map(move |deficiency| DeficiencyRecord {
+                    inspection_id: id,
+                    date,
+                    port: port.clone(),
+                    mou,
+                    deficiency,
+                })
+        })
+        .collect())
+}
+
+// Assess the port state control targeting risk of a vessel
+#[ic_cdk::query]
+fn get_psc_risk(vessel_id: u64) -> Result<PscRiskAssessment, Error> {
+    require_vessel_access(vessel_id, VesselAccess::View)?;
+    match _get_vessel(&vessel_id) {
+        Some(vessel) => Ok(assess(&vessel, &flag_detention_rates(), time())),
+        None => Err(Error::NotFound {
+            msg: format!("a vessel with id={} not found", vessel_id),
+        }),
+    }
+}
+
+// Rank the fleet by targeting risk, highest first
+#[ic_cdk::query]
+fn get_psc_targeting_list(limit: u32) -> Vec<PscRiskAssessment> {
+    let now = time();
+    let flag_rates = flag_detention_rates();
+    let mut assessments: Vec<PscRiskAssessment> = VESSEL_STORAGE.with(|service| {
+        service
+            .borrow()
+            .iter()
+            .filter(|(id, _)| can_access_vessel(*id, VesselAccess::View))
+            .map(|(_, vessel)| assess(&vessel, &flag_rates, now))
+            .collect()
+    });
+    assessments.sort_by(|a, b| b.score.cmp(&a.score).then(a.vessel_id.cmp(&b.vessel_id)));
+    assessments.truncate(limit as usize);
+    assessments
+}
+
+// Score a vessel from its inspection history, age and flag performance
+fn assess(
+    vessel: &Vessel,
+    flag_rates: &BTreeMap<String, (usize, usize)>,
+    now: u64,
+) -> PscRiskAssessment {
+    let history_start = add_months(now, -HISTORY_MONTHS);
+    let inspections = vessel_inspections(vessel.id);
+    let recent: Vec<&PscInspection> = inspections
+        .iter()
+        .filter(|i| i.date >= history_start)
+        .collect();
+    let mut factors = Vec::new();
+
+    // Detentions and deficiencies within the history period
+    let detentions = recent.iter().filter(|i| i.detained).count() as u32;
+    if detentions > 0 {
+        factors.push(RiskFactor {
+            name: format!(
+                "{} detention(s) in the last {} months",
+                detentions, HISTORY_MONTHS
+            ),
+            points: (detentions * 15).min(45),
+        });
+    }
+    let deficiencies: u32 = recent.iter().map(|i| i.deficiencies.len() as u32).sum();
+    if deficiencies > 0 {
+        factors.push(RiskFactor {
+            name: format!(
+                "{} deficiencies in the last {} months",
+                deficiencies, HISTORY_MONTHS
+            ),
+            points: deficiencies.min(20),
+        });
+    }
+
+    // Time since the last inspection: ships not seen for a while come up for inspection
+    match inspections.first() {
+        None => factors.push(RiskFactor {
+            name: "never inspected".to_string(),
+            points: 15,
+        }),
+        Some(last) if last.date < add_months(now, -12) => factors.push(RiskFactor {
+            name: "not inspected in the last 12 months".to_string(),
+            points: 10,
+        }),
+        Some(_) => {}
+    }
+
+    // Age of the ship
+    if let Some(year_built) = vessel.year_built {
+        let age = (year_of(now) - year_built as i64).max(0);
+        let points = match age {
+            25.. => 15,
+            15..=24 => 10,
+            12..=14 => 5,
+            _ => 0,
+        };
+        if points > 0 {
+            factors.push(RiskFactor {
+                name: format!("ship age {} years", age),
+                points,
+            });
+        }
+    }
+
+    // Flag performance, from the detention rate of all inspections under the same flag
+    let flag_rate = vessel
+        .flag
+        .as_ref()
+        .and_then(|flag| flag_rates.get(flag))
+        .filter(|(inspected, _)| *inspected >= MIN_FLAG_INSPECTIONS);
+    match flag_rate {
+        Some(&(inspected, detained)) => {
+            let percent = detained * 100 / inspected;
+            let points = match percent {
+                10.. => 15,
+                5..=9 => 7,
+                _ => 0,
+            };
+            if points > 0 {
+                factors.push(RiskFactor {
+                    name: format!("flag detention rate {}%", percent),
+                    points,
+                });
+            }
+        }
+        None => factors.push(RiskFactor {
+            name: "flag performance unknown".to_string(),
+            points: 5,
+        }),
+    }
+
+    let score = factors.iter().map(|f| f.points).sum();
+    let level = match score {
+        0..=14 => RiskLevel::Low,
+        15..=39 => RiskLevel::Standard,
+        _ => RiskLevel::High,
+    };
+    PscRiskAssessment {
+        vessel_id: vessel.id,
+        vessel_name: vessel.name.clone(),
+        score,
+        level,
+        factors,
+    }
+}
+
+// (inspections, detentions) per flag across all stored inspections
+fn flag_detention_rates() -> BTreeMap<String, (usize, usize)> {
+    let mut rates: BTreeMap<String, (usize, usize)> = BTreeMap::new();
+    PSC_INSPECTION_STORAGE.with(|service| {
+        for (_, inspection) in service.borrow().iter() {
+            if let Some(flag) = _get_vessel(&inspection.vessel_id).and_then(|v| v.flag) {
+                let entry = rates.entry(flag).or_default();
+                entry.0 += 1;
+                if inspection.detained {
+                    entry.1 += 1;
+                }
+            }
+        }
+    });
+    rates
+}
+
+// Collect all inspections of a vessel using the vessel index, most recent first
+fn vessel_inspections(vessel_id: u64) -> Vec<PscInspection> {
+    let ids: Vec<u64> = VESSEL_INSPECTION_INDEX.with(|index| {
+        index
+            .borrow()
+            .range((vessel_id, 0)..=(vessel_id, u64::MAX))
+            .map(|((_, inspection_id), _)| inspection_id)
+            .collect()
+    });
+    let mut inspections: Vec<PscInspection> = PSC_INSPECTION_STORAGE.with(|service| {
+        let service = service.borrow();
+        ids.iter().filter_map(|id| service.get(id)).collect()
+    });
+    inspections.sort_by_key(|i| Reverse(i.date));
+    inspections
+}
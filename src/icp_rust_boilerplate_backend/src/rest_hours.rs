@@ -0,0 +1,455 @@
+// MLC 2006 rest-hour recording and violation detection
+use crate::alerts::{self, AlertTrigger};
+use crate::audit;
+use crate::calendar::NANOS_PER_DAY;
+use crate::crew::{_get_crew_member, vessel_assignments};
+use crate::organizations::{can_access_vessel, require_vessel_access, VesselAccess};
+use crate::{_get_vessel, Error, Memory, VESSEL_MEMORY_MANAGER};
+use candid::{Decode, Encode};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use std::collections::{BTreeMap, BTreeSet};
+use std::{borrow::Cow, cell::RefCell};
+
+// ((vessel_id, day), crew_member_id) -> ()
+type VesselRestHourIndex = StableBTreeMap<((u64, u64), u64), (), Memory>;
+
+const MINUTES_PER_DAY: u64 = 24 * 60;
+const MAX_REST_PERIODS_PER_DAY: usize = 24;
+
+// MLC 2006 Standard A2.3 limits
+const MIN_REST_IN_24_HOURS: u64 = 10 * 60;
+const MIN_REST_IN_7_DAYS: u64 = 77 * 60;
+const MAX_REST_PERIODS: usize = 2;
+const MIN_LONGEST_REST_PERIOD: u64 = 6 * 60;
+
+// A rest period within a day, in minutes after midnight (end is exclusive)
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, Default)]
+pub(crate) struct RestPeriod {
+    start_minute: u16,
+    end_minute: u16,
+}
+
+// Define the structure for the rest hours a crew member logged for one day
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub(crate) struct RestHourRecord {
+    crew_member_id: u64,
+    vessel_id: u64,
+    // Days since the Unix epoch (UTC)
+    day: u64,
+    rest_periods: Vec<RestPeriod>,
+}
+
+// Define the kinds of MLC rest-hour violations
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Debug)]
+pub(crate) enum RestHourViolationKind {
+    InsufficientRestIn24Hours { rest_minutes: u64 },
+    InsufficientRestIn7Days { rest_minutes: u64 },
+    TooManyRestPeriods { count: u32 },
+    NoRestPeriodOfSixHours,
+}
+
+// A violation found for a crew member on a given day
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct RestHourViolation {
+    crew_member_id: u64,
+    day: u64,
+    kind: RestHourViolationKind,
+}
+
+// Implement Storable trait for RestHourRecord
+impl Storable for RestHourRecord {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+// Implement BoundedStorable trait for RestHourRecord
+impl BoundedStorable for RestHourRecord {
+    const MAX_SIZE: u32 = 1024;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Define thread-local variables for rest-hour storage
+thread_local! {
+    // (crew_member_id, day) -> RestHourRecord
+    static REST_HOUR_STORAGE: RefCell<StableBTreeMap<(u64, u64), RestHourRecord, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            VESSEL_MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(11)))
+    ));
+
+    static VESSEL_REST_HOUR_INDEX: RefCell<VesselRestHourIndex> =
+        RefCell::new(StableBTreeMap::init(
+            VESSEL_MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(12)))
+    ));
+}
+
+// Record (or correct) the rest periods of a crew member for one day
+#[ic_cdk::update]
+fn record_rest_hours(record: RestHourRecord) -> Result<RestHourRecord, Error> {
+    if _get_crew_member(&record.crew_member_id).is_none() {
+        return Err(Error::NotFound {
+            msg: format!("a crew member with id={} not found", record.crew_member_id),
+        });
+    }
+    require_vessel_access(record.vessel_id, VesselAccess::Edit)?;
+    if _get_vessel(&record.vessel_id).is_none() {
+        return Err(Error::NotFound {
+            msg: format!("a vessel with id={} not found", record.vessel_id),
+        });
+    }
+    // Rest is logged for the vessel the crew member served on that day
+    let day_start = record.day.saturating_mul(NANOS_PER_DAY);
+    let day_end = day_start.saturating_add(NANOS_PER_DAY);
+    let on_board = vessel_assignments(record.vessel_id).iter().any(|a| {
+        a.crew_member_id == record.crew_member_id
+            && a.sign_on_date < day_end
+            && a.sign_off_date.is_none_or(|off| off > day_start)
+    });
+    if !on_board {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "crew member id={} was not signed on vessel id={} on day {}",
+                record.crew_member_id, record.vessel_id, record.day
+            ),
+        });
+    }
+    validate_rest_periods(&record.rest_periods)?;
+
+    let mut record = record;
+    record.rest_periods.sort_by_key(|p| p.start_minute);
+
+    let key = (record.crew_member_id, record.day);
+    let previous =
+        REST_HOUR_STORAGE.with(|service| service.borrow_mut().insert(key, record.clone()));
+    VESSEL_REST_HOUR_INDEX.with(|index| {
+        let mut index = index.borrow_mut();
+        if let Some(previous) = &previous {
+            index.remove(&((previous.vessel_id, previous.day), previous.crew_member_id));
+        }
+        index.insert(((record.vessel_id, record.day), record.crew_member_id), ());
+    });
+    audit::record(
+        "record_rest_hours",
+        "rest_hours",
+        format!("{}/{}", record.crew_member_id, record.day),
+        previous.as_ref(),
+        Some(&record),
+    );
+
+    // Alert on violations of the day just logged
+    let records = crew_records(
+        record.crew_member_id,
+        record.day.saturating_sub(6),
+        record.day + 1,
+    );
+    for (_, kind) in evaluate(&records)
+        .into_iter()
+        .filter(|(day, _)| *day == record.day)
+    {
+        alerts::raise(
+            AlertTrigger::RestHourViolation,
+            Some(record.vessel_id),
+            record.crew_member_id,
+            &format!(
+                "crew member id={} on day {}: {:?}",
+                record.crew_member_id, record.day, kind
+            ),
+        );
+    }
+    Ok(record)
+}
+
+// Retrieve the rest-hour records of a crew member between two days (inclusive),
+// limited to those logged on vessels the caller may view
+#[ic_cdk::query]
+fn get_rest_hours(
+    crew_member_id: u64,
+    from_day: u64,
+    to_day: u64,
+) -> Result<Vec<RestHourRecord>, Error> {
+    if _get_crew_member(&crew_member_id).is_none() {
+        return Err(Error::NotFound {
+            msg: format!("a crew member with id={} not found", crew_member_id),
+        });
+    }
+    let mut visible: BTreeMap<u64, bool> = BTreeMap::new();
+    Ok(crew_records(crew_member_id, from_day, to_day)
+        .into_iter()
+        .filter(|record| {
+            *visible
+                .entry(record.vessel_id)
+                .or_insert_with(|| can_access_vessel(record.vessel_id, VesselAccess::View))
+        })
+        .collect())
+}
+
+// Report MLC rest-hour violations of everyone who logged hours on a vessel between two days (inclusive)
+#[ic_cdk::query]
+fn get_rest_hour_violations(
+    vessel_id: u64,
+    from_day: u64,
+    to_day: u64,
+) -> Result<Vec<RestHourViolation>, Error> {
+    require_vessel_access(vessel_id, VesselAccess::View)?;
+    if _get_vessel(&vessel_id).is_none() {
+        return Err(Error::NotFound {
+            msg: format!("a vessel with id={} not found", vessel_id),
+        });
+    }
+    if from_day > to_day {
+        return Err(Error::InvalidInput {
+            msg: "from_day must not be after to_day".to_string(),
+        });
+    }
+
+    // Days each crew member logged on this vessel within the range
+    let mut logged: BTreeMap<u64, BTreeSet<u64>> = BTreeMap::new();
+    VESSEL_REST_HOUR_INDEX.with(|index| {
+        for (((_, day), crew_member_id), _) in index
+            .borrow()
+            .range(((vessel_id, from_day), 0)..=((vessel_id, to_day), u64::MAX))
+        {
+            logged.entry(crew_member_id).or_default().insert(day);
+        }
+    });
+
+    let mut violations = Vec::new();
+    for (crew_member_id, days) in logged {
+        // The 7-day and cross-midnight checks look at the surrounding days as well
+        let records = crew_records(crew_member_id, from_day.saturating_sub(6), to_day + 1);
+        violations.extend(
+            evaluate(&records)
+                .into_iter()
+                .filter(|(day, _)| days.contains(day))
+                .map(|(day, kind)| RestHourViolation {
+                    crew_member_id,
+                    day,
+                    kind,
+                }),
+        );
+    }
+    Ok(violations)
+}
+
+// Reject periods that fall outside the day, are empty or overlap each other
+fn validate_rest_periods(periods: &[RestPeriod]) -> Result<(), Error> {
+    if periods.len() > MAX_REST_PERIODS_PER_DAY {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "at most {} rest periods can be logged per day",
+                MAX_REST_PERIODS_PER_DAY
+            ),
+        });
+    }
+    let mut sorted = periods.to_vec();
+    sorted.sort_by_key(|p| p.start_minute);
+    let mut previous_end = 0;
+    for period in &sorted {
+        if period.start_minute >= period.end_minute || period.end_minute as u64 > MINUTES_PER_DAY {
+            return Err(Error::InvalidInput {
+                msg: format!(
+                    "invalid rest period {}..{}: minutes must satisfy start < end <= {}",
+                    period.start_minute, period.end_minute, MINUTES_PER_DAY
+                ),
+            });
+        }
+        if period.start_minute < previous_end {
+            return Err(Error::InvalidInput {
+                msg: "rest periods must not overlap".to_string(),
+            });
+        }
+        previous_end = period.end_minute;
+    }
+    Ok(())
+}
+
+// Retrieve the records of a crew member between two days (inclusive)
+fn crew_records(crew_member_id: u64, from_day: u64, to_day: u64) -> Vec<RestHourRecord> {
+    REST_HOUR_STORAGE.with(|service| {
+        service
+            .borrow()
+            .range((crew_member_id, from_day)..=(crew_member_id, to_day))
+            .map(|(_, record)| record)
+            .collect()
+    })
+}
+
+// Evaluate a crew member's records against the MLC limits, one entry per day and violation kind
+fn evaluate(records: &[RestHourRecord]) -> Vec<(u64, RestHourViolationKind)> {
+    let recorded: BTreeSet<u64> = records.iter().map(|r| r.day).collect();
+
+    // Merge all periods into one timeline of absolute minutes so rest spanning midnight counts once
+    let mut timeline: Vec<(u64, u64)> = Vec::new();
+    for record in records {
+        for period in &record.rest_periods {
+            let start = record.day * MINUTES_PER_DAY + period.start_minute as u64;
+            let end = record.day * MINUTES_PER_DAY + period.end_minute as u64;
+            match timeline.last_mut() {
+                Some(last) if last.1 == start => last.1 = end,
+                _ => timeline.push((start, end)),
+            }
+        }
+    }
+    let rest_between = |from: u64, to: u64| -> u64 {
+        timeline
+            .iter()
+            .map(|&(s, e)| e.min(to).saturating_sub(s.max(from)))
+            .sum()
+    };
+
+    let mut violations = Vec::new();
+    for &day in &recorded {
+        let day_start = day * MINUTES_PER_DAY;
+        let day_end = day_start + MINUTES_PER_DAY;
+
+        // Any 24 hours: the worst windows start at midnight or right after a rest period ends,
+        // and only windows whose last day was logged are evaluated
+        let window_starts = std::iter::once(day_start).chain(
+            timeline
+                .iter()
+                .map(|&(_, e)| e)
+                .filter(|&e| e > day_start && e < day_end),
+        );
+        let least_rest = window_starts
+            .filter(|&start| recorded.contains(&start.div_ceil(MINUTES_PER_DAY)))
+            .map(|start| rest_between(start, start + MINUTES_PER_DAY))
+            .min();
+        if let Some(rest_minutes) = least_rest.filter(|&rest| rest < MIN_REST_IN_24_HOURS) {
+            violations.push((
+                day,
+                RestHourViolationKind::InsufficientRestIn24Hours { rest_minutes },
+            ));
+        }
+
+        // Any 7 days: the week ending with this day, when all of it was logged
+        if day >= 6 && (day - 6..=day).all(|d| recorded.contains(&d)) {
+            let rest_minutes = rest_between(day_end - 7 * MINUTES_PER_DAY, day_end);
+            if rest_minutes < MIN_REST_IN_7_DAYS {
+                violations.push((
+                    day,
+                    RestHourViolationKind::InsufficientRestIn7Days { rest_minutes },
+                ));
+            }
+        }
+
+        // Rest may be split into at most two periods, one of them at least six hours long
+        let periods: Vec<&(u64, u64)> = timeline
+            .iter()
+            .filter(|&&(s, e)| s < day_end && e > day_start)
+            .collect();
+        if periods.len() > MAX_REST_PERIODS {
+            violations.push((
+                day,
+                RestHourViolationKind::TooManyRestPeriods {
+                    count: periods.len() as u32,
+                },
+            ));
+        } else if !periods.is_empty()
+            && periods
+                .iter()
+                .all(|&&(s, e)| e - s < MIN_LONGEST_REST_PERIOD)
+        {
+            violations.push((day, RestHourViolationKind::NoRestPeriodOfSixHours));
+        }
+    }
+    violations
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn period(start_minute: u16, end_minute: u16) -> RestPeriod {
+        RestPeriod {
+            start_minute,
+            end_minute,
+        }
+    }
+
+    fn record(day: u64, rest_periods: Vec<RestPeriod>) -> RestHourRecord {
+        RestHourRecord {
+            crew_member_id: 1,
+            vessel_id: 1,
+            day,
+            rest_periods,
+        }
+    }
+
+    #[test]
+    fn rest_periods_must_fit_the_day_without_overlapping() {
+        assert!(validate_rest_periods(&[period(0, 360), period(360, 720)]).is_ok());
+        assert!(validate_rest_periods(&[period(600, 1440)]).is_ok());
+        assert!(validate_rest_periods(&[period(0, 360), period(300, 720)]).is_err());
+        assert!(validate_rest_periods(&[period(360, 360)]).is_err());
+        assert!(validate_rest_periods(&[period(1200, 1441)]).is_err());
+        let too_many: Vec<RestPeriod> = (0..25).map(|i| period(i * 50, i * 50 + 10)).collect();
+        assert!(validate_rest_periods(&too_many).is_err());
+    }
+
+    #[test]
+    fn compliant_day_has_no_violations() {
+        let records = [record(10, vec![period(0, 360), period(1080, 1440)])];
+        assert!(evaluate(&records).is_empty());
+    }
+
+    #[test]
+    fn rest_across_midnight_counts_as_one_period() {
+        let records = [
+            record(10, vec![period(1080, 1440)]),
+            record(11, vec![period(0, 360), period(720, 1080)]),
+        ];
+        let violations = evaluate(&records);
+        assert!(violations.iter().all(|(day, _)| *day == 10));
+        assert!(violations.iter().any(|(_, kind)| matches!(
+            kind,
+            RestHourViolationKind::InsufficientRestIn24Hours { rest_minutes: 360 }
+        )));
+    }
+
+    #[test]
+    fn more_than_two_rest_periods_are_a_violation() {
+        let records = [record(
+            5,
+            vec![period(0, 240), period(480, 720), period(960, 1200)],
+        )];
+        let violations = evaluate(&records);
+        assert_eq!(violations.len(), 1);
+        assert!(matches!(
+            violations[0],
+            (5, RestHourViolationKind::TooManyRestPeriods { count: 3 })
+        ));
+    }
+
+    #[test]
+    fn short_rest_periods_are_a_violation() {
+        let records = [record(5, vec![period(0, 300), period(600, 900)])];
+        let violations = evaluate(&records);
+        assert_eq!(violations.len(), 1);
+        assert!(matches!(
+            violations[0],
+            (5, RestHourViolationKind::NoRestPeriodOfSixHours)
+        ));
+    }
+
+    #[test]
+    fn week_is_checked_once_seven_days_are_logged() {
+        let week: Vec<RestHourRecord> = (0..7)
+            .map(|day| record(day, vec![period(0, 600)]))
+            .collect();
+        let violations = evaluate(&week);
+        assert_eq!(violations.len(), 1);
+        assert!(matches!(
+            violations[0],
+            (
+                6,
+                RestHourViolationKind::InsufficientRestIn7Days { rest_minutes: 4200 }
+            )
+        ));
+        assert!(evaluate(&week[..6]).is_empty());
+    }
+}
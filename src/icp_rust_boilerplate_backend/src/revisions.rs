@@ -0,0 +1,446 @@
+// Revision history and point-in-time reads for vessels and voyages
+use crate::access::{require_role, Role};
+use crate::audit;
+use crate::events;
+use crate::organizations::{can_access_vessel, require_vessel_access, VesselAccess};
+use crate::{
+    _get_vessel, _get_voyage, check_vessel_free, do_insert_vessel, do_insert_voyage, Error, Memory,
+    Vessel, Voyage, VESSEL_MEMORY_MANAGER,
+};
+use candid::{Decode, Encode, Principal};
+use ic_cdk::api::{caller, time};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use std::thread::LocalKey;
+use std::{borrow::Cow, cell::RefCell};
+
+const MAX_PAGE_SIZE: u32 = 100;
+
+// ((id, effective_at), revision) -> ()
+type RevisionTimeIndex = StableBTreeMap<((u64, u64), u64), (), Memory>;
+
+// A stored version of a Vessel; `vessel` is None once the vessel was deleted
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct VesselRevision {
+    revision: u64,
+    effective_at: u64,
+    changed_by: Principal,
+    vessel: Option<Vessel>,
+}
+
+// A stored version of a Voyage; `voyage` is None once the voyage was deleted
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct VoyageRevision {
+    revision: u64,
+    effective_at: u64,
+    changed_by: Principal,
+    voyage: Option<Voyage>,
+}
+
+// Implement Storable trait for VesselRevision
+impl Storable for VesselRevision {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+// Implement BoundedStorable trait for VesselRevision
+impl BoundedStorable for VesselRevision {
+    const MAX_SIZE: u32 = Vessel::MAX_SIZE + 128;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Implement Storable trait for VoyageRevision
+impl Storable for VoyageRevision {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+// Implement BoundedStorable trait for VoyageRevision
+impl BoundedStorable for VoyageRevision {
+    const MAX_SIZE: u32 = Voyage::MAX_SIZE + 128;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Define thread-local variables for revision storage
+thread_local! {
+    // (vessel_id, revision) -> VesselRevision
+    static VESSEL_REVISIONS: RefCell<StableBTreeMap<(u64, u64), VesselRevision, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            VESSEL_MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(27)))
+    ));
+
+    // (voyage_id, revision) -> VoyageRevision
+    static VOYAGE_REVISIONS: RefCell<StableBTreeMap<(u64, u64), VoyageRevision, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            VESSEL_MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(28)))
+    ));
+
+    static VESSEL_REVISION_TIMES: RefCell<RevisionTimeIndex> =
+        RefCell::new(StableBTreeMap::init(
+            VESSEL_MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(61)))
+    ));
+
+    static VOYAGE_REVISION_TIMES: RefCell<RevisionTimeIndex> =
+        RefCell::new(StableBTreeMap::init(
+            VESSEL_MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(62)))
+    ));
+}
+
+// List up to `limit` stored revisions of a vessel from revision `start` on, oldest first
+#[ic_cdk::query]
+fn get_vessel_revisions(id: u64, start: u64, limit: u32) -> Vec<VesselRevision> {
+    if !can_access_vessel(id, VesselAccess::View) {
+        return Vec::new();
+    }
+    VESSEL_REVISIONS.with(|service| {
+        service
+            .borrow()
+            .range((id, start)..=(id, u64::MAX))
+            .take(limit.clamp(1, MAX_PAGE_SIZE) as usize)
+            .map(|(_, revision)| revision)
+            .collect()
+    })
+}
+
+// List up to `limit` stored revisions of a voyage from revision `start` on, oldest first
+#[ic_cdk::query]
+fn get_voyage_revisions(id: u64, start: u64, limit: u32) -> Vec<VoyageRevision> {
+    // A voyage never moves to another vessel, so any stored state tells whose it is
+    let vessel_id = VOYAGE_REVISIONS.with(|service| {
+        service
+            .borrow()
+            .range((id, 0)..=(id, u64::MAX))
+            .find_map(|(_, r)| r.voyage.map(|v| v.vessel_id))
+    });
+    if vessel_id.is_some_and(|vessel_id| !can_access_vessel(vessel_id, VesselAccess::View)) {
+        return Vec::new();
+    }
+    VOYAGE_REVISIONS.with(|service| {
+        service
+            .borrow()
+            .range((id, start)..=(id, u64::MAX))
+            .take(limit.clamp(1, MAX_PAGE_SIZE) as usize)
+            .map(|(_, revision)| revision)
+            .collect()
+    })
+}
+
+// Retrieve a Vessel as it was at the given timestamp
+#[ic_cdk::query]
+fn get_vessel_at(id: u64, timestamp: u64) -> Result<Vessel, Error> {
+    require_vessel_access(id, VesselAccess::View)?;
+    let vessel = if has_revisions(&VESSEL_REVISION_TIMES, id) {
+        revision_at(&VESSEL_REVISION_TIMES, id, timestamp)
+            .and_then(|revision| VESSEL_REVISIONS.with(|s| s.borrow().get(&(id, revision))))
+            .and_then(|r| r.vessel)
+    } else {
+        // Never changed since it was added
+        _get_vessel(&id).filter(|v| v.last_update <= timestamp)
+    };
+    vessel.ok_or(Error::NotFound {
+        msg: format!("a vessel with id={} did not exist at {}", id, timestamp),
+    })
+}
+
+// Retrieve a Voyage as it was at the given timestamp
+#[ic_cdk::query]
+fn get_voyage_at(id: u64, timestamp: u64) -> Result<Voyage, Error> {
+    let voyage = if has_revisions(&VOYAGE_REVISION_TIMES, id) {
+        revision_at(&VOYAGE_REVISION_TIMES, id, timestamp)
+            .and_then(|revision| VOYAGE_REVISIONS.with(|s| s.borrow().get(&(id, revision))))
+            .and_then(|r| r.voyage)
+    } else {
+        // Never changed since it was added
+        _get_voyage(&id).filter(|v| v.departure_time <= timestamp)
+    };
+    let voyage = voyage.ok_or(Error::NotFound {
+        msg: format!("a voyage with id={} did not exist at {}", id, timestamp),
+    })?;
+    require_vessel_access(voyage.vessel_id, VesselAccess::View)?;
+    Ok(voyage)
+}
+
+// Restore a vessel to the content of an earlier revision (admins only)
+#[ic_cdk::update]
+fn revert_vessel(id: u64, revision: u64) -> Result<Vessel, Error> {
+    require_role(Role::Admin)?;
+    let target = VESSEL_REVISIONS
+        .with(|service| service.borrow().get(&(id, revision)))
+        .and_then(|r| r.vessel)
+        .ok_or(Error::NotFound {
+            msg: format!(
+                "revision {} of vessel id={} not found or is a deletion",
+                revision, id
+            ),
+        })?;
+
+    // Deleted vessels live in the archive, which reverting would leave behind
+    let before = _get_vessel(&id).ok_or(Error::InvalidInput {
+        msg: format!(
+            "vessel id={} is deleted; bring it back with restore_vessel first",
+            id
+        ),
+    })?;
+    let vessel = Vessel {
+        last_update: time(),
+        version: Some(before.current_version() + 1),
+        ..target
+    };
+    do_insert_vessel(&vessel);
+    audit::record("revert_vessel", "vessel", id, Some(&before), Some(&vessel));
+    track_vessel(id, Some(&before), Some(&vessel));
+    events::vessel_changed(id, Some(&before), Some(&vessel));
+    Ok(vessel)
+}
+
+// Restore a voyage to the content of an earlier revision (admins only)
+#[ic_cdk::update]
+fn revert_voyage(id: u64, revision: u64) -> Result<Voyage, Error> {
+    require_role(Role::Admin)?;
+    let target = VOYAGE_REVISIONS
+        .with(|service| service.borrow().get(&(id, revision)))
+        .and_then(|r| r.voyage)
+        .ok_or(Error::NotFound {
+            msg: format!(
+                "revision {} of voyage id={} not found or is a deletion",
+                revision, id
+            ),
+        })?;
+
+    // Deleted voyages live in the archive, which reverting would leave behind
+    let before = _get_voyage(&id).ok_or(Error::InvalidInput {
+        msg: format!(
+            "voyage id={} is deleted; bring it back with restore_voyage first",
+            id
+        ),
+    })?;
+    // The vessel may have been booked over the old times since
+    check_vessel_free(&target, &[id])?;
+    let voyage = Voyage {
+        version: Some(before.current_version() + 1),
+        ..target
+    };
+    do_insert_voyage(&voyage);
+    audit::record("revert_voyage", "voyage", id, Some(&before), Some(&voyage));
+    track_voyage(id, Some(&before), Some(&voyage));
+    events::voyage_changed(id, Some(&before), Some(&voyage));
+    Ok(voyage)
+}
+
+// Store the new version of a vessel. Vessels that predate revision tracking
+// get their previous state stored first, effective from its last update.
+pub(crate) fn track_vessel(id: u64, before: Option<&Vessel>, after: Option<&Vessel>) {
+    VESSEL_REVISIONS.with(|service| {
+        let mut service = service.borrow_mut();
+        let mut next = service
+            .iter_upper_bound(&(id, u64::MAX))
+            .next()
+            .filter(|((found_id, _), _)| *found_id == id)
+            .map_or(0, |((_, revision), _)| revision + 1);
+        if let (0, Some(before)) = (next, before) {
+            let seed = VesselRevision {
+                revision: 0,
+                effective_at: before.last_update,
+                changed_by: Principal::anonymous(),
+                vessel: Some(before.clone()),
+            };
+            index_time(&VESSEL_REVISION_TIMES, id, seed.effective_at, 0);
+            service.insert((id, 0), seed);
+            next = 1;
+        }
+        let revision = VesselRevision {
+            revision: next,
+            effective_at: time(),
+            changed_by: caller(),
+            vessel: after.cloned(),
+        };
+        index_time(&VESSEL_REVISION_TIMES, id, revision.effective_at, next);
+        service.insert((id, next), revision);
+    });
+}
+
+// Store the new version of a voyage. Voyages that predate revision tracking
+// get their previous state stored first, effective from their departure.
+pub(crate) fn track_voyage(id: u64, before: Option<&Voyage>, after: Option<&Voyage>) {
+    VOYAGE_REVISIONS.with(|service| {
+        let mut service = service.borrow_mut();
+        let mut next = service
+            .iter_upper_bound(&(id, u64::MAX))
+            .next()
+            .filter(|((found_id, _), _)| *found_id == id)
+            .map_or(0, |((_, revision), _)| revision + 1);
+        if let (0, Some(before)) = (next, before) {
+            let seed = VoyageRevision {
+                revision: 0,
+                effective_at: before.departure_time,
+                changed_by: Principal::anonymous(),
+                voyage: Some(before.clone()),
+            };
+            index_time(&VOYAGE_REVISION_TIMES, id, seed.effective_at, 0);
+            service.insert((id, 0), seed);
+            next = 1;
+        }
+        let revision = VoyageRevision {
+            revision: next,
+            effective_at: time(),
+            changed_by: caller(),
+            voyage: after.cloned(),
+        };
+        index_time(&VOYAGE_REVISION_TIMES, id, revision.effective_at, next);
+        service.insert((id, next), revision);
+    });
+}
+
+// Drop the revisions of a vessel that is gone for good
+pub(crate) fn forget_vessel(id: u64) {
+    for (effective_at, revision) in revision_times(&VESSEL_REVISION_TIMES, id) {
+        VESSEL_REVISION_TIMES
+            .with(|index| index.borrow_mut().remove(&((id, effective_at), revision)));
+        VESSEL_REVISIONS.with(|service| service.borrow_mut().remove(&(id, revision)));
+    }
+}
+
+// Drop the revisions of a voyage that is gone for good
+pub(crate) fn forget_voyage(id: u64) {
+    for (effective_at, revision) in revision_times(&VOYAGE_REVISION_TIMES, id) {
+        VOYAGE_REVISION_TIMES
+            .with(|index| index.borrow_mut().remove(&((id, effective_at), revision)));
+        VOYAGE_REVISIONS.with(|service| service.borrow_mut().remove(&(id, revision)));
+    }
+}
+
+// Index the revisions stored before the time indexes existed
+pub(crate) fn backfill_time_indexes() {
+    let indexed = VESSEL_REVISION_TIMES.with(|index| !index.borrow().is_empty())
+        || VOYAGE_REVISION_TIMES.with(|index| !index.borrow().is_empty());
+    if indexed {
+        return;
+    }
+    VESSEL_REVISIONS.with(|service| {
+        for ((id, revision), r) in service.borrow().iter() {
+            index_time(&VESSEL_REVISION_TIMES, id, r.effective_at, revision);
+        }
+    });
+    VOYAGE_REVISIONS.with(|service| {
+        for ((id, revision), r) in service.borrow().iter() {
+            index_time(&VOYAGE_REVISION_TIMES, id, r.effective_at, revision);
+        }
+    });
+}
+
+// Helper method to record when a revision took effect
+fn index_time(
+    index: &'static LocalKey<RefCell<RevisionTimeIndex>>,
+    id: u64,
+    at: u64,
+    revision: u64,
+) {
+    index.with(|index| index.borrow_mut().insert(((id, at), revision), ()));
+}
+
+// Whether any revision of the record was stored
+fn has_revisions(index: &'static LocalKey<RefCell<RevisionTimeIndex>>, id: u64) -> bool {
+    index.with(|index| {
+        index
+            .borrow()
+            .range(((id, 0), 0)..=((id, u64::MAX), u64::MAX))
+            .next()
+            .is_some()
+    })
+}
+
+// The revision in effect at a timestamp: the last one that took effect at or before it
+fn revision_at(
+    index: &'static LocalKey<RefCell<RevisionTimeIndex>>,
+    id: u64,
+    timestamp: u64,
+) -> Option<u64> {
+    index.with(|index| {
+        let index = index.borrow();
+        // Keys with the very last revision number cannot exist, so this bound is inclusive in effect
+        let found = index
+            .iter_upper_bound(&((id, timestamp), u64::MAX))
+            .next()
+            .map(|(key, _)| key);
+        found
+            .filter(|((found_id, _), _)| *found_id == id)
+            .map(|(_, revision)| revision)
+    })
+}
+
+// The (effective_at, revision) pairs of a record in time order
+fn revision_times(
+    index: &'static LocalKey<RefCell<RevisionTimeIndex>>,
+    id: u64,
+) -> Vec<(u64, u64)> {
+    index.with(|index| {
+        index
+            .borrow()
+            .range(((id, 0), 0)..=((id, u64::MAX), u64::MAX))
+            .map(|(((_, at), revision), _)| (at, revision))
+            .collect()
+    })
+}
+
+// Up to `limit` locations a vessel reported between two timestamps, starting with the one in
+// effect at `from`, with the time each was reported
+pub(crate) fn vessel_track(id: u64, from: u64, to: u64, limit: usize) -> Vec<(u64, String)> {
+    let first = VESSEL_REVISION_TIMES.with(|index| {
+        index
+            .borrow()
+            .iter_upper_bound(&((id, from), u64::MAX))
+            .next()
+            .map(|(key, _)| key)
+            .filter(|((found_id, _), _)| *found_id == id)
+            .unwrap_or(((id, 0), 0))
+    });
+    let revisions: Vec<u64> = VESSEL_REVISION_TIMES.with(|index| {
+        index
+            .borrow()
+            .range(first..=((id, to), u64::MAX))
+            .take(limit)
+            .map(|((_, revision), _)| revision)
+            .collect()
+    });
+    revisions
+        .into_iter()
+        .filter_map(|revision| VESSEL_REVISIONS.with(|s| s.borrow().get(&(id, revision))))
+        .filter_map(|r| r.vessel.map(|v| (r.effective_at, v.current_location)))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn revision_at_finds_the_one_in_effect() {
+        index_time(&VESSEL_REVISION_TIMES, 7, 100, 0);
+        index_time(&VESSEL_REVISION_TIMES, 7, 200, 1);
+        index_time(&VESSEL_REVISION_TIMES, 7, 200, 2);
+        index_time(&VESSEL_REVISION_TIMES, 8, 50, 0);
+        assert_eq!(revision_at(&VESSEL_REVISION_TIMES, 7, 99), None);
+        assert_eq!(revision_at(&VESSEL_REVISION_TIMES, 7, 100), Some(0));
+        assert_eq!(revision_at(&VESSEL_REVISION_TIMES, 7, 199), Some(0));
+        assert_eq!(revision_at(&VESSEL_REVISION_TIMES, 7, 200), Some(2));
+        assert_eq!(revision_at(&VESSEL_REVISION_TIMES, 7, u64::MAX), Some(2));
+        assert_eq!(revision_at(&VESSEL_REVISION_TIMES, 8, 49), None);
+        assert_eq!(revision_at(&VESSEL_REVISION_TIMES, 9, 1_000), None);
+        assert!(has_revisions(&VESSEL_REVISION_TIMES, 8));
+        assert!(!has_revisions(&VESSEL_REVISION_TIMES, 9));
+        assert_eq!(
+            revision_times(&VESSEL_REVISION_TIMES, 7),
+            vec![(100, 0), (200, 1), (200, 2)]
+        );
+    }
+}
@@ -0,0 +1,502 @@
+// Statutory ship certificates and their survey schedule
+use crate::audit;
+use crate::calendar::add_months;
+use crate::organizations::{require_vessel_access, VesselAccess};
+use crate::{_get_vessel, Error, IdCell, Memory, VESSEL_MEMORY_MANAGER};
+use candid::{Decode, Encode};
+use ic_cdk::api::time;
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use std::{borrow::Cow, cell::RefCell};
+
+// Surveys may be carried out within three months either side of an anniversary date
+const SURVEY_WINDOW_MONTHS: i64 = 3;
+// Keep a ShipCertificate within its MAX_SIZE: an endorsement takes 9 bytes encoded, so a
+// full certificate is about 1.1 KiB of the 2 KiB
+const MAX_TEXT_LEN: usize = 200;
+const MAX_ENDORSEMENTS: usize = 50;
+// Statutory certificates run for at most five years; leave room for extensions
+const MAX_VALIDITY_MONTHS: i64 = 10 * 12;
+
+// Define the kinds of statutory certificates a vessel carries
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default, PartialEq, Eq, Debug)]
+pub(crate) enum ShipCertificateKind {
+    #[default]
+    SolasSafetyConstruction,
+    SolasSafetyEquipment,
+    SolasSafetyRadio,
+    // MARPOL Annex I International Oil Pollution Prevention
+    Iopp,
+    // ISM Document of Compliance (copy held on board)
+    IsmDoc,
+    // ISM Safety Management Certificate
+    IsmSmc,
+    // ISPS International Ship Security Certificate
+    IspsIssc,
+    LoadLine,
+    // Any non-mandatory certificate, e.g. class or flag specific
+    Other {
+        name: String,
+    },
+}
+
+// Define the kinds of surveys that are endorsed on a certificate
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Debug)]
+pub(crate) enum SurveyKind {
+    Annual,
+    Intermediate,
+    Renewal,
+}
+
+// A survey endorsed on a certificate
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct SurveyEndorsement {
+    survey: SurveyKind,
+    date: u64,
+}
+
+// Define the structure for a statutory certificate of a vessel
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub(crate) struct ShipCertificate {
+    pub(crate) id: u64,
+    pub(crate) vessel_id: u64,
+    kind: ShipCertificateKind,
+    certificate_number: String,
+    issued_by: String,
+    issue_date: u64,
+    pub(crate) expiry_date: u64,
+    endorsements: Vec<SurveyEndorsement>,
+}
+
+// Payload used to record a certificate for a vessel
+#[derive(candid::CandidType, Serialize, Deserialize, Default)]
+pub(crate) struct ShipCertificatePayload {
+    vessel_id: u64,
+    kind: ShipCertificateKind,
+    certificate_number: String,
+    issued_by: String,
+    issue_date: u64,
+    expiry_date: u64,
+}
+
+// Where a survey window stands relative to now
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub(crate) enum SurveyWindowStatus {
+    Upcoming,
+    Open,
+    Completed,
+    Overdue,
+}
+
+// A computed window in which a survey has to be carried out
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct SurveyWindow {
+    survey: SurveyKind,
+    due_date: u64,
+    window_start: u64,
+    window_end: u64,
+    completed_on: Option<u64>,
+    status: SurveyWindowStatus,
+}
+
+// A certificate together with its survey windows
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct CertificateSchedule {
+    certificate: ShipCertificate,
+    windows: Vec<SurveyWindow>,
+    mandatory: bool,
+    lapsed: bool,
+}
+
+impl ShipCertificateKind {
+    // Certificates a vessel must hold in force to sail
+    fn is_mandatory(&self) -> bool {
+        !matches!(self, ShipCertificateKind::Other { .. })
+    }
+
+    // (annual surveys, intermediate survey) required during the certificate's validity
+    fn survey_scheme(&self) -> (bool, bool) {
+        match self {
+            ShipCertificateKind::SolasSafetyConstruction
+            | ShipCertificateKind::SolasSafetyEquipment
+            | ShipCertificateKind::Iopp
+            | ShipCertificateKind::LoadLine => (true, true),
+            ShipCertificateKind::SolasSafetyRadio | ShipCertificateKind::IsmDoc => (true, false),
+            ShipCertificateKind::IsmSmc | ShipCertificateKind::IspsIssc => (false, true),
+            ShipCertificateKind::Other { .. } => (false, false),
+        }
+    }
+}
+
+impl ShipCertificate {
+    // Compute the survey windows of the certificate and their status at the given time
+    fn survey_windows(&self, at: u64) -> Vec<SurveyWindow> {
+        let (annual, intermediate) = self.kind.survey_scheme();
+        let anniversary = |years: i64| add_months(self.issue_date, years * 12);
+        let anniversaries = (1..)
+            .map(anniversary)
+            .take_while(|&date| date < self.expiry_date)
+            .count() as i64;
+
+        let mut windows = Vec::new();
+        if annual {
+            for year in 1..=anniversaries {
+                let due_date = anniversary(year);
+                windows.push((
+                    SurveyKind::Annual,
+                    due_date,
+                    add_months(due_date, -SURVEY_WINDOW_MONTHS),
+                    add_months(due_date, SURVEY_WINDOW_MONTHS),
+                ));
+            }
+        }
+        if intermediate && anniversaries >= 2 {
+            // Between three months before the second and three months after the third anniversary
+            let last = anniversaries.min(3);
+            windows.push((
+                SurveyKind::Intermediate,
+                anniversary(2),
+                add_months(anniversary(2), -SURVEY_WINDOW_MONTHS),
+                add_months(anniversary(last), SURVEY_WINDOW_MONTHS).min(self.expiry_date),
+            ));
+        }
+        windows.push((
+            SurveyKind::Renewal,
+            self.expiry_date,
+            add_months(self.expiry_date, -SURVEY_WINDOW_MONTHS).max(self.issue_date),
+            self.expiry_date,
+        ));
+        windows.sort_by_key(|w| w.2);
+
+        windows
+            .into_iter()
+            .map(|(survey, due_date, window_start, window_end)| {
+                // An intermediate survey held within an annual window also counts as the annual survey
+                let completed_on = self
+                    .endorsements
+                    .iter()
+                    .filter(|e| {
+                        e.survey == survey
+                            || (survey == SurveyKind::Annual
+                                && e.survey == SurveyKind::Intermediate)
+                    })
+                    .map(|e| e.date)
+                    .find(|&date| date >= window_start && date <= window_end);
+                let status = match completed_on {
+                    Some(_) => SurveyWindowStatus::Completed,
+                    None if at < window_start => SurveyWindowStatus::Upcoming,
+                    None if at <= window_end => SurveyWindowStatus::Open,
+                    None => SurveyWindowStatus::Overdue,
+                };
+                SurveyWindow {
+                    survey,
+                    due_date,
+                    window_start,
+                    window_end,
+                    completed_on,
+                    status,
+                }
+            })
+            .collect()
+    }
+
+    // A certificate lapses when it expires or when a periodical survey window closes unendorsed
+    pub(crate) fn is_lapsed_at(&self, at: u64) -> bool {
+        at < self.issue_date
+            || at >= self.expiry_date
+            || self
+                .survey_windows(at)
+                .iter()
+                .any(|w| w.survey != SurveyKind::Renewal && w.status == SurveyWindowStatus::Overdue)
+    }
+}
+
+// Implement Storable trait for ShipCertificate
+impl Storable for ShipCertificate {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+// Implement BoundedStorable trait for ShipCertificate
+impl BoundedStorable for ShipCertificate {
+    const MAX_SIZE: u32 = 2048;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Define thread-local variables for ship certificate storage
+thread_local! {
+    static SHIP_CERTIFICATE_ID_COUNTER: RefCell<IdCell> = RefCell::new(
+        IdCell::init(VESSEL_MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(13))), 0)
+            .expect("Cannot create a counter")
+    );
+
+    static SHIP_CERTIFICATE_STORAGE: RefCell<StableBTreeMap<u64, ShipCertificate, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            VESSEL_MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(14)))
+    ));
+
+    // (vessel_id, certificate_id) -> ()
+    static VESSEL_CERTIFICATE_INDEX: RefCell<StableBTreeMap<(u64, u64), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            VESSEL_MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(15)))
+    ));
+}
+
+// Record a statutory certificate for a vessel
+#[ic_cdk::update]
+fn add_ship_certificate(payload: ShipCertificatePayload) -> Result<ShipCertificate, Error> {
+    require_vessel_access(payload.vessel_id, VesselAccess::Edit)?;
+    if _get_vessel(&payload.vessel_id).is_none() {
+        return Err(Error::NotFound {
+            msg: format!("a vessel with id={} not found", payload.vessel_id),
+        });
+    }
+    if payload.expiry_date <= payload.issue_date {
+        return Err(Error::InvalidInput {
+            msg: "expiry_date must be after issue_date".to_string(),
+        });
+    }
+    if payload.expiry_date > add_months(payload.issue_date, MAX_VALIDITY_MONTHS) {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "expiry_date must be within {} years of issue_date",
+                MAX_VALIDITY_MONTHS / 12
+            ),
+        });
+    }
+    let other_name = match &payload.kind {
+        ShipCertificateKind::Other { name } => name.len(),
+        _ => 0,
+    };
+    if payload.certificate_number.len() > MAX_TEXT_LEN
+        || payload.issued_by.len() > MAX_TEXT_LEN
+        || other_name > MAX_TEXT_LEN
+    {
+        return Err(Error::InvalidInput {
+            msg: format!("text fields must not exceed {} bytes", MAX_TEXT_LEN),
+        });
+    }
+
+    // Generate a new ID for the ShipCertificate
+    let id = SHIP_CERTIFICATE_ID_COUNTER
+        .with(|counter| {
+            let current_value = *counter.borrow().get();
+            counter.borrow_mut().set(current_value + 1)
+        })
+        .expect("cannot increment ship certificate id counter");
+
+    let certificate = ShipCertificate {
+        id,
+        vessel_id: payload.vessel_id,
+        kind: payload.kind,
+        certificate_number: payload.certificate_number,
+        issued_by: payload.issued_by,
+        issue_date: payload.issue_date,
+        expiry_date: payload.expiry_date,
+        endorsements: Vec::new(),
+    };
+    do_insert_ship_certificate(&certificate);
+    VESSEL_CERTIFICATE_INDEX.with(|index| {
+        index
+            .borrow_mut()
+            .insert((certificate.vessel_id, certificate.id), ())
+    });
+    audit::record(
+        "add_ship_certificate",
+        "ship_certificate",
+        id,
+        None,
+        Some(&certificate),
+    );
+    Ok(certificate)
+}
+
+// Endorse a completed survey on a certificate
+#[ic_cdk::update]
+fn record_survey(
+    certificate_id: u64,
+    survey: SurveyKind,
+    date: u64,
+) -> Result<ShipCertificate, Error> {
+    match _get_ship_certificate(&certificate_id) {
+        Some(mut certificate) => {
+            require_vessel_access(certificate.vessel_id, VesselAccess::Edit)?;
+            let windows = certificate.survey_windows(date);
+            let Some(window) = windows
+                .iter()
+                .find(|w| w.survey == survey && date >= w.window_start && date <= w.window_end)
+            else {
+                return Err(Error::InvalidInput {
+                    msg: format!(
+                        "no {:?} survey window of certificate id={} covers the given date",
+                        survey, certificate_id
+                    ),
+                });
+            };
+            // A window needs one endorsement; further ones would only grow the certificate
+            if let Some(completed_on) = window.completed_on {
+                return Err(Error::InvalidInput {
+                    msg: format!(
+                        "the {:?} survey window of certificate id={} was already completed on {}",
+                        survey, certificate_id, completed_on
+                    ),
+                });
+            }
+            if certificate.endorsements.len() >= MAX_ENDORSEMENTS {
+                return Err(Error::InvalidInput {
+                    msg: format!(
+                        "certificate id={} already holds the maximum of {} endorsements",
+                        certificate_id, MAX_ENDORSEMENTS
+                    ),
+                });
+            }
+            let before = certificate.clone();
+            certificate
+                .endorsements
+                .push(SurveyEndorsement { survey, date });
+            do_insert_ship_certificate(&certificate);
+            audit::record(
+                "record_survey",
+                "ship_certificate",
+                certificate_id,
+                Some(&before),
+                Some(&certificate),
+            );
+            Ok(certificate)
+        }
+        None => Err(Error::NotFound {
+            msg: format!("a ship certificate with id={} not found", certificate_id),
+        }),
+    }
+}
+
+// List the certificates of a vessel
+#[ic_cdk::query]
+fn get_ship_certificates(vessel_id: u64) -> Result<Vec<ShipCertificate>, Error> {
+    require_vessel_access(vessel_id, VesselAccess::View)?;
+    if _get_vessel(&vessel_id).is_none() {
+        return Err(Error::NotFound {
+            msg: format!("a vessel with id={} not found", vessel_id),
+        });
+    }
+    Ok(vessel_certificates(vessel_id))
+}
+
+// Compute the survey schedule of every certificate of a vessel
+#[ic_cdk::query]
+fn get_survey_schedule(vessel_id: u64) -> Result<Vec<CertificateSchedule>, Error> {
+    require_vessel_access(vessel_id, VesselAccess::View)?;
+    if _get_vessel(&vessel_id).is_none() {
+        return Err(Error::NotFound {
+            msg: format!("a vessel with id={} not found", vessel_id),
+        });
+    }
+    let now = time();
+    Ok(vessel_certificates(vessel_id)
+        .into_iter()
+        .map(|certificate| CertificateSchedule {
+            windows: certificate.survey_windows(now),
+            mandatory: certificate.kind.is_mandatory(),
+            lapsed: certificate.is_lapsed_at(now),
+            certificate,
+        })
+        .collect())
+}
+
+// Fail when a mandatory certificate kind held by the vessel has no certificate in force.
+// Kinds that were never recorded for the vessel are not considered lapsed.
+pub(crate) fn check_mandatory_certificates(vessel_id: u64, at: u64) -> Result<(), Error> {
+    let certificates = vessel_certificates(vessel_id);
+    let mut lapsed: Vec<String> = Vec::new();
+    for certificate in certificates.iter().filter(|c| c.kind.is_mandatory()) {
+        let in_force = certificates
+            .iter()
+            .any(|c| c.kind == certificate.kind && !c.is_lapsed_at(at));
+        let name = format!("{:?}", certificate.kind);
+        if !in_force && !lapsed.contains(&name) {
+            lapsed.push(name);
+        }
+    }
+    if lapsed.is_empty() {
+        Ok(())
+    } else {
+        Err(Error::InvalidInput {
+            msg: format!(
+                "vessel id={} cannot sail: mandatory certificates lapsed: {}",
+                vessel_id,
+                lapsed.join(", ")
+            ),
+        })
+    }
+}
+
+// Helper method to insert a ShipCertificate into storage
+fn do_insert_ship_certificate(certificate: &ShipCertificate) {
+    SHIP_CERTIFICATE_STORAGE.with(|service| {
+        service
+            .borrow_mut()
+            .insert(certificate.id, certificate.clone())
+    });
+}
+
+// Retrieve a ShipCertificate by ID from storage
+fn _get_ship_certificate(id: &u64) -> Option<ShipCertificate> {
+    SHIP_CERTIFICATE_STORAGE.with(|service| service.borrow().get(id))
+}
+
+// Collect all certificates of a vessel using the vessel index
+fn vessel_certificates(vessel_id: u64) -> Vec<ShipCertificate> {
+    let ids: Vec<u64> = VESSEL_CERTIFICATE_INDEX.with(|index| {
+        index
+            .borrow()
+            .range((vessel_id, 0)..=(vessel_id, u64::MAX))
+            .map(|((_, certificate_id), _)| certificate_id)
+            .collect()
+    });
+    ids.iter().filter_map(_get_ship_certificate).collect()
+}
+
+// Page through ship certificates in ID order, starting at `start`
+pub(crate) fn ship_certificates_from(start: u64, limit: usize) -> Vec<(u64, ShipCertificate)> {
+    SHIP_CERTIFICATE_STORAGE.with(|service| service.borrow().range(start..).take(limit).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::calendar::NANOS_PER_DAY;
+
+    const YEAR: u64 = 365 * NANOS_PER_DAY;
+
+    fn certificate(issue_date: u64, expiry_date: u64) -> ShipCertificate {
+        ShipCertificate {
+            kind: ShipCertificateKind::SolasSafetyEquipment,
+            issue_date,
+            expiry_date,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn five_year_certificate_has_annual_intermediate_and_renewal_windows() {
+        let issue_date = 50 * YEAR;
+        let windows = certificate(issue_date, add_months(issue_date, 60)).survey_windows(0);
+        let count = |kind| windows.iter().filter(|w| w.survey == kind).count();
+        assert_eq!(count(SurveyKind::Annual), 4);
+        assert_eq!(count(SurveyKind::Intermediate), 1);
+        assert_eq!(count(SurveyKind::Renewal), 1);
+    }
+
+    #[test]
+    fn extreme_expiry_dates_terminate() {
+        let certificate = certificate(50 * YEAR, u64::MAX);
+        let windows = certificate.survey_windows(51 * YEAR);
+        assert!(windows.iter().any(|w| w.survey == SurveyKind::Renewal));
+        assert!(!certificate.is_lapsed_at(51 * YEAR));
+    }
+}
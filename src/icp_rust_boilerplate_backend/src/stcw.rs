@@ -0,0 +1,315 @@
+// STCW certificates and medical fitness documents held by crew members
+use crate::audit;
+use crate::calendar::NANOS_PER_DAY;
+use crate::crew::{
+    _get_crew_member, can_access_crew_member, current_vessel, require_crew_access, Rank,
+};
+use crate::organizations::VesselAccess;
+use crate::{Error, IdCell, Memory, VESSEL_MEMORY_MANAGER};
+use candid::{Decode, Encode};
+use ic_cdk::api::time;
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use std::{borrow::Cow, cell::RefCell};
+
+// Define the kinds of certificates and documents a seafarer can hold
+#[derive(
+    candid::CandidType, Clone, Copy, Serialize, Deserialize, Default, PartialEq, Eq, Debug,
+)]
+pub(crate) enum StcwCertificateKind {
+    // STCW II/2 master
+    Master,
+    // STCW II/2 chief mate
+    ChiefMate,
+    // STCW II/1 officer in charge of a navigational watch
+    OfficerOfNavigationalWatch,
+    // STCW III/2 chief engineer officer
+    ChiefEngineerOfficer,
+    // STCW III/2 second engineer officer
+    SecondEngineerOfficer,
+    // STCW III/1 officer in charge of an engineering watch
+    OfficerOfEngineeringWatch,
+    // STCW III/6 electro-technical officer
+    ElectroTechnicalOfficer,
+    // STCW II/5 able seafarer deck
+    AbleSeafarerDeck,
+    // STCW III/5 able seafarer engine
+    AbleSeafarerEngine,
+    // STCW II/4 rating forming part of a navigational watch
+    RatingNavigationalWatch,
+    // STCW III/4 rating forming part of an engineering watch
+    RatingEngineeringWatch,
+    // MLC 2006 ship's cook certificate
+    ShipsCook,
+    // STCW VI/1 basic safety training
+    #[default]
+    BasicSafetyTraining,
+    // MLC 2006 / STCW I/9 medical fitness certificate
+    MedicalFitness,
+}
+
+// Define the structure for a certificate held by a crew member
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub(crate) struct CrewCertificate {
+    pub(crate) id: u64,
+    pub(crate) crew_member_id: u64,
+    pub(crate) kind: StcwCertificateKind,
+    pub(crate) certificate_number: String,
+    pub(crate) issuing_authority: String,
+    pub(crate) issue_date: u64,
+    pub(crate) expiry_date: u64,
+}
+
+// Payload used to record a certificate for a crew member
+#[derive(candid::CandidType, Serialize, Deserialize, Default)]
+pub(crate) struct CrewCertificatePayload {
+    crew_member_id: u64,
+    kind: StcwCertificateKind,
+    certificate_number: String,
+    issuing_authority: String,
+    issue_date: u64,
+    expiry_date: u64,
+}
+
+// A certificate about to expire together with where its holder currently serves
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct ExpiringCrewCertificate {
+    certificate: CrewCertificate,
+    crew_member_name: String,
+    vessel_id: Option<u64>,
+}
+
+impl CrewCertificate {
+    // Whether the certificate is in force at the given point in time
+    fn is_valid_at(&self, at: u64) -> bool {
+        self.issue_date <= at && at < self.expiry_date
+    }
+}
+
+// Implement Storable trait for CrewCertificate
+impl Storable for CrewCertificate {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+// Implement BoundedStorable trait for CrewCertificate
+impl BoundedStorable for CrewCertificate {
+    const MAX_SIZE: u32 = 1024;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Longest certificate_number and issuing_authority accepted; two of them together with
+// the fixed fields keep a certificate well inside its 1024 byte bound
+const MAX_TEXT_LEN: usize = 200;
+
+// Define thread-local variables for crew certificate storage
+thread_local! {
+    static CREW_CERTIFICATE_ID_COUNTER: RefCell<IdCell> = RefCell::new(
+        IdCell::init(VESSEL_MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(8))), 0)
+            .expect("Cannot create a counter")
+    );
+
+    static CREW_CERTIFICATE_STORAGE: RefCell<StableBTreeMap<u64, CrewCertificate, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            VESSEL_MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(9)))
+    ));
+
+    // (crew_member_id, certificate_id) -> ()
+    static CREW_CERTIFICATE_INDEX: RefCell<StableBTreeMap<(u64, u64), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            VESSEL_MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(10)))
+    ));
+}
+
+// Record a certificate for a crew member
+#[ic_cdk::update]
+fn add_crew_certificate(payload: CrewCertificatePayload) -> Result<CrewCertificate, Error> {
+    if _get_crew_member(&payload.crew_member_id).is_none() {
+        return Err(Error::NotFound {
+            msg: format!("a crew member with id={} not found", payload.crew_member_id),
+        });
+    }
+    require_crew_access(payload.crew_member_id, VesselAccess::Edit)?;
+    if payload.certificate_number.len() > MAX_TEXT_LEN
+        || payload.issuing_authority.len() > MAX_TEXT_LEN
+    {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "certificate_number and issuing_authority must be at most {} bytes",
+                MAX_TEXT_LEN
+            ),
+        });
+    }
+    if payload.expiry_date <= payload.issue_date {
+        return Err(Error::InvalidInput {
+            msg: "expiry_date must be after issue_date".to_string(),
+        });
+    }
+
+    // Generate a new ID for the CrewCertificate
+    let id = CREW_CERTIFICATE_ID_COUNTER
+        .with(|counter| {
+            let current_value = *counter.borrow().get();
+            counter.borrow_mut().set(current_value + 1)
+        })
+        .expect("cannot increment crew certificate id counter");
+
+    let certificate = CrewCertificate {
+        id,
+        crew_member_id: payload.crew_member_id,
+        kind: payload.kind,
+        certificate_number: payload.certificate_number,
+        issuing_authority: payload.issuing_authority,
+        issue_date: payload.issue_date,
+        expiry_date: payload.expiry_date,
+    };
+    CREW_CERTIFICATE_STORAGE.with(|service| {
+        service
+            .borrow_mut()
+            .insert(certificate.id, certificate.clone())
+    });
+    CREW_CERTIFICATE_INDEX.with(|index| {
+        index
+            .borrow_mut()
+            .insert((certificate.crew_member_id, certificate.id), ())
+    });
+    audit::record(
+        "add_crew_certificate",
+        "crew_certificate",
+        id,
+        None,
+        Some(&certificate),
+    );
+    Ok(certificate)
+}
+
+// List the certificates held by a crew member
+#[ic_cdk::query]
+fn get_crew_certificates(crew_member_id: u64) -> Result<Vec<CrewCertificate>, Error> {
+    if _get_crew_member(&crew_member_id).is_none() {
+        return Err(Error::NotFound {
+            msg: format!("a crew member with id={} not found", crew_member_id),
+        });
+    }
+    require_crew_access(crew_member_id, VesselAccess::View)?;
+    Ok(crew_certificates(crew_member_id))
+}
+
+// List certificates across the fleet that expire within the given number of days.
+// Callers see the crew serving on vessels they may view; operators also see crew ashore.
+#[ic_cdk::query]
+fn get_expiring_crew_certificates(days: u64) -> Vec<ExpiringCrewCertificate> {
+    let now = time();
+    let horizon = now.saturating_add(days.saturating_mul(NANOS_PER_DAY));
+    let mut expiring: Vec<ExpiringCrewCertificate> = CREW_CERTIFICATE_STORAGE
+        .with(|service| {
+            service
+                .borrow()
+                .iter()
+                .map(|(_, certificate)| certificate)
+                .filter(|c| c.expiry_date >= now && c.expiry_date <= horizon)
+                .collect::<Vec<_>>()
+        })
+        .into_iter()
+        .map(|certificate| {
+            let crew_member_name = _get_crew_member(&certificate.crew_member_id)
+                .map(|member| member.name)
+                .unwrap_or_default();
+            let vessel_id = current_vessel(certificate.crew_member_id, now);
+            ExpiringCrewCertificate {
+                certificate,
+                crew_member_name,
+                vessel_id,
+            }
+        })
+        .filter(|e| can_access_crew_member(e.certificate.crew_member_id, VesselAccess::View))
+        .collect();
+    expiring.sort_by_key(|e| e.certificate.expiry_date);
+    expiring
+}
+
+// Certificates of competency that qualify a seafarer to serve in a rank;
+// an empty list means no certificate of competency is required
+fn competency_for_rank(rank: Rank) -> &'static [StcwCertificateKind] {
+    use StcwCertificateKind::*;
+    match rank {
+        Rank::Master => &[Master],
+        Rank::ChiefOfficer => &[Master, ChiefMate],
+        Rank::SecondOfficer | Rank::ThirdOfficer => {
+            &[Master, ChiefMate, OfficerOfNavigationalWatch]
+        }
+        Rank::ChiefEngineer => &[ChiefEngineerOfficer],
+        Rank::SecondEngineer => &[ChiefEngineerOfficer, SecondEngineerOfficer],
+        Rank::ThirdEngineer => &[
+            ChiefEngineerOfficer,
+            SecondEngineerOfficer,
+            OfficerOfEngineeringWatch,
+        ],
+        Rank::ElectroTechnicalOfficer => &[ElectroTechnicalOfficer],
+        Rank::Bosun | Rank::AbleSeafarer => &[AbleSeafarerDeck],
+        Rank::Oiler => &[AbleSeafarerEngine, RatingEngineeringWatch],
+        Rank::Cook => &[ShipsCook],
+        Rank::OrdinarySeaman | Rank::Steward | Rank::Cadet => &[],
+    }
+}
+
+// Check that a crew member holds valid certificates for a rank at the given date
+pub(crate) fn check_rank_coverage(crew_member_id: u64, rank: Rank, at: u64) -> Result<(), Error> {
+    let valid: Vec<StcwCertificateKind> = crew_certificates(crew_member_id)
+        .into_iter()
+        .filter(|c| c.is_valid_at(at))
+        .map(|c| c.kind)
+        .collect();
+
+    let mut missing = Vec::new();
+    for required in [
+        StcwCertificateKind::BasicSafetyTraining,
+        StcwCertificateKind::MedicalFitness,
+    ] {
+        if !valid.contains(&required) {
+            missing.push(format!("{:?}", required));
+        }
+    }
+    let competency = competency_for_rank(rank);
+    if !competency.is_empty() && !competency.iter().any(|kind| valid.contains(kind)) {
+        missing.push(format!("one of {:?}", competency));
+    }
+
+    if missing.is_empty() {
+        Ok(())
+    } else {
+        Err(Error::InvalidInput {
+            msg: format!(
+                "crew member id={} is not certified to serve as {:?}: missing valid {}",
+                crew_member_id,
+                rank,
+                missing.join(", ")
+            ),
+        })
+    }
+}
+
+// Collect all certificates of a crew member using the crew index
+fn crew_certificates(crew_member_id: u64) -> Vec<CrewCertificate> {
+    let ids: Vec<u64> = CREW_CERTIFICATE_INDEX.with(|index| {
+        index
+            .borrow()
+            .range((crew_member_id, 0)..=(crew_member_id, u64::MAX))
+            .map(|((_, certificate_id), _)| certificate_id)
+            .collect()
+    });
+    CREW_CERTIFICATE_STORAGE.with(|service| {
+        let service = service.borrow();
+        ids.iter().filter_map(|id| service.get(id)).collect()
+    })
+}
+
+// Page through crew certificates in ID order, starting at `start`
+pub(crate) fn crew_certificates_from(start: u64, limit: usize) -> Vec<(u64, CrewCertificate)> {
+    CREW_CERTIFICATE_STORAGE.with(|service| service.borrow().range(start..).take(limit).collect())
+}
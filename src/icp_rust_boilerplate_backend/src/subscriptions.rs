@@ -0,0 +1,548 @@
+// Push delivery of events to subscribed canisters through a retrying outbox
+use crate::access::{has_role, require_authenticated, Role};
+use crate::calendar::NANOS_PER_DAY;
+use crate::events::{get_event, Event, EventKind};
+use crate::organizations::{can_principal_access_vessel, VesselAccess};
+use crate::{audit, Error, IdCell, Memory, VESSEL_MEMORY_MANAGER};
+use candid::{Decode, Encode, Principal};
+use ic_cdk::api::call::notify;
+use ic_cdk::api::{caller, time};
+use ic_cdk_timers::TimerId;
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use std::time::Duration;
+use std::{borrow::Cow, cell::RefCell};
+
+// Deliveries attempted per flush, to stay within the instruction limit
+const FLUSH_BATCH: usize = 100;
+const MAX_ATTEMPTS: u32 = 8;
+// Doubled after every failed attempt: 10s, 20s, 40s, ...
+const BASE_BACKOFF_NANOS: u64 = 10_000_000_000;
+const MAX_SUBSCRIPTIONS_PER_CALLER: usize = 20;
+// Every subscription is matched against each event, so their total is bounded too
+const MAX_SUBSCRIPTIONS: u64 = 1000;
+// Kind index entry of subscriptions that take every kind
+const ANY_KIND: u8 = u8::MAX;
+const MAX_METHOD_LEN: usize = 64;
+const MAX_PAGE_SIZE: usize = 100;
+// Delivered and failed deliveries are kept this long for get_deliveries
+const DELIVERY_RETENTION: u64 = 7 * NANOS_PER_DAY;
+
+// Define the structure for a subscription of a canister to events
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct Subscription {
+    id: u64,
+    subscriber: Principal,
+    // Update method of the subscriber called with the Event as its only argument
+    method: String,
+    // Event kinds to deliver; empty delivers every kind
+    kinds: Vec<EventKind>,
+    vessel_id: Option<u64>,
+    // Only voyage events departing from or bound for this port
+    port: Option<String>,
+    created_at: u64,
+}
+
+// Define the structure for subscribing to events
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct SubscriptionPayload {
+    method: String,
+    kinds: Vec<EventKind>,
+    vessel_id: Option<u64>,
+    port: Option<String>,
+}
+
+// Define the states of a delivery
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Debug)]
+pub(crate) enum DeliveryStatus {
+    Pending,
+    // The one-way call was accepted by the system; the subscriber sends no acknowledgement
+    Delivered,
+    // Gave up after MAX_ATTEMPTS or because the subscription was removed
+    Failed,
+}
+
+// Define the structure for the delivery of one event to one subscription
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct Delivery {
+    id: u64,
+    subscription_id: u64,
+    seq: u64,
+    status: DeliveryStatus,
+    attempts: u32,
+    next_attempt_at: u64,
+    last_error: Option<String>,
+    delivered_at: Option<u64>,
+}
+
+// Implement Storable trait for Subscription
+impl Storable for Subscription {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+// Implement BoundedStorable trait for Subscription
+impl BoundedStorable for Subscription {
+    const MAX_SIZE: u32 = 1024;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Implement Storable trait for Delivery
+impl Storable for Delivery {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+// Implement BoundedStorable trait for Delivery
+impl BoundedStorable for Delivery {
+    const MAX_SIZE: u32 = 512;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Define thread-local variables for subscriptions and the outbox
+thread_local! {
+    static SUBSCRIPTION_ID_COUNTER: RefCell<IdCell> = RefCell::new(
+        IdCell::init(VESSEL_MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(41))), 0)
+            .expect("Cannot create a counter")
+    );
+
+    static SUBSCRIPTION_STORAGE: RefCell<StableBTreeMap<u64, Subscription, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            VESSEL_MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(42)))
+    ));
+
+    static DELIVERY_ID_COUNTER: RefCell<IdCell> = RefCell::new(
+        IdCell::init(VESSEL_MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(43))), 0)
+            .expect("Cannot create a counter")
+    );
+
+    static DELIVERY_STORAGE: RefCell<StableBTreeMap<u64, Delivery, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            VESSEL_MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(44)))
+    ));
+
+    // (subscription_id, delivery_id) -> ()
+    static SUBSCRIPTION_DELIVERY_INDEX: RefCell<StableBTreeMap<(u64, u64), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            VESSEL_MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(45)))
+    ));
+
+    // The outbox: (next_attempt_at, delivery_id) -> () for pending deliveries
+    static OUTBOX: RefCell<StableBTreeMap<(u64, u64), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            VESSEL_MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(46)))
+    ));
+
+    // (event kind, subscription_id) -> (); ANY_KIND for subscriptions without kinds
+    static SUBSCRIPTION_KIND_INDEX: RefCell<StableBTreeMap<(u8, u64), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            VESSEL_MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(65)))
+    ));
+
+    // Removed subscriptions whose deliveries are still being dropped
+    static UNSUBSCRIBED: RefCell<StableBTreeMap<u64, (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            VESSEL_MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(66)))
+    ));
+
+    // The armed flush timer and when it fires; lost on upgrade, the periodic jobs re-arm it
+    static FLUSH_TIMER: RefCell<Option<(u64, TimerId)>> = const { RefCell::new(None) };
+}
+
+// Subscribe the calling canister to events
+#[ic_cdk::update]
+fn subscribe(payload: SubscriptionPayload) -> Result<Subscription, Error> {
+    let subscriber = caller();
+    if !is_canister(&subscriber) {
+        return Err(Error::Unauthorized {
+            msg: "only canisters can subscribe to events".to_string(),
+        });
+    }
+    let method = payload.method.trim().to_string();
+    if method.is_empty() || method.len() > MAX_METHOD_LEN {
+        return Err(Error::InvalidInput {
+            msg: format!("method must be between 1 and {} characters", MAX_METHOD_LEN),
+        });
+    }
+    let port = payload.port.map(|port| normalize_port(&port));
+    if port.as_deref() == Some("") {
+        return Err(Error::InvalidInput {
+            msg: "port must not be empty".to_string(),
+        });
+    }
+    let total = SUBSCRIPTION_STORAGE.with(|service| service.borrow().len());
+    if total >= MAX_SUBSCRIPTIONS {
+        return Err(Error::InvalidInput {
+            msg: format!("at most {} subscriptions can exist", MAX_SUBSCRIPTIONS),
+        });
+    }
+    let existing = SUBSCRIPTION_STORAGE.with(|service| {
+        service
+            .borrow()
+            .iter()
+            .filter(|(_, s)| s.subscriber == subscriber)
+            .count()
+    });
+    if existing >= MAX_SUBSCRIPTIONS_PER_CALLER {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "a canister can hold at most {} subscriptions",
+                MAX_SUBSCRIPTIONS_PER_CALLER
+            ),
+        });
+    }
+
+    let id = SUBSCRIPTION_ID_COUNTER
+        .with(|counter| {
+            let current_value = *counter.borrow().get();
+            counter.borrow_mut().set(current_value + 1)
+        })
+        .expect("cannot increment subscription id counter");
+
+    let subscription = Subscription {
+        id,
+        subscriber,
+        method,
+        kinds: payload.kinds,
+        vessel_id: payload.vessel_id,
+        port,
+        created_at: time(),
+    };
+    SUBSCRIPTION_STORAGE.with(|service| service.borrow_mut().insert(id, subscription.clone()));
+    SUBSCRIPTION_KIND_INDEX.with(|index| {
+        let mut index = index.borrow_mut();
+        for tag in kind_tags(&subscription) {
+            index.insert((tag, id), ());
+        }
+    });
+    audit::record("subscribe", "subscription", id, None, Some(&subscription));
+    Ok(subscription)
+}
+
+// Remove a subscription (its subscriber or an admin); the periodic jobs drop its deliveries
+#[ic_cdk::update]
+fn unsubscribe(id: u64) -> Result<(), Error> {
+    let subscription = owned_subscription(id)?;
+    SUBSCRIPTION_STORAGE.with(|service| service.borrow_mut().remove(&id));
+    SUBSCRIPTION_KIND_INDEX.with(|index| {
+        let mut index = index.borrow_mut();
+        for tag in kind_tags(&subscription) {
+            index.remove(&(tag, id));
+        }
+    });
+    UNSUBSCRIBED.with(|service| service.borrow_mut().insert(id, ()));
+    audit::record("unsubscribe", "subscription", id, Some(&subscription), None);
+    Ok(())
+}
+
+// List the caller's subscriptions; admins see every subscription
+#[ic_cdk::query]
+fn get_subscriptions() -> Vec<Subscription> {
+    let caller = caller();
+    let all = has_role(&caller, Role::Admin);
+    SUBSCRIPTION_STORAGE.with(|service| {
+        service
+            .borrow()
+            .iter()
+            .map(|(_, s)| s)
+            .filter(|s| all || s.subscriber == caller)
+            .collect()
+    })
+}
+
+// List the deliveries of a subscription, newest first, optionally in one state
+#[ic_cdk::query]
+fn get_deliveries(
+    subscription_id: u64,
+    status: Option<DeliveryStatus>,
+    limit: u32,
+) -> Result<Vec<Delivery>, Error> {
+    owned_subscription(subscription_id)?;
+    let ids: Vec<u64> = SUBSCRIPTION_DELIVERY_INDEX.with(|index| {
+        index
+            .borrow()
+            .range((subscription_id, 0)..=(subscription_id, u64::MAX))
+            .map(|((_, id), _)| id)
+            .collect()
+    });
+    Ok(ids
+        .into_iter()
+        .rev()
+        .filter_map(|id| _get_delivery(&id))
+        .filter(|d| status.is_none_or(|status| d.status == status))
+        .take((limit as usize).clamp(1, MAX_PAGE_SIZE))
+        .collect())
+}
+
+// Queue the event for every subscription it matches and arm the flush timer
+pub(crate) fn enqueue(event: &Event) {
+    let kind = event.payload.kind();
+    let vessel_id = event.payload.vessel_id();
+    let ports: Vec<String> = event
+        .payload
+        .ports()
+        .into_iter()
+        .map(normalize_port)
+        .collect();
+    let candidates: Vec<u64> = SUBSCRIPTION_KIND_INDEX.with(|index| {
+        let index = index.borrow();
+        [kind as u8, ANY_KIND]
+            .into_iter()
+            .flat_map(|tag| {
+                index
+                    .range((tag, 0)..=(tag, u64::MAX))
+                    .map(|((_, id), _)| id)
+                    .collect::<Vec<_>>()
+            })
+            .collect()
+    });
+    let matching: Vec<u64> = SUBSCRIPTION_STORAGE.with(|service| {
+        let service = service.borrow();
+        candidates
+            .iter()
+            .filter_map(|id| service.get(id))
+            .filter(|s| s.vessel_id.is_none_or(|id| id == vessel_id))
+            .filter(|s| s.port.as_ref().is_none_or(|port| ports.contains(port)))
+            .filter(|s| can_principal_access_vessel(&s.subscriber, vessel_id, VesselAccess::View))
+            .map(|s| s.id)
+            .collect()
+    });
+    if matching.is_empty() {
+        return;
+    }
+
+    let now = time();
+    for subscription_id in matching {
+        let id = DELIVERY_ID_COUNTER
+            .with(|counter| {
+                let current_value = *counter.borrow().get();
+                counter.borrow_mut().set(current_value + 1)
+            })
+            .expect("cannot increment delivery id counter");
+        let delivery = Delivery {
+            id,
+            subscription_id,
+            seq: event.seq,
+            status: DeliveryStatus::Pending,
+            attempts: 0,
+            next_attempt_at: now,
+            last_error: None,
+            delivered_at: None,
+        };
+        do_insert_delivery(&delivery);
+        SUBSCRIPTION_DELIVERY_INDEX
+            .with(|index| index.borrow_mut().insert((subscription_id, id), ()));
+        OUTBOX.with(|outbox| outbox.borrow_mut().insert((now, id), ()));
+    }
+    schedule_flush(now);
+}
+
+// Attempt the deliveries that are due, then re-arm the timer for the next one
+pub(crate) fn flush() {
+    FLUSH_TIMER.with(|timer| timer.borrow_mut().take());
+    let now = time();
+    let due: Vec<(u64, u64)> = OUTBOX.with(|outbox| {
+        outbox
+            .borrow()
+            .range(..(now + 1, 0))
+            .take(FLUSH_BATCH)
+            .map(|(key, _)| key)
+            .collect()
+    });
+
+    for key in due {
+        OUTBOX.with(|outbox| outbox.borrow_mut().remove(&key));
+        let Some(mut delivery) = _get_delivery(&key.1) else {
+            continue;
+        };
+        let subscription = SUBSCRIPTION_STORAGE.with(|s| s.borrow().get(&delivery.subscription_id));
+        // Only rejected calls are retried
+        let result = match (subscription, get_event(delivery.seq)) {
+            (Some(subscription), Some(event)) => {
+                notify(subscription.subscriber, &subscription.method, (event,))
+                    .map_err(|code| (format!("call rejected: {:?}", code), true))
+            }
+            (None, _) => Err(("subscription removed".to_string(), false)),
+            (_, None) => Err((format!("event seq={} not found", delivery.seq), false)),
+        };
+        delivery.attempts += 1;
+        match result {
+            Ok(()) => {
+                delivery.status = DeliveryStatus::Delivered;
+                delivery.delivered_at = Some(now);
+                delivery.last_error = None;
+            }
+            Err((e, retryable)) => {
+                delivery.last_error = Some(e);
+                if retryable && delivery.attempts < MAX_ATTEMPTS {
+                    delivery.next_attempt_at =
+                        now + (BASE_BACKOFF_NANOS << (delivery.attempts - 1));
+                    OUTBOX.with(|outbox| {
+                        outbox
+                            .borrow_mut()
+                            .insert((delivery.next_attempt_at, delivery.id), ())
+                    });
+                } else {
+                    delivery.status = DeliveryStatus::Failed;
+                }
+            }
+        }
+        do_insert_delivery(&delivery);
+    }
+
+    let next = OUTBOX.with(|outbox| outbox.borrow().iter().next().map(|((at, _), _)| at));
+    if let Some(at) = next {
+        schedule_flush(at);
+    }
+}
+
+// Drop the delivered and failed deliveries that finished before the retention period, looking
+// at the `limit` oldest deliveries; deliveries finish in roughly the order they were queued
+pub(crate) fn purge_deliveries(limit: usize) {
+    let cutoff = time().saturating_sub(DELIVERY_RETENTION);
+    let expired: Vec<Delivery> = DELIVERY_STORAGE.with(|service| {
+        service
+            .borrow()
+            .iter()
+            .take(limit)
+            .map(|(_, delivery)| delivery)
+            .filter(|d| d.status != DeliveryStatus::Pending)
+            .filter(|d| d.delivered_at.unwrap_or(d.next_attempt_at) < cutoff)
+            .collect()
+    });
+    for delivery in expired {
+        remove_delivery(&delivery);
+    }
+}
+
+// Drop up to `limit` deliveries of removed subscriptions, finishing one subscription at a time
+pub(crate) fn purge_unsubscribed(limit: usize) {
+    let mut remaining = limit;
+    while remaining > 0 {
+        let Some((id, _)) = UNSUBSCRIBED.with(|service| service.borrow().first_key_value()) else {
+            return;
+        };
+        let deliveries: Vec<u64> = SUBSCRIPTION_DELIVERY_INDEX.with(|index| {
+            index
+                .borrow()
+                .range((id, 0)..=(id, u64::MAX))
+                .take(remaining)
+                .map(|((_, delivery_id), _)| delivery_id)
+                .collect()
+        });
+        // Fewer than asked for means these are the last ones
+        if deliveries.len() < remaining {
+            UNSUBSCRIBED.with(|service| service.borrow_mut().remove(&id));
+        }
+        remaining = remaining.saturating_sub(deliveries.len().max(1));
+        for delivery_id in deliveries {
+            if let Some(delivery) = _get_delivery(&delivery_id) {
+                remove_delivery(&delivery);
+            }
+        }
+    }
+}
+
+// Index the kinds of the subscriptions stored before the kind index existed
+pub(crate) fn backfill_kind_index() {
+    let indexed = SUBSCRIPTION_KIND_INDEX.with(|index| !index.borrow().is_empty());
+    if indexed {
+        return;
+    }
+    SUBSCRIPTION_STORAGE.with(|service| {
+        SUBSCRIPTION_KIND_INDEX.with(|index| {
+            let mut index = index.borrow_mut();
+            for (id, subscription) in service.borrow().iter() {
+                for tag in kind_tags(&subscription) {
+                    index.insert((tag, id), ());
+                }
+            }
+        })
+    });
+}
+
+// The kind index entries of a subscription
+fn kind_tags(subscription: &Subscription) -> Vec<u8> {
+    if subscription.kinds.is_empty() {
+        vec![ANY_KIND]
+    } else {
+        subscription.kinds.iter().map(|kind| *kind as u8).collect()
+    }
+}
+
+// Arm the flush timer to fire at `at`, unless it already fires earlier
+fn schedule_flush(at: u64) {
+    FLUSH_TIMER.with(|timer| {
+        let mut timer = timer.borrow_mut();
+        if let Some((armed_at, id)) = *timer {
+            if armed_at <= at {
+                return;
+            }
+            ic_cdk_timers::clear_timer(id);
+        }
+        let delay = Duration::from_nanos(at.saturating_sub(time()));
+        *timer = Some((at, ic_cdk_timers::set_timer(delay, flush)));
+    });
+}
+
+// Fail unless the caller is the subscriber or an admin
+fn owned_subscription(id: u64) -> Result<Subscription, Error> {
+    let caller = require_authenticated()?;
+    let subscription = SUBSCRIPTION_STORAGE
+        .with(|service| service.borrow().get(&id))
+        .ok_or(Error::NotFound {
+            msg: format!("a subscription with id={} not found", id),
+        })?;
+    if subscription.subscriber != caller && !has_role(&caller, Role::Admin) {
+        return Err(Error::Unauthorized {
+            msg: format!("caller {} does not own subscription id={}", caller, id),
+        });
+    }
+    Ok(subscription)
+}
+
+// Canister IDs are opaque principals, which end with the 0x01 tag byte
+fn is_canister(principal: &Principal) -> bool {
+    principal.as_slice().last() == Some(&0x01)
+}
+
+fn normalize_port(port: &str) -> String {
+    port.replace(' ', "").to_uppercase()
+}
+
+// Helper method to insert a Delivery into storage
+fn do_insert_delivery(delivery: &Delivery) {
+    DELIVERY_STORAGE.with(|service| service.borrow_mut().insert(delivery.id, delivery.clone()));
+}
+
+// Helper method to remove a Delivery, its index entry and any pending attempt from storage
+fn remove_delivery(delivery: &Delivery) {
+    DELIVERY_STORAGE.with(|service| service.borrow_mut().remove(&delivery.id));
+    SUBSCRIPTION_DELIVERY_INDEX.with(|index| {
+        index
+            .borrow_mut()
+            .remove(&(delivery.subscription_id, delivery.id))
+    });
+    if delivery.status == DeliveryStatus::Pending {
+        OUTBOX.with(|outbox| {
+            outbox
+                .borrow_mut()
+                .remove(&(delivery.next_attempt_at, delivery.id))
+        });
+    }
+}
+
+// Retrieve a Delivery by ID from storage
+fn _get_delivery(id: &u64) -> Option<Delivery> {
+    DELIVERY_STORAGE.with(|service| service.borrow().get(id))
+}
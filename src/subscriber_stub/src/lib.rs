@@ -0,0 +1,96 @@
+// Stub subscriber canister: subscribes to the backend's events and records what it receives
+#[macro_use]
+extern crate serde;
+use candid::{Encode, IDLArgs, Principal};
+use ic_cdk::api::call::{arg_data_raw, call_raw};
+use ic_cdk::api::{caller, time};
+use std::cell::RefCell;
+
+// Mirrors the backend's EventKind
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize)]
+enum EventKind {
+    VesselRegistered,
+    VesselUpdated,
+    PositionReported,
+    VesselRemoved,
+    VoyageScheduled,
+    VoyageUpdated,
+    VoyageDeparted,
+    VoyageArrived,
+    VoyageRemoved,
+}
+
+// Mirrors the backend's SubscriptionPayload
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct SubscriptionPayload {
+    method: String,
+    kinds: Vec<EventKind>,
+    vessel_id: Option<u64>,
+    port: Option<String>,
+}
+
+// An event as received, in Candid text form
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct ReceivedEvent {
+    from: Principal,
+    received_at: u64,
+    event: String,
+}
+
+thread_local! {
+    static RECEIVED: RefCell<Vec<ReceivedEvent>> = const { RefCell::new(Vec::new()) };
+}
+
+// Subscribe this canister to the backend's events, delivered to `on_event`.
+// Returns the backend's reply in Candid text form.
+#[ic_cdk::update]
+async fn subscribe(
+    backend: Principal,
+    kinds: Vec<EventKind>,
+    vessel_id: Option<u64>,
+    port: Option<String>,
+) -> Result<String, String> {
+    let payload = SubscriptionPayload {
+        method: "on_event".to_string(),
+        kinds,
+        vessel_id,
+        port,
+    };
+    let args = Encode!(&payload).map_err(|e| e.to_string())?;
+    let reply = call_raw(backend, "subscribe", args, 0)
+        .await
+        .map_err(|(code, msg)| format!("{:?}: {}", code, msg))?;
+    IDLArgs::from_bytes(&reply)
+        .map(|reply| reply.to_string())
+        .map_err(|e| e.to_string())
+}
+
+// Called by the backend with one Event; decoded without its type so the stub never falls behind
+#[ic_cdk::update]
+fn on_event() {
+    let event = IDLArgs::from_bytes(&arg_data_raw())
+        .map(|args| args.to_string())
+        .unwrap_or_else(|e| format!("undecodable event: {}", e));
+    RECEIVED.with(|received| {
+        received.borrow_mut().push(ReceivedEvent {
+            from: caller(),
+            received_at: time(),
+            event,
+        })
+    });
+}
+
+// List the events received so far
+#[ic_cdk::query]
+fn get_received() -> Vec<ReceivedEvent> {
+    RECEIVED.with(|received| received.borrow().clone())
+}
+
+// Forget the events received so far
+#[ic_cdk::update]
+fn clear_received() {
+    RECEIVED.with(|received| received.borrow_mut().clear());
+}
+
+// Need this to generate candid
+ic_cdk::export_candid!();